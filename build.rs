@@ -1,11 +1,25 @@
 use miette::{IntoDiagnostic, Result};
+use std::env;
 
 fn main() -> Result<()> {
-    prost_build::compile_protos(&["src/update_metadata.proto"], &["src/"]).into_diagnostic()?;
+    prost_build::Config::new()
+        // machine readable output uses the raw extents rather than the pretty text form, and
+        // inspect --raw-manifest text walks the whole manifest
+        .type_attribute(".chromeos_update_engine", "#[derive(serde::Serialize)]")
+        .field_attribute(
+            ".chromeos_update_engine.InstallOperation.type",
+            "#[serde(serialize_with = \"serialize_op_type\")]",
+        )
+        .field_attribute(
+            ".chromeos_update_engine.CowMergeOperation.type",
+            "#[serde(serialize_with = \"serialize_cow_op_type\")]",
+        )
+        .compile_protos(&["src/update_metadata.proto"], &["src/"])
+        .into_diagnostic()?;
 
     const INCLUDES: [&str; 2] = [".", "./bsdiff/include"];
 
-    autocxx_build::Builder::new("src/extract/bspatch.rs", &INCLUDES)
+    autocxx_build::Builder::new("src/bspatch.rs", &INCLUDES)
         .auto_allowlist(true)
         .extra_clang_args(&["-D__off_t=long"])
         .build()?
@@ -28,9 +42,16 @@ fn main() -> Result<()> {
         .file("bsdiff/utils.cc")
         .compile("bspatch");
 
-    println!("cargo:rustc-link-lib=bz2");
-    println!("cargo:rustc-link-lib=brotlidec");
-    println!("cargo:rerun-if-changed=src/extract/bspatch.rs");
+    if env::var_os("CARGO_FEATURE_STATIC").is_some() {
+        // bzip2-sys already builds and links a static libbz2 for the bzip2 crate,
+        // and brotlidec's static archive doesn't pull in brotlicommon by itself
+        println!("cargo:rustc-link-lib=static=brotlidec");
+        println!("cargo:rustc-link-lib=static=brotlicommon");
+    } else {
+        println!("cargo:rustc-link-lib=bz2");
+        println!("cargo:rustc-link-lib=brotlidec");
+    }
+    println!("cargo:rerun-if-changed=src/bspatch.rs");
     println!("cargo:rerun-if-changed=build.rs");
     Ok(())
 }
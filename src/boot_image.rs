@@ -0,0 +1,166 @@
+use anyhow::{bail, Result};
+use binrw::BinRead;
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{Read, Seek},
+};
+
+/// partitions which contain an android boot image
+pub const BOOT_PARTITIONS: [&str; 3] = ["boot", "init_boot", "vendor_boot"];
+
+pub fn is_boot_partition(name: &str) -> bool {
+    BOOT_PARTITIONS.contains(&name)
+}
+
+/// the start of a boot image header, which is laid out differently depending on the version
+#[derive(BinRead)]
+#[br(little, magic = b"ANDROID!")]
+struct RawBootHeader {
+    words: [u32; 8],
+    header_version: u32,
+    os_version_v0: u32,
+}
+
+#[derive(BinRead)]
+#[br(little, magic = b"VNDRBOOT")]
+struct RawVendorBootHeader {
+    header_version: u32,
+    page_size: u32,
+    _kernel_addr: u32,
+    _ramdisk_addr: u32,
+    vendor_ramdisk_size: u32,
+}
+
+pub enum BootImageHeader {
+    Boot {
+        header_version: u32,
+        kernel_size: u32,
+        ramdisk_size: u32,
+        /// only present before version 3
+        second_size: Option<u32>,
+        /// only present before version 3, afterwards pages are always 4096 bytes
+        page_size: Option<u32>,
+        os_version: u32,
+    },
+    VendorBoot {
+        header_version: u32,
+        page_size: u32,
+        vendor_ramdisk_size: u32,
+    },
+}
+
+impl BootImageHeader {
+    pub fn read(stream: &mut (impl Read + Seek)) -> Result<Self> {
+        let start = stream.stream_position()?;
+        if let Ok(raw) = RawBootHeader::read(stream) {
+            let w = raw.words;
+            return Ok(if raw.header_version < 3 {
+                BootImageHeader::Boot {
+                    header_version: raw.header_version,
+                    kernel_size: w[0],
+                    ramdisk_size: w[2],
+                    second_size: Some(w[4]),
+                    page_size: Some(w[7]),
+                    os_version: raw.os_version_v0,
+                }
+            } else {
+                BootImageHeader::Boot {
+                    header_version: raw.header_version,
+                    kernel_size: w[0],
+                    ramdisk_size: w[1],
+                    second_size: None,
+                    page_size: None,
+                    os_version: w[2],
+                }
+            });
+        }
+
+        stream.seek(std::io::SeekFrom::Start(start))?;
+        if let Ok(raw) = RawVendorBootHeader::read(stream) {
+            return Ok(BootImageHeader::VendorBoot {
+                header_version: raw.header_version,
+                page_size: raw.page_size,
+                vendor_ramdisk_size: raw.vendor_ramdisk_size,
+            });
+        }
+        bail!("Not an android boot image (missing ANDROID! or VNDRBOOT magic)")
+    }
+}
+
+/// formats the packed os_version field as `a.b.c (patch level yyyy-mm)`
+fn format_os_version(os_version: u32) -> String {
+    if os_version == 0 {
+        return "unknown".to_string();
+    }
+    let version = os_version >> 11;
+    let patch_level = os_version & 0x7ff;
+    format!(
+        "{}.{}.{} (patch level {:04}-{:02})",
+        version >> 14,
+        (version >> 7) & 0x7f,
+        version & 0x7f,
+        (patch_level >> 4) + 2000,
+        patch_level & 0xf
+    )
+}
+
+impl Display for BootImageHeader {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BootImageHeader::Boot {
+                header_version,
+                kernel_size,
+                ramdisk_size,
+                second_size,
+                page_size,
+                os_version,
+            } => {
+                writeln!(f, "header_version: {}", header_version)?;
+                writeln!(f, "kernel_size: {}", kernel_size)?;
+                writeln!(f, "ramdisk_size: {}", ramdisk_size)?;
+                if let Some(second_size) = second_size {
+                    writeln!(f, "second_size: {}", second_size)?;
+                }
+                if let Some(page_size) = page_size {
+                    writeln!(f, "page_size: {}", page_size)?;
+                }
+                write!(f, "os_version: {}", format_os_version(*os_version))
+            }
+            BootImageHeader::VendorBoot { header_version, page_size, vendor_ramdisk_size } => {
+                writeln!(f, "header_version: {}", header_version)?;
+                writeln!(f, "page_size: {}", page_size)?;
+                write!(f, "vendor_ramdisk_size: {}", vendor_ramdisk_size)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{format_os_version, BootImageHeader};
+
+    #[test]
+    fn boot_header_v4_test() {
+        let mut header = b"ANDROID!".to_vec();
+        // kernel_size, ramdisk_size, os_version, header_size, reserved[4]
+        // android 14.0.0 with a 2024-06 patch level
+        let os_version = (14 << 25) | (24 << 4) | 6;
+        for word in [100_u32, 200, os_version, 1584, 0, 0, 0, 0, 4, 0] {
+            header.extend_from_slice(&word.to_le_bytes());
+        }
+
+        match BootImageHeader::read(&mut Cursor::new(header)).unwrap() {
+            BootImageHeader::Boot {
+                header_version, kernel_size, ramdisk_size, os_version, ..
+            } => {
+                assert_eq!((header_version, kernel_size, ramdisk_size), (4, 100, 200));
+                assert_eq!(format_os_version(os_version), "14.0.0 (patch level 2024-06)");
+            }
+            _ => panic!("expected a boot header"),
+        }
+
+        assert!(BootImageHeader::read(&mut Cursor::new(vec![0; 64])).is_err());
+    }
+}
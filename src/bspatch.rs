@@ -13,7 +13,11 @@ use self::ffi::{
     StreamAdapterCpp,
 };
 
-use super::{StreamRead, StreamWrite};
+trait StreamRead: Read + Seek {}
+impl<T: Read + Seek> StreamRead for T {}
+
+trait StreamWrite: Write + Seek {}
+impl<T: Write + Seek> StreamWrite for T {}
 
 include_cpp! {
     #include "bsdiff/file_interface.h"
@@ -142,6 +146,8 @@ impl FileInterface_methods for StreamAdapter {
         true
     }
 
+    // for ExtentStreams (including new_suffix ones) seeking to the end is bounded by the end of
+    // the inner stream, so this reports the real length rather than the length of the extents
     unsafe fn GetSize(&mut self, size_ptr: *mut u64) -> bool {
         let result = self.inner.stream_len_unsafe();
         if let Some(size) = self.record_err(result) {
@@ -152,6 +158,26 @@ impl FileInterface_methods for StreamAdapter {
     }
 }
 
+/// applies a bsdiff patch, in the format used by the bsdiff operations of Android OTAs, to `src`
+/// and writes the result to `dst`
+///
+/// in an OTA the streams are `ExtentStream`s over the blocks an operation reads and writes, but
+/// any seekable streams work, as long as `src` is positioned at its start
+///
+/// bspatch reports failures with an error code: 1 is an IO error, which becomes the error `src`
+/// or `dst` returned, and 2 means `data` isn't a valid patch
+///
+/// ```
+/// use android_ota_extractor::bspatch;
+/// use std::{fs, io::Cursor};
+///
+/// let mut old = Cursor::new(fs::read("test/bin1")?);
+/// let patch = fs::read("test/patch")?;
+/// let mut new = Cursor::new(vec![]);
+/// bspatch(&mut old, &mut new, &patch)?;
+/// assert!(new.into_inner() == fs::read("test/bin2")?);
+/// # anyhow::Ok(())
+/// ```
 pub fn bspatch(
     src: &mut (impl Read + Seek),
     dst: &mut (impl Write + Seek),
@@ -176,10 +202,12 @@ pub fn bspatch(
 mod tests {
     use std::{
         fs::{self, File},
-        io::{self, Cursor, Seek, Write},
+        io::{self, Cursor, Seek, SeekFrom, Write},
     };
 
+    use crate::extent::{Extent, ExtentStream};
     use anyhow::anyhow;
+    use cast::{u64, usize};
 
     use super::bspatch;
 
@@ -196,6 +224,30 @@ mod tests {
         assert!(new_vec == new_correct);
     }
 
+    #[test]
+    fn bspatch_oversized_src_test() {
+        let patch = fs::read("test/patch").unwrap();
+        let new_correct = fs::read("test/bin2").unwrap();
+        let old_len = usize(fs::metadata("test/bin1").unwrap().len());
+
+        let suffix = ExtentStream::new_suffix(File::open("test/bin1").unwrap(), 0).unwrap();
+        let oversized = ExtentStream::new(
+            File::open("test/bin1").unwrap(),
+            vec![Extent { start: 0, len: old_len + 4096 }],
+        )
+        .unwrap()
+        .unwrap();
+        for mut old in [suffix, oversized] {
+            // this is what GetSize reports to bspatch
+            assert_eq!(old.seek(SeekFrom::End(0)).unwrap(), u64(old_len));
+            old.rewind().unwrap();
+
+            let mut new_vec = vec![];
+            bspatch(&mut old, &mut Cursor::new(&mut new_vec), &patch).unwrap();
+            assert!(new_vec == new_correct);
+        }
+    }
+
     #[test]
     fn bspatch_io_err_test() {
         struct BadWriter<T: Write + Seek> {
@@ -0,0 +1,70 @@
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// the kinds of warnings, with codes that stay stable so scripts can match on them
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum Code {
+    /// --max-ops stopped before all operations of a partition were applied
+    #[serde(rename = "W001_INCOMPLETE_IMAGE")]
+    IncompleteImage,
+    /// an operation failed with a transient error and was applied again
+    #[serde(rename = "W002_OP_RETRIED")]
+    OpRetried,
+    /// a partition only copies blocks in place but its hash changes
+    #[serde(rename = "W003_COPY_HASH_MISMATCH")]
+    CopyHashMismatch,
+    /// the payload doesn't start at the beginning of the file
+    #[serde(rename = "W004_PAYLOAD_PREFIX")]
+    PayloadPrefix,
+    /// an operation writes dst blocks a previous operation already wrote
+    #[serde(rename = "W005_DST_OVERLAP")]
+    DstOverlap,
+}
+
+impl Code {
+    pub fn name(self) -> &'static str {
+        match self {
+            Code::IncompleteImage => "W001_INCOMPLETE_IMAGE",
+            Code::OpRetried => "W002_OP_RETRIED",
+            Code::CopyHashMismatch => "W003_COPY_HASH_MISMATCH",
+            Code::PayloadPrefix => "W004_PAYLOAD_PREFIX",
+            Code::DstOverlap => "W005_DST_OVERLAP",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    pub code: Code,
+    pub message: String,
+}
+
+static DIAGNOSTICS: Mutex<Vec<Diagnostic>> = Mutex::new(vec![]);
+
+/// records a warning for the front end to print once it's done
+pub fn warn(code: Code, message: String) {
+    DIAGNOSTICS.lock().unwrap().push(Diagnostic { code, message });
+}
+
+/// the warnings recorded so far
+pub fn collected() -> Vec<Diagnostic> {
+    DIAGNOSTICS.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Code;
+
+    #[test]
+    fn code_name_test() {
+        for code in [
+            Code::IncompleteImage,
+            Code::OpRetried,
+            Code::CopyHashMismatch,
+            Code::PayloadPrefix,
+            Code::DstOverlap,
+        ] {
+            assert_eq!(serde_json::to_value(code).unwrap(), code.name());
+        }
+    }
+}
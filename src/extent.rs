@@ -0,0 +1,844 @@
+use crate::update_metadata::Extent as RawExtent;
+use anyhow::{anyhow, bail, Result};
+use cast::{i64, u64, usize};
+use std::{
+    cmp::min,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+};
+
+/// calculates `pos + offset`, returning it if it is at least `start` and the raw result otherwise
+pub fn calculate_rel(start: u64, pos: u64, offset: i64) -> Result<u64, i64> {
+    let abs_pos = i64(pos).unwrap() + offset;
+    if let Ok(abs_pos) = u64(abs_pos) {
+        if abs_pos >= start {
+            return Ok(abs_pos);
+        }
+    }
+    Err(abs_pos)
+}
+
+/// a contiguous byte range of a stream
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Extent {
+    pub start: usize,
+    pub len: usize,
+}
+
+impl Extent {
+    /// the start of a sparse hole, which isn't backed by the stream: reading it gives zeros and
+    /// writes to it are dropped, like `start_block == u64::MAX` in a manifest
+    pub const HOLE: usize = usize::MAX;
+
+    pub fn hole(len: usize) -> Self {
+        Self { start: Self::HOLE, len }
+    }
+
+    pub fn is_hole(&self) -> bool {
+        self.start == Self::HOLE
+    }
+}
+
+/// the error for an extent, or a run of them, which ends past usize::MAX
+fn overflow_error(extent: &Extent) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        anyhow!("Extent {:?} ends past the largest possible position", extent),
+    )
+}
+
+/// joins extents which continue right where the previous one ends, so reading across them
+/// doesn't seek the inner stream; the outer positions stay the same
+fn merge_contiguous(extents: Vec<Extent>) -> io::Result<Vec<Extent>> {
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents {
+        match merged.last_mut() {
+            Some(last)
+                if (last.is_hole() && extent.is_hole())
+                    || (!last.is_hole()
+                        && last.start.checked_add(last.len) == Some(extent.start)) =>
+            {
+                last.len =
+                    last.len.checked_add(extent.len).ok_or_else(|| overflow_error(&extent))?
+            }
+            _ => merged.push(extent),
+        }
+    }
+    Ok(merged)
+}
+
+/// the outer starting position of each extent followed by the end of the last one, failing if
+/// an extent or the whole stream ends past usize::MAX
+fn outer_positions(extents: &[Extent]) -> io::Result<Vec<usize>> {
+    let mut positions = Vec::with_capacity(extents.len() + 1);
+    let mut end = 0_usize;
+    positions.push(end);
+    for extent in extents {
+        if !extent.is_hole() && extent.start.checked_add(extent.len).is_none() {
+            return Err(overflow_error(extent));
+        }
+        end = end.checked_add(extent.len).ok_or_else(|| overflow_error(extent))?;
+        positions.push(end);
+    }
+    Ok(positions)
+}
+
+/// fails if any two extents which aren't holes share bytes, or if an extent ends past usize::MAX
+///
+/// the order doesn't matter, since payloads list the blocks of fragmented files out of order
+fn check_disjoint(extents: &[Extent]) -> io::Result<()> {
+    let mut sorted =
+        extents.iter().filter(|extent| !extent.is_hole() && extent.len > 0).collect::<Vec<_>>();
+    sorted.sort_unstable_by_key(|extent| extent.start);
+    let mut prev_end = None;
+    for (i, extent) in sorted.iter().enumerate() {
+        let end = extent.start.checked_add(extent.len).ok_or_else(|| overflow_error(extent))?;
+        if prev_end.is_some_and(|prev_end| extent.start < prev_end) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!("Extents {:?} and {:?} overlap", sorted[i - 1], extent),
+            ));
+        }
+        prev_end = Some(end);
+    }
+    Ok(())
+}
+
+/// terminology:
+/// - inner position: the position as seen by the inner stream
+/// - outer position: the position as seen by users of the ExtentStream
+/// the inner position jumps around while the outer position is contiguous
+///
+/// notes:
+/// - extents may come in any order, and may repeat bytes when reading, like the src extents of
+///   an operation may; writes need disjoint extents, which new_disjoint checks; holes
+///   (see `Extent::hole`) may appear anywhere and never touch the inner stream
+/// - seeking past the end of the inner stream won't necessarily error,
+///   but seeking past the end of the extents will error
+/// - if the stream ends before the extents do, then seek will use the shorter one for SeekFrom::End.
+///   for example, if the extents are [0..20, 40..60] but the stream is only of length 45,
+///   then seek(SeekFrom::End(0)) will return 25 (0..20 + 40..45), unless `trust_extent_length`
+///   was called, in which case the inner stream isn't asked for its length at all
+/// - if no extents are specified then new returns none
+pub struct ExtentStream<T: Seek> {
+    inner: T,
+    cursor: (usize, usize),
+    extents: Vec<Extent>,
+    /// `extents_outer[i]` is the outer starting position of the ith extent
+    /// we also make `extents_outer[extents.len()]` the (exclusive) end of the last extent
+    /// thus the ith extent goes from `extents_outer[i]` to `extents_outer[i + 1]` (exclusive)
+    extents_outer: Vec<usize>,
+    /// whether SeekFrom::End can assume the inner stream is as long as the extents need
+    trust_extent_length: bool,
+}
+
+enum NextArea {
+    CurrentExtent(usize), // number of bytes remaining in current extent
+    NextExtent(usize),    // index of next extent (must be valid!)
+    None,
+}
+
+impl<T: Seek> ExtentStream<T> {
+    pub fn new(inner: T, extents: Vec<Extent>) -> io::Result<Option<Self>> {
+        if extents.is_empty() {
+            return Ok(None);
+        }
+        let extents = merge_contiguous(extents)?;
+
+        let mut result = Self {
+            inner,
+            cursor: (0, 0),
+            extents_outer: outer_positions(&extents)?,
+            extents,
+            trust_extent_length: false,
+        };
+        result.set_cursor(0, 0)?;
+
+        Ok(Some(result))
+    }
+
+    /// like new, but fails if the extents overlap, for streams which are written to
+    pub fn new_disjoint(inner: T, extents: Vec<Extent>) -> io::Result<Option<Self>> {
+        check_disjoint(&extents)?;
+        Self::new(inner, extents)
+    }
+
+    pub fn new_range(inner: T, start: usize, len: usize) -> io::Result<Self> {
+        Self::new(inner, vec![Extent { start, len }]).map(Option::unwrap)
+    }
+
+    pub fn new_suffix(inner: T, start: usize) -> io::Result<Self> {
+        Self::new(inner, vec![Extent { start, len: usize::MAX / 2 - start }]).map(Option::unwrap)
+    }
+
+    /// makes SeekFrom::End relative to the end of the extents without seeking the inner stream,
+    /// for inner streams which are known to be long enough but are slow or unable to seek to
+    /// their end, like a slice of a bigger file
+    pub fn trust_extent_length(mut self) -> Self {
+        self.trust_extent_length = true;
+        self
+    }
+
+    /// the end of the stream for SeekFrom::End, which is where the inner stream ends if that's
+    /// before the end of the extents
+    fn end(&mut self) -> io::Result<usize> {
+        if self.trust_extent_length {
+            return Ok(self.len());
+        }
+        let inner_len = usize(self.inner.seek(SeekFrom::End(0))?);
+        let mut inner_len_outer = 0;
+        for extent in &self.extents {
+            if extent.is_hole() || extent.start + extent.len <= inner_len {
+                inner_len_outer += extent.len;
+            } else {
+                if extent.start < inner_len {
+                    inner_len_outer += inner_len - extent.start;
+                }
+                break;
+            }
+        }
+        Ok(min(self.len(), inner_len_outer))
+    }
+
+    /// warning: this will not necessarily be the same as the length reported by Seek::stream_len,
+    /// this is because this method reports the length as specified by the extents,
+    /// whereas the underlying stream might end before the extents do which will be reflected in seeking
+    pub fn len(&self) -> usize {
+        *self.extents_outer.last().unwrap()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn next_area(&self) -> NextArea {
+        let (extent_i, byte_i) = self.cursor;
+        if extent_i >= self.extents.len() {
+            return NextArea::None;
+        }
+
+        let extent_len = self.extents[extent_i].len;
+        let extent_rem =
+            extent_len.checked_sub(byte_i).expect("internal error: extent index > extent size");
+
+        if extent_rem > 0 {
+            NextArea::CurrentExtent(extent_rem)
+        } else if extent_i + 1 < self.extents.len() {
+            NextArea::NextExtent(extent_i + 1)
+        } else {
+            NextArea::None
+        }
+    }
+
+    fn set_cursor(&mut self, extent_i: usize, byte_i: usize) -> io::Result<u64> {
+        self.cursor = (extent_i, byte_i);
+        let extent = self.extents[extent_i];
+        if !extent.is_hole() {
+            self.inner.seek(SeekFrom::Start(u64(extent.start + byte_i)))?;
+        }
+        Ok(u64(self.extents_outer[extent_i] + byte_i))
+    }
+
+    fn in_hole(&self) -> bool {
+        self.extents[self.cursor.0].is_hole()
+    }
+
+    fn find_cursor_outer(&self, outer_pos: usize) -> Option<(usize, usize)> {
+        // extents_outer is sorted, so the containing extent is the last one starting at or before
+        // outer_pos, which also skips over empty extents
+        let i = self.extents_outer.partition_point(|&start| start <= outer_pos) - 1;
+        if i < self.extents.len() {
+            return Some((i, outer_pos - self.extents_outer[i]));
+        }
+        if outer_pos == self.len() {
+            // we are at the very end
+            return Some((self.extents.len() - 1, self.extents.last().unwrap().len));
+        }
+        None
+    }
+}
+
+impl<T: Seek> Seek for ExtentStream<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let err_before_start = |pos| {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                anyhow!("Attempted to seek before the start of extent stream (pos = {} < 0)", pos),
+            ))
+        };
+        match pos {
+            SeekFrom::Start(pos) => {
+                let pos = usize(pos);
+                if let Some((extent_i, byte_i)) = self.find_cursor_outer(pos) {
+                    self.set_cursor(extent_i, byte_i)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        anyhow!(
+                            "Attempted to seek past end of extent stream (pos = {} > {} = end)",
+                            pos,
+                            self.len()
+                        ),
+                    ))
+                }
+            }
+            SeekFrom::End(offset) => match calculate_rel(0, u64(self.end()?), i64(offset)) {
+                Ok(pos) => self.seek(SeekFrom::Start(pos)),
+                Err(pos) => err_before_start(pos),
+            },
+            SeekFrom::Current(offset) => {
+                let inner_pos = u64(self.extents_outer[self.cursor.0] + self.cursor.1);
+                match calculate_rel(0, inner_pos, i64(offset)) {
+                    Ok(pos) => self.seek(SeekFrom::Start(pos)),
+                    Err(pos) => err_before_start(pos),
+                }
+            }
+        }
+    }
+}
+
+impl<T: Read + Seek> Read for ExtentStream<T> {
+    fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
+        let mut total = 0;
+        while !buf.is_empty() {
+            match self.next_area() {
+                NextArea::CurrentExtent(rem) => {
+                    let max_len = min(buf.len(), rem);
+                    let len = if self.in_hole() {
+                        buf[..max_len].fill(0);
+                        max_len
+                    } else {
+                        self.inner.read(&mut buf[..max_len])?
+                    };
+                    self.cursor.1 += len;
+
+                    buf = &mut buf[len..];
+                    total += len;
+                    if len == 0 {
+                        break;
+                    }
+                }
+                NextArea::NextExtent(index) => {
+                    self.set_cursor(index, 0)?;
+                }
+                NextArea::None => break,
+            }
+        }
+        Ok(total)
+    }
+}
+
+/// what `fill_buf` returns inside a hole
+static ZEROS: [u8; 4096] = [0; 4096];
+
+/// the buffer is the inner stream's buffer, cut off at the end of the current extent
+impl<T: BufRead + Seek> BufRead for ExtentStream<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        loop {
+            match self.next_area() {
+                NextArea::CurrentExtent(rem) => {
+                    if self.in_hole() {
+                        return Ok(&ZEROS[..min(rem, ZEROS.len())]);
+                    }
+                    let buf = self.inner.fill_buf()?;
+                    return Ok(&buf[..min(buf.len(), rem)]);
+                }
+                NextArea::NextExtent(index) => {
+                    self.set_cursor(index, 0)?;
+                }
+                NextArea::None => return Ok(&[]),
+            }
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if !self.in_hole() {
+            self.inner.consume(amt);
+        }
+        self.cursor.1 += amt;
+    }
+}
+
+impl<T: Write + Seek> Write for ExtentStream<T> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let mut total = 0;
+        while !buf.is_empty() {
+            match self.next_area() {
+                NextArea::CurrentExtent(rem) => {
+                    let max_len = min(buf.len(), rem);
+                    let len =
+                        if self.in_hole() { max_len } else { self.inner.write(&buf[..max_len])? };
+                    self.cursor.1 += len;
+
+                    buf = &buf[len..];
+                    total += len;
+                    if len == 0 {
+                        break;
+                    }
+                }
+                NextArea::NextExtent(index) => {
+                    self.set_cursor(index, 0)?;
+                }
+                NextArea::None => break,
+            }
+        }
+        Ok(total)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn convert_extent(extent: &RawExtent, block_size: usize) -> Result<Extent> {
+    let start_block = extent.start_block.ok_or_else(|| anyhow!("Missing start_block in extent"))?;
+    let num_blocks = extent.num_blocks.ok_or_else(|| anyhow!("Missing num_block in extent"))?;
+    let len = block_size
+        .checked_mul(usize(num_blocks))
+        .ok_or_else(|| anyhow!("Extent of {} blocks is too long", num_blocks))?;
+    if start_block == u64::MAX {
+        return Ok(Extent::hole(len));
+    }
+    let start = block_size
+        .checked_mul(usize(start_block))
+        .ok_or_else(|| anyhow!("Extent starting at block {} is out of range", start_block))?;
+    Ok(Extent { start, len })
+}
+
+/// converts extents from a manifest, which are measured in blocks, into byte ranges
+///
+/// sparse holes (`start_block == u64::MAX`) become hole extents, see `Extent::hole`
+///
+/// errors if `block_size` is 0, if an extent is missing either of its fields or if its byte
+/// range doesn't fit into a usize
+pub fn convert_extents(extents: &[RawExtent], block_size: usize) -> Result<Vec<Extent>> {
+    if block_size == 0 {
+        bail!("Block size cannot be 0")
+    }
+    extents.iter().map(|extent| convert_extent(extent, block_size)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use cast::u8;
+    use once_cell::sync::Lazy;
+    use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+
+    use super::{convert_extents, ExtentStream};
+    use crate::{extent::Extent, update_metadata::Extent as RawExtent};
+
+    static RAW_EXTENTS: Lazy<Vec<RawExtent>> = Lazy::new(|| {
+        vec![(0, 4), (6, 5), (20, 13), (80, 100)]
+            .into_iter()
+            .map(|(start_block, num_blocks)| RawExtent {
+                start_block: Some(start_block),
+                num_blocks: Some(num_blocks),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    const BLOCK_SIZE: usize = 3;
+
+    #[test]
+    fn extent_converter_test() {
+        let extents = convert_extents(RAW_EXTENTS.as_slice(), BLOCK_SIZE).unwrap();
+        assert_eq!(
+            extents,
+            vec![(0, 12), (18, 15), (60, 39), (240, 300)]
+                .into_iter()
+                .map(|(start, len)| Extent { start, len })
+                .collect::<Vec<_>>()
+        )
+    }
+
+    #[test]
+    fn extent_converter_fail_test() {
+        let mut raw_extents = RAW_EXTENTS.clone();
+        raw_extents[2].start_block = None;
+        assert!(convert_extents(raw_extents.as_slice(), BLOCK_SIZE).is_err());
+
+        let mut raw_extents = RAW_EXTENTS.clone();
+        raw_extents[2].num_blocks = None;
+        assert!(convert_extents(raw_extents.as_slice(), BLOCK_SIZE).is_err());
+
+        assert!(convert_extents(RAW_EXTENTS.as_slice(), 0).is_err());
+    }
+
+    #[test]
+    fn extent_converter_overflow_test() {
+        let convert = |start_block, num_blocks| {
+            let raw = RawExtent { start_block: Some(start_block), num_blocks: Some(num_blocks) };
+            convert_extents(&[raw], BLOCK_SIZE)
+        };
+        assert!(convert(u64::MAX - 1, 1).is_err());
+        assert!(convert(0, u64::MAX - 1).is_err());
+        // holes have no start to overflow, but their length still can
+        assert_eq!(convert(u64::MAX, 2).unwrap(), [Extent::hole(6)]);
+        assert!(convert(u64::MAX, u64::MAX - 1).is_err());
+        assert!(convert(u64::MAX, u64::MAX / 3 + 1).is_err());
+    }
+
+    #[test]
+    fn extent_stream_hole_test() {
+        let mut raw_extents = RAW_EXTENTS[..2].to_vec();
+        raw_extents.insert(1, RawExtent { start_block: Some(u64::MAX), num_blocks: Some(1) });
+        let extents = convert_extents(&raw_extents, BLOCK_SIZE).unwrap();
+        assert_eq!(extents[1], Extent::hole(3));
+
+        // the hole reads as zeros without touching the inner stream
+        let data = (1..=33).collect::<Vec<u8>>();
+        let mut stream = ExtentStream::new(Cursor::new(&data), extents.clone()).unwrap().unwrap();
+        let mut read = vec![];
+        stream.read_to_end(&mut read).unwrap();
+        let mut expected = data[..12].to_vec();
+        expected.extend([0; 3]);
+        expected.extend(&data[18..33]);
+        assert_eq!(read, expected);
+
+        // and writes to it are dropped
+        let mut written = vec![0xff; 33];
+        let mut stream = ExtentStream::new(Cursor::new(&mut written), extents).unwrap().unwrap();
+        stream.write_all(&[1; 30]).unwrap();
+        assert_eq!(written[..12], [1; 12]);
+        assert_eq!(written[12..18], [0xff; 6]);
+        assert_eq!(written[18..33], [1; 15]);
+    }
+
+    static EXTENTS: Lazy<Vec<Extent>> = Lazy::new(|| {
+        vec![(0, 3), (5, 2), (7, 3), (20, 5)]
+            .into_iter()
+            .map(|(start, len)| Extent { start, len })
+            .collect::<Vec<_>>()
+    });
+    static EXTENTS_INNER_LEN: Lazy<usize> = Lazy::new(|| {
+        let last = EXTENTS.last().unwrap();
+        last.start + last.len
+    });
+
+    #[test]
+    fn extent_stream_read_test() {
+        let src =
+            (0_u8..u8(*EXTENTS_INNER_LEN + 10).unwrap()).map(|i| 2 * i + 1).collect::<Vec<_>>();
+        let mut stream = ExtentStream::new(Cursor::new(&src), EXTENTS.clone()).unwrap().unwrap();
+        let mut dst = vec![];
+        assert_eq!(stream.read_to_end(&mut dst).unwrap(), 13);
+        assert_eq!(dst, [1, 3, 5, 11, 13, 15, 17, 19, 41, 43, 45, 47, 49]);
+        assert_eq!(stream.read_to_end(&mut dst).unwrap(), 0);
+    }
+
+    #[test]
+    fn extent_stream_write_test() {
+        let src = (0_u8..13_u8).map(|i| 2 * i + 1).collect::<Vec<_>>();
+        let mut dst = vec![0_u8; *EXTENTS_INNER_LEN];
+        let mut stream =
+            ExtentStream::new(Cursor::new(&mut dst), EXTENTS.clone()).unwrap().unwrap();
+        stream.write_all(&src).unwrap();
+        assert_eq!(stream.write(&src).unwrap(), 0);
+
+        assert_eq!(
+            dst,
+            [1, 3, 5, 0, 0, 7, 9, 11, 13, 15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 17, 19, 21, 23, 25]
+        );
+    }
+
+    #[test]
+    fn extent_stream_seek_rw_test() {
+        let mut data = vec![0; *EXTENTS_INNER_LEN];
+        let mut stream =
+            ExtentStream::new(Cursor::new(&mut data), EXTENTS.clone()).unwrap().unwrap();
+
+        // write
+        assert_eq!(stream.seek(SeekFrom::Start(7)).unwrap(), 7);
+        stream.write_all(&[10, 11]).unwrap();
+        stream.write_all(&[13, 14]).unwrap();
+        assert_eq!(stream.seek(SeekFrom::Current(-7)).unwrap(), 4);
+        stream.write_all(&[16, 17]).unwrap();
+        assert_eq!(stream.seek(SeekFrom::End(-2)).unwrap(), 11);
+        stream.write_all(&[19, 20]).unwrap();
+        assert_eq!(stream.write(&[21]).unwrap(), 0);
+
+        // read
+        assert_eq!(stream.seek(SeekFrom::Start(5)).unwrap(), 5);
+        let mut dst = vec![];
+        assert_eq!(stream.read_to_end(&mut dst).unwrap(), 8);
+        assert_eq!(dst, [17, 0, 10, 11, 13, 14, 19, 20]);
+
+        // write
+        assert_eq!(stream.seek(SeekFrom::End(-7)).unwrap(), 6);
+        stream.write_all(&[22, 23]).unwrap();
+
+        // read
+        assert_eq!(stream.seek(SeekFrom::Start(5)).unwrap(), 5);
+        let mut dst = vec![];
+        assert_eq!(stream.read_to_end(&mut dst).unwrap(), 8);
+        assert_eq!(dst, [17, 22, 23, 11, 13, 14, 19, 20]);
+
+        let mut target = vec![0_u8; *EXTENTS_INNER_LEN];
+        let changes = vec![
+            (9, 10),
+            (20, 11),
+            (21, 13),
+            (22, 14),
+            (6, 16),
+            (7, 17),
+            (23, 19),
+            (24, 20),
+            (8, 22),
+            (9, 23),
+        ];
+        for (i, v) in changes {
+            target[i] = v;
+        }
+        assert_eq!(data, target);
+    }
+
+    #[test]
+    fn extent_stream_too_short_read_test() {
+        let src = (0_u8..21_u8).map(|i| 2 * i + 1).collect::<Vec<_>>();
+        let mut stream =
+            ExtentStream::new(Cursor::new(src.as_slice()), EXTENTS.clone()).unwrap().unwrap();
+        let mut dst = vec![];
+        assert_eq!(stream.read_to_end(&mut dst).unwrap(), 9);
+        assert_eq!(dst, [1, 3, 5, 11, 13, 15, 17, 19, 41]);
+        assert_eq!(stream.read_to_end(&mut dst).unwrap(), 0);
+    }
+
+    #[test]
+    fn extent_stream_too_short_write_test() {
+        let src = (0_u8..13_u8).map(|i| 2 * i + 1).collect::<Vec<_>>();
+        let mut dst = vec![0; 9];
+        let mut stream =
+            ExtentStream::new(Cursor::new(dst.as_mut_slice()), EXTENTS.clone()).unwrap().unwrap();
+        assert_eq!(stream.write(&src).unwrap(), 7);
+        assert_eq!(stream.write(&src).unwrap(), 0);
+
+        assert_eq!(dst, [1, 3, 5, 0, 0, 7, 9, 11, 13]);
+    }
+
+    #[test]
+    fn extent_stream_too_short_seek_test() {
+        let data = vec![0; 27];
+        let mut stream = ExtentStream::new(Cursor::new(&data), vec![Extent { start: 10, len: 20 }])
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), 17);
+    }
+
+    /// a stream which can't seek relative to its end, like some slices of bigger streams
+    struct NoEndSeek<T>(T);
+
+    impl<T: Seek> Seek for NoEndSeek<T> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::End(_) => Err(io::ErrorKind::Unsupported.into()),
+                pos => self.0.seek(pos),
+            }
+        }
+    }
+
+    impl<T: Read> Read for NoEndSeek<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn extent_stream_trust_extent_length_test() {
+        let data = (0..30).collect::<Vec<u8>>();
+        let extents =
+            vec![Extent { start: 20, len: 5 }, Extent::hole(3), Extent { start: 2, len: 4 }];
+        let mut stream =
+            ExtentStream::new(NoEndSeek(Cursor::new(&data)), extents.clone()).unwrap().unwrap();
+        assert!(stream.seek(SeekFrom::End(0)).is_err());
+
+        let mut stream = ExtentStream::new(NoEndSeek(Cursor::new(&data)), extents)
+            .unwrap()
+            .unwrap()
+            .trust_extent_length();
+        assert_eq!(stream.seek(SeekFrom::End(-6)).unwrap(), 6);
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [0, 0, 2, 3, 4, 5]);
+        assert!(stream.seek(SeekFrom::End(1)).is_err());
+    }
+
+    #[test]
+    fn extent_stream_suffix_seek_test() {
+        // new_suffix claims a huge length, but seeking to the end stops at the inner stream's end
+        let data = vec![0; 27];
+        let mut stream = ExtentStream::new_suffix(Cursor::new(&data), 10).unwrap();
+        assert_eq!(stream.seek(SeekFrom::End(0)).unwrap(), 17);
+        assert_eq!(stream.seek(SeekFrom::End(-17)).unwrap(), 0);
+    }
+
+    #[test]
+    fn extent_stream_fragmented_seek_test() {
+        // every other byte, so each extent has length 1
+        let extents = (0..5000).map(|i| Extent { start: 2 * i, len: 1 }).collect::<Vec<_>>();
+        let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut stream = ExtentStream::new(Cursor::new(&data), extents).unwrap().unwrap();
+
+        for pos in [4321, 0, 4999, 17, 2500] {
+            assert_eq!(stream.seek(SeekFrom::Start(pos)).unwrap(), pos);
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], data[2 * pos as usize]);
+        }
+        assert_eq!(stream.seek(SeekFrom::Start(5000)).unwrap(), 5000);
+        assert!(stream.seek(SeekFrom::Start(5001)).is_err());
+    }
+
+    #[test]
+    fn extent_stream_merge_test() {
+        let data = (0..10).collect::<Vec<u8>>();
+        let extents = vec![Extent { start: 0, len: 3 }, Extent { start: 3, len: 2 }];
+        let mut stream = ExtentStream::new(Cursor::new(&data), extents).unwrap().unwrap();
+        assert_eq!(stream.extents, [Extent { start: 0, len: 5 }]);
+        assert_eq!(stream.len(), 5);
+
+        let mut read = vec![];
+        stream.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data[..5]);
+        assert_eq!(stream.seek(SeekFrom::Start(3)).unwrap(), 3);
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, [3]);
+    }
+
+    #[test]
+    fn extent_stream_buf_read_test() {
+        let data = (0..30).collect::<Vec<u8>>();
+        let extents =
+            vec![Extent { start: 20, len: 5 }, Extent::hole(3), Extent { start: 2, len: 4 }];
+        let mut stream =
+            ExtentStream::new(BufReader::with_capacity(4, Cursor::new(&data)), extents)
+                .unwrap()
+                .unwrap();
+        // the buffer never goes past the end of an extent
+        assert_eq!(stream.fill_buf().unwrap(), [20, 21, 22, 23]);
+        stream.consume(3);
+        assert_eq!(stream.fill_buf().unwrap(), [23]);
+        stream.consume(1);
+        assert_eq!(stream.fill_buf().unwrap(), [24]);
+        stream.consume(1);
+        assert_eq!(stream.fill_buf().unwrap(), [0, 0, 0]);
+        stream.consume(2);
+        let mut rest = vec![];
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [0, 2, 3, 4, 5]);
+        assert!(stream.fill_buf().unwrap().is_empty());
+
+        // read_until goes through fill_buf and consume, and seeking drops the buffer
+        stream.seek(SeekFrom::Start(1)).unwrap();
+        let mut line = vec![];
+        stream.read_until(3, &mut line).unwrap();
+        assert_eq!(line, [21, 22, 23, 24, 0, 0, 0, 2, 3]);
+    }
+
+    #[test]
+    fn extent_stream_overlap_test() {
+        let data = vec![0; 30];
+        let new = |extents: &[(usize, usize)]| {
+            let extents = extents.iter().map(|&(start, len)| Extent { start, len }).collect();
+            ExtentStream::new_disjoint(Cursor::new(&data), extents).map(|stream| stream.is_some())
+        };
+        // out of order is fine as long as nothing overlaps, and so are repeated holes
+        assert!(new(&[(20, 5), (0, 3), (10, 10)]).unwrap());
+        assert!(new(&[(Extent::HOLE, 4), (0, 3), (Extent::HOLE, 4)]).unwrap());
+        assert!(new(&[(5, 0), (0, 10)]).unwrap());
+
+        let err = new(&[(0, 10), (9, 5)]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(new(&[(20, 5), (0, 3), (2, 2)]).is_err());
+        assert!(new(&[(0, 10), (3, 2)]).is_err());
+        assert!(new(&[(5, usize::MAX - 2)]).is_err());
+
+        // reading the same bytes twice is fine
+        let extents = vec![Extent { start: 0, len: 4 }, Extent { start: 2, len: 4 }];
+        let mut stream = ExtentStream::new(Cursor::new(&data), extents).unwrap().unwrap();
+        let mut read = vec![];
+        stream.read_to_end(&mut read).unwrap();
+        assert_eq!(read.len(), 8);
+    }
+
+    #[test]
+    fn extent_stream_overflow_test() {
+        let new = |extents: &[(usize, usize)]| {
+            let extents = extents.iter().map(|&(start, len)| Extent { start, len }).collect();
+            ExtentStream::new(Cursor::new(&[0; 30]), extents).map(|stream| stream.is_some())
+        };
+        assert!(new(&[(usize::MAX - 4, 4)]).unwrap());
+        // an extent ending past usize::MAX, also when reading doesn't check for overlaps
+        let err = new(&[(usize::MAX - 4, 5)]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // contiguous extents and holes which are merged into one that's too long
+        assert!(new(&[(0, usize::MAX - 4), (usize::MAX - 4, 5)]).is_err());
+        assert!(new(&[(Extent::HOLE, usize::MAX), (Extent::HOLE, 1)]).is_err());
+        // extents whose total length is too long
+        assert!(new(&[(0, usize::MAX / 2 + 1), (Extent::HOLE, usize::MAX / 2 + 1)]).is_err());
+    }
+
+    #[test]
+    fn find_cursor_outer_matches_scan_test() {
+        // thousands of extents of varying length, some of them empty
+        let lens = (0..3000).map(|i| (i * 7) % 5).collect::<Vec<_>>();
+        let extents = lens
+            .iter()
+            .scan(0, |start, &len| {
+                let extent = Extent { start: *start, len };
+                *start += len + 1;
+                Some(extent)
+            })
+            .collect::<Vec<_>>();
+        let stream = ExtentStream::new(Cursor::new(vec![]), extents).unwrap().unwrap();
+
+        // the linear scan find_cursor_outer used before
+        let scan = |outer_pos| {
+            for i in 0..stream.extents.len() {
+                if stream.extents_outer[i] <= outer_pos && outer_pos < stream.extents_outer[i + 1] {
+                    return Some((i, outer_pos - stream.extents_outer[i]));
+                }
+            }
+            (outer_pos == stream.len())
+                .then(|| (stream.extents.len() - 1, stream.extents.last().unwrap().len))
+        };
+        for pos in 0..=stream.len() + 2 {
+            assert_eq!(stream.find_cursor_outer(pos), scan(pos), "position {}", pos);
+        }
+    }
+
+    #[test]
+    fn extent_stream_seek_fail_test() {
+        let data = vec![0_u8; *EXTENTS_INNER_LEN];
+        let mut stream = ExtentStream::new(Cursor::new(&data), EXTENTS.clone()).unwrap().unwrap();
+
+        // start
+        assert!(stream.seek(SeekFrom::Start(0)).is_ok());
+        assert!(stream.seek(SeekFrom::Start(5)).is_ok());
+        assert!(stream.seek(SeekFrom::Start(13)).is_ok());
+        assert!(stream.seek(SeekFrom::Start(14)).is_err());
+        assert!(stream.seek(SeekFrom::Start(20)).is_err());
+
+        // end
+        assert!(stream.seek(SeekFrom::End(-15)).is_err());
+        assert!(stream.seek(SeekFrom::End(-14)).is_err());
+        assert!(stream.seek(SeekFrom::End(-13)).is_ok());
+        assert!(stream.seek(SeekFrom::End(-5)).is_ok());
+        assert!(stream.seek(SeekFrom::End(0)).is_ok());
+        assert!(stream.seek(SeekFrom::End(1)).is_err());
+
+        // current
+        assert!(stream.seek(SeekFrom::Start(5)).is_ok());
+        assert!(stream.seek(SeekFrom::Current(-7)).is_err());
+        assert!(stream.seek(SeekFrom::Current(-6)).is_err());
+        assert!(stream.seek(SeekFrom::Current(-5)).is_ok());
+
+        assert!(stream.seek(SeekFrom::Start(5)).is_ok());
+        assert!(stream.seek(SeekFrom::Current(-3)).is_ok());
+
+        assert!(stream.seek(SeekFrom::Start(5)).is_ok());
+        assert!(stream.seek(SeekFrom::Current(8)).is_ok());
+
+        assert!(stream.seek(SeekFrom::Start(5)).is_ok());
+        assert!(stream.seek(SeekFrom::Current(9)).is_err());
+    }
+}
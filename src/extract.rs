@@ -1,29 +1,91 @@
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use base64::prelude::*;
 use bzip2::read::BzDecoder;
-use cast::{i64, u64, usize};
-use sha2::{Digest, Sha256};
+use cast::{u64, usize};
+use clap::ValueEnum;
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use std::{
-    fs::{self, File},
-    io::{self, Read, Seek, Write},
-    path::Path,
+    cmp::min,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
 
 use crate::{
-    extract::bspatch::bspatch,
-    parse_parts,
+    boot_image::{is_boot_partition, BootImageHeader},
+    bspatch,
+    diagnostic::{self, Code},
+    extent::{convert_extents, ExtentStream},
+    flash,
+    patch_level::{check_min_patch_level, PatchLevel},
+    path_template::{relative_path, PathFields, PathTemplate},
+    payload::{data_extents, PayloadLayout},
+    progress::{format_size, BytesProgress, ProgressSink},
     update_metadata::{
-        install_operation::Type as OperationType, DeltaArchiveManifest, PartitionUpdate,
-        DEFAULT_BLOCK_SIZE,
+        install_operation::Type as OperationType, DeltaArchiveManifest, Extent as RawExtent,
+        InstallOperation, PartitionUpdate, DEFAULT_BLOCK_SIZE,
     },
-    ExtractArgs,
+    HasUpdateType, UpdateType,
 };
 
-use self::extent::{convert_extents, ExtentStream};
+use self::{
+    archive::archive_ops,
+    budget::Budget,
+    care_map::{write_care_map, written_ranges},
+    checkpoint::Checkpoint,
+    checksums::write_checksums,
+    dry_run::dry_run,
+    ext4::{changed_files, Ext4},
+    forward::ForwardWriter,
+    hash::{HashKind, HashingReader, HashingWriter},
+    overlap::WrittenBlocks,
+    profile::{PhaseTimes, Profile, Timed},
+    report::{PartReport, Report},
+    sparse::convert_to_sparse,
+    src_image::SrcImage,
+    super_image::{write_super_image, LogicalImage},
+};
 
-mod bspatch;
-mod extent;
+/// set while an image is written to stdout, so the status messages don't end up in it
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// prints a status message to stdout, or to stderr while an image is written to stdout
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::extract::STATUS_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod archive;
+mod budget;
+mod care_map;
+mod checkpoint;
+mod checksums;
+mod dry_run;
+mod ext4;
+mod forward;
+pub mod hash;
+mod overlap;
+mod profile;
+mod report;
+mod sparse;
+mod src_image;
+mod super_image;
 
 trait StreamRead: Read + Seek {}
 impl<T: Read + Seek> StreamRead for T {}
@@ -31,29 +93,53 @@ impl<T: Read + Seek> StreamRead for T {}
 trait StreamWrite: Write + Seek {}
 impl<T: Write + Seek> StreamWrite for T {}
 
-pub fn calculate_rel(start: u64, pos: u64, offset: i64) -> Result<u64, i64> {
-    let abs_pos = i64(pos).unwrap() + offset;
-    if let Ok(abs_pos) = u64(abs_pos) {
-        if abs_pos >= start {
-            return Ok(abs_pos);
-        }
+/// copies `src` to `dst` through `buffer`, then pads `dst` with zeros up to `len` bytes
+fn copy_padded(
+    src: &mut impl Read,
+    dst: &mut impl Write,
+    len: usize,
+    buffer: &mut [u8],
+) -> io::Result<()> {
+    let mut written = 0;
+    loop {
+        let read = match src.read(buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        dst.write_all(&buffer[..read])?;
+        written += read;
+    }
+    buffer.fill(0);
+    while written < len {
+        let chunk = min(len - written, buffer.len());
+        dst.write_all(&buffer[..chunk])?;
+        written += chunk;
     }
-    Err(abs_pos)
-}
-
-fn copy_padded(src: &mut impl Read, dst: &mut impl Write, len: usize) -> io::Result<()> {
-    let written = io::copy(src, dst)?;
-    io::copy(&mut io::repeat(0).take(u64(len).saturating_sub(written)), dst)?;
     Ok(())
 }
 
-fn check_hash(stream: &mut (impl Read + Seek), expected_hash: &[u8]) -> Result<()> {
+/// hashes the rest of the stream, leaving its position unchanged
+fn hash_stream(stream: &mut (impl Read + Seek), kind: HashKind) -> Result<Vec<u8>> {
     let pos = stream.stream_position()?;
-    let mut hasher = Sha256::new();
+    let mut hasher = kind.hasher();
     io::copy(stream, &mut hasher)?;
     stream.seek(io::SeekFrom::Start(pos))?;
-    let hash = hasher.finalize();
-    if hash.as_slice() != expected_hash {
+    Ok(hasher.finalize())
+}
+
+/// checks the hash of the rest of the stream, leaving its position unchanged
+pub fn check_hash(
+    stream: &mut (impl Read + Seek),
+    kind: HashKind,
+    expected_hash: &[u8],
+) -> Result<()> {
+    compare_hash(&hash_stream(stream, kind)?, expected_hash)
+}
+
+fn compare_hash(hash: &[u8], expected_hash: &[u8]) -> Result<()> {
+    if hash != expected_hash {
         bail!(
             "Found hash {} but expected {}",
             BASE64_STANDARD.encode(hash),
@@ -63,151 +149,2427 @@ fn check_hash(stream: &mut (impl Read + Seek), expected_hash: &[u8]) -> Result<(
     Ok(())
 }
 
+/// settings used while processing a single partition
+struct PartConfig<'a> {
+    args: &'a ExtractOptions,
+    layout: PayloadLayout,
+    block_size: usize,
+    progress: &'a dyn ProgressSink,
+}
+
+/// what an operation type reads as `(src, data)`, or none if `apply_op` can't apply it
+fn op_inputs(op_type: OperationType) -> Option<(bool, bool)> {
+    use OperationType::*;
+    match op_type {
+        Replace | ReplaceBz | ReplaceXz | ReplaceZstd => Some((false, true)),
+        Zero => Some((false, false)),
+        SourceCopy | Move => Some((true, false)),
+        SourceBsdiff | BrotliBsdiff | Bsdiff => Some((true, true)),
+        _ => None,
+    }
+}
+
+/// applies operation `i` of partition `name`, reading its src and data and writing its dst
+/// extents
+#[allow(clippy::too_many_arguments)]
+fn apply_op(
+    name: &str,
+    i: usize,
+    op: &InstallOperation,
+    op_type: OperationType,
+    data: &mut (impl Read + Seek),
+    src: Option<&mut (impl Read + Seek)>,
+    dst: &mut (impl Write + Seek),
+    config: &PartConfig,
+    times: &PhaseTimes,
+    buffer: &mut [u8],
+) -> Result<()> {
+    let args = config.args;
+    let block_size = config.block_size;
+    let (reads_src, reads_data) = op_inputs(op_type)
+        .ok_or_else(|| anyhow!("Unsupported operation type {} for op {}", op.r#type, i))?;
+    let mut src = src
+        .filter(|_| reads_src)
+        .map(|src| -> Result<_> {
+            Ok(ExtentStream::new(
+                src,
+                convert_extents(&op.src_extents, block_size)
+                    .with_context(|| format!("Failed to parse src_extents"))?,
+            )?)
+        })
+        .transpose()
+        .with_context(|| format!("Error while constructing src stream"))?
+        .flatten();
+    let mut part_dst = ExtentStream::new_disjoint(
+        &mut *dst,
+        convert_extents(&op.dst_extents, block_size)
+            .with_context(|| format!("Failed to parse dst_extents"))?,
+    )
+    .with_context(|| format!("Error while constructing dst stream"))?
+    .ok_or_else(|| anyhow!("No dst extents"))?;
+    let mut data = data_extents(&config.layout, op)
+        .filter(|_| reads_data)
+        .map(|extents| ExtentStream::new(&mut *data, extents))
+        .transpose()
+        .with_context(|| format!("Error while constructing data stream"))?
+        .flatten();
+
+    if !args.skips_hash(HashCheck::Src) {
+        if let (Some(src), Some(hash)) = (src.as_mut(), op.src_sha256_hash.as_deref()) {
+            times
+                .time(&times.hash, || check_hash(src, HashKind::Sha256, hash))
+                .with_context(|| format!("Error ocurred while checking src hash"))?;
+        }
+    }
+    let data_hash = op.data_sha256_hash.as_deref().filter(|_| !args.skips_hash(HashCheck::Data));
+    // the data of replace operations is hashed while it's decompressed instead of being read twice
+    let replace = matches!(
+        op_type,
+        OperationType::Replace
+            | OperationType::ReplaceBz
+            | OperationType::ReplaceXz
+            | OperationType::ReplaceZstd
+    );
+    if !replace {
+        if let (Some(data), Some(hash)) = (data.as_mut(), data_hash) {
+            times
+                .time(&times.hash, || check_hash(data, HashKind::Sha256, hash))
+                .with_context(|| format!("Error ocurred while checking data hash"))?;
+        }
+    }
+
+    let dst_len = part_dst.len();
+    // when dumping each op, the op is applied to its own file which is then copied into place
+    let mut op_file = args
+        .per_op_output
+        .as_ref()
+        .map(|dir| File::create(Path::new(dir).join(format!("{}_op{}.bin", name, i))))
+        .transpose()
+        .with_context(|| format!("Failed to create per op output file"))?;
+    let op_dst: &mut dyn StreamWrite = match op_file.as_mut() {
+        Some(op_file) => op_file,
+        None => &mut part_dst,
+    };
+    let mut dst = Timed::new(op_dst, &times.write);
+    let src = src.map(|src| Timed::new(src, &times.read));
+    let data = data.map(|data| Timed::new(data, &times.read));
+
+    match &op_type {
+        // replace: data -> dst
+        OperationType::Replace
+        | OperationType::ReplaceBz
+        | OperationType::ReplaceXz
+        | OperationType::ReplaceZstd => {
+            let data = data.ok_or_else(|| anyhow!("No data given for replace operation"))?;
+            let mut data = HashingReader::new(data, data_hash.map(|_| HashKind::Sha256));
+
+            let mut decoder: Box<dyn Read> = match &op_type {
+                OperationType::Replace => Box::new(&mut data),
+                OperationType::ReplaceBz => Box::new(BzDecoder::new(&mut data)),
+                OperationType::ReplaceXz => Box::new(XzDecoder::new(&mut data)),
+                OperationType::ReplaceZstd => Box::new(ZstdDecoder::new(&mut data)?),
+                _ => unreachable!(),
+            };
+            let written = times.time_excluding_io(&times.decompress, || {
+                copy_padded(&mut decoder, &mut dst, dst_len, buffer)
+            });
+            drop(decoder);
+            let written = written.with_context(|| format!("Error while writing output"));
+
+            // corrupt data usually fails to decompress too, but the hash mismatch says why;
+            // transient errors are returned as they are, so the operation is retried right away
+            if written.as_ref().map_or_else(|err| !is_transient(err), |()| true) {
+                if let (Some(hash), Some(expected_hash)) = (data.finalize()?, data_hash) {
+                    compare_hash(&hash, expected_hash)
+                        .with_context(|| format!("Error ocurred while checking data hash"))?;
+                }
+            }
+            written?;
+        }
+        // remove: _ -> dst
+        OperationType::Zero => {
+            copy_padded(&mut io::empty(), &mut dst, dst_len, buffer)
+                .with_context(|| format!("Error while writing output"))?;
+        }
+
+        // diff ops; require src, which for the in place versions is the image being written,
+        // read ahead by `apply_in_place`
+        // copy: src -> dst, where move is the in place version
+        OperationType::SourceCopy | OperationType::Move => {
+            let mut src = src.ok_or_else(|| anyhow!("No src given for copy operation"))?;
+
+            copy_padded(&mut src, &mut dst, dst_len, buffer)
+                .with_context(|| format!("Error while writing output"))?;
+        }
+        // bsdiff: src + data -> dst, where bsdiff is the in place version
+        OperationType::SourceBsdiff | OperationType::BrotliBsdiff | OperationType::Bsdiff => {
+            let mut src = src.ok_or_else(|| anyhow!("No src given for bsdiff operation"))?;
+            let mut data_vec = vec![];
+            data.ok_or_else(|| anyhow!("No data given for bsdiff operation"))?
+                .read_to_end(&mut data_vec)
+                .with_context(|| format!("Error ocurred while reading patch data"))?;
+
+            times
+                .time_excluding_io(&times.patch, || bspatch(&mut src, &mut dst, &data_vec))
+                .with_context(|| format!("Error ocurred applying patch"))?;
+        }
+        _ => unreachable!(),
+    }
+
+    if let Some(op_file) = op_file.as_mut() {
+        op_file.rewind()?;
+        copy_padded(op_file, &mut part_dst, dst_len, buffer)
+            .with_context(|| format!("Error while writing output"))?;
+    }
+    Ok(())
+}
+
+/// the block ranges of extents, skipping incomplete ones and sparse holes
+fn block_ranges(extents: &[RawExtent]) -> impl Iterator<Item = Range<u64>> + '_ {
+    extents.iter().filter_map(|extent| {
+        let start = extent.start_block?;
+        Some(start..start.checked_add(extent.num_blocks?)?)
+    })
+}
+
+/// the dst block ranges of an operation, skipping incomplete extents
+fn dst_block_ranges(op: &InstallOperation) -> impl Iterator<Item = Range<u64>> + '_ {
+    block_ranges(&op.dst_extents)
+}
+
+/// the bytes the extents cover, saturating since the extents aren't checked yet
+fn extents_bytes(extents: &[RawExtent], block_size: usize) -> u64 {
+    block_ranges(extents)
+        .fold(0, |sum: u64, blocks| sum.saturating_add(blocks.end - blocks.start))
+        .saturating_mul(u64(block_size))
+}
+
+/// the bytes an operation writes
+fn op_dst_bytes(op: &InstallOperation, block_size: usize) -> u64 {
+    extents_bytes(&op.dst_extents, block_size)
+}
+
+/// the bytes all operations of a partition write, which progress is reported against
+fn part_dst_bytes(part: &PartitionUpdate, block_size: usize) -> u64 {
+    part.operations.iter().fold(0, |sum, op| sum.saturating_add(op_dst_bytes(op, block_size)))
+}
+
+/// whether the operation is one of the deprecated non A/B ones, which read their src extents
+/// from the partition being updated instead of a separate src image
+fn reads_in_place(op: &InstallOperation) -> bool {
+    op.r#type == OperationType::Move as i32 || op.r#type == OperationType::Bsdiff as i32
+}
+
+/// whether the operation reads a src image other than the partition being updated
+fn reads_separate_src(op: &InstallOperation) -> bool {
+    !op.src_extents.is_empty() && !reads_in_place(op)
+}
+
+/// whether the partition is updated in place, so its image starts out as the src image and its
+/// operations read what the ones before them wrote, see `image_src`
+fn updates_in_place(part: &PartitionUpdate) -> bool {
+    part.operations.iter().any(reads_in_place)
+}
+
+/// roughly the most memory extracting the partition holds at once, for --max-total-memory:
+/// operations applied on --threads workers or in place are read into memory along with their
+/// output, a couple for each worker, and diff operations read their whole patch and src
+fn part_memory(part: &PartitionUpdate, block_size: usize, args: &ExtractOptions) -> u64 {
+    let parallel = args.threads > 1 && !updates_in_place(part);
+    let op_memory = |op: &InstallOperation| {
+        let read =
+            extents_bytes(&op.src_extents, block_size).saturating_add(op.data_length.unwrap_or(0));
+        let op_type = OperationType::try_from(op.r#type).ok();
+        if parallel || reads_in_place(op) {
+            read.saturating_add(op_dst_bytes(op, block_size))
+        } else if op_type.and_then(op_inputs) == Some((true, true)) {
+            read
+        } else {
+            0
+        }
+    };
+    let in_flight = if parallel { 2 * args.threads } else { 1 };
+    let largest = part.operations.iter().map(op_memory).max().unwrap_or(0);
+    largest
+        .saturating_mul(u64(in_flight))
+        .saturating_add(u64(args.io_buffer_size).saturating_mul(u64(args.threads)))
+}
+
+/// how many files extracting the partition has open at once, for --max-open-files: the payload,
+/// the image and a second handle to read it back, the src image and the file of the current
+/// operation for --per-op-output, and for a partition updated in place the image once more
+fn part_files(part: &PartitionUpdate, args: &ExtractOptions) -> u64 {
+    3 + u64::from(args.src.is_some())
+        + u64::from(args.per_op_output.is_some())
+        + u64::from(updates_in_place(part))
+}
+
+/// the src the operations of the partition read from: the src image, or for a partition updated
+/// in place the image at `path`, which is first filled with the src image
+///
+/// `image` is the image the operations write, which is opened again for reading so the reads
+/// don't move the position the writes continue from
+fn image_src(
+    part: &PartitionUpdate,
+    src: Option<BufReader<Box<dyn StreamRead>>>,
+    image: &mut File,
+    path: &Path,
+) -> Result<Option<BufReader<Box<dyn StreamRead>>>> {
+    let name = &part.partition_name;
+    if !updates_in_place(part) {
+        return Ok(src);
+    }
+    // the src image is overwritten as the operations go, so nothing else can read it
+    if let Some(i) = part.operations.iter().position(reads_separate_src) {
+        bail!("{} op #{} reads a separate src image, but {} is updated in place", name, i, name);
+    }
+    let mut src =
+        src.ok_or_else(|| anyhow!("No src given for {}, which is updated in place", name))?;
+    io::copy(&mut src, image).with_context(|| format!("Failed to copy the src image"))?;
+    if let Some(size) = part.new_partition_info.as_ref().and_then(|info| info.size) {
+        image.set_len(size)?;
+    }
+    image.rewind()?;
+    let image: Box<dyn StreamRead> = Box::new(File::open(path)?);
+    Ok(Some(BufReader::new(image)))
+}
+
+/// records the dst blocks of operation `i`, warning or with `strict` failing if a previous
+/// operation already wrote some of them
+fn check_overlap(
+    written: &mut WrittenBlocks,
+    part: &PartitionUpdate,
+    i: usize,
+    strict: bool,
+) -> Result<()> {
+    for blocks in dst_block_ranges(&part.operations[i]) {
+        let Some(overlap) = written.insert(blocks) else {
+            continue;
+        };
+        let message = format!(
+            "{} op #{} writes dst blocks {}..{} which a previous operation already wrote",
+            part.partition_name, i, overlap.start, overlap.end
+        );
+        if strict {
+            bail!(message);
+        }
+        diagnostic::warn(Code::DstOverlap, message);
+    }
+    Ok(())
+}
+
+/// whether an operation which failed with `err` may succeed when it's applied again
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().filter_map(|cause| cause.downcast_ref::<io::Error>()).any(|err| {
+        matches!(
+            err.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+        )
+    })
+}
+
+/// applies the operations of the partition to `dst`, where for a partition which
+/// `updates_in_place`, `src` reads the image `dst` writes
 fn process_part(
-    manifest: &DeltaArchiveManifest,
     part: &PartitionUpdate,
     data: &mut (impl Read + Seek),
     mut src: Option<&mut (impl Read + Seek)>,
     dst: &mut (impl Write + Seek),
-    skip_hash: bool,
+    config: &PartConfig,
+    profile: &mut Profile,
+    mut checkpoint: Option<&mut Checkpoint>,
 ) -> Result<()> {
-    let block_size = usize(manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE));
-    for i in 0..part.operations.len() {
+    let args = config.args;
+    let start = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.next_op());
+    if start > 0 {
+        status!("resuming from operation #{}", start);
+    }
+    let mut buffer = vec![0; args.io_buffer_size];
+    let mut end = part.operations.len();
+    if let Some(max_ops) = args.max_ops {
+        if max_ops < end {
+            status!("only applying the first {} of {} operations", max_ops, end);
+            diagnostic::warn(
+                Code::IncompleteImage,
+                format!(
+                    "only the first {} of {} operations of {} were applied, \
+                    the image is incomplete and must not be flashed",
+                    max_ops, end, part.partition_name
+                ),
+            );
+            end = max_ops;
+        }
+    }
+    let mut written = (args.check_overlap || args.strict).then(WrittenBlocks::default);
+    if let Some(written) = written.as_mut() {
+        // the operations before a resumed one were checked by the previous run
+        for op in &part.operations[..start] {
+            for blocks in dst_block_ranges(op) {
+                written.insert(blocks);
+            }
+        }
+    }
+    let mut bytes = BytesProgress {
+        done: part.operations[..start]
+            .iter()
+            .fold(0, |sum, op| sum.saturating_add(op_dst_bytes(op, config.block_size))),
+        total: part_dst_bytes(part, config.block_size),
+    };
+    // operations read ahead on other threads wouldn't see what the in place ones before them wrote
+    if args.threads > 1 && !updates_in_place(part) {
+        let ops = OpRange { part, ops: start..end, written: written.as_mut(), bytes };
+        return apply_ops_parallel(ops, data, src, dst, config, profile, checkpoint);
+    }
+    for i in start..end {
+        let op_type = prepare_op(part, i, written.as_mut(), &mut bytes, config)?;
         let op = &part.operations[i];
-        // Unsupported operations:
-        // - Discard: not sure what this does
-        // - Move, Bsdiff: deprecated non A/B versions of SourceCopy and SourceBsdiff
-        // - Puffdiff, Zucchini, Lz4diffBsdiff, Lz4diffPuffdiff - TODO maybe
-        let op_type = OperationType::try_from(op.r#type)
-            .map_err(|_| anyhow!("Invalid operation type {} for op {}", op.r#type, i))?;
-        println!("applying operation #{}: {:?}", i, op_type);
-
-        let mut src = src
-            .as_deref_mut()
-            .map(|src| -> Result<_> {
-                Ok(ExtentStream::new(
-                    src,
-                    convert_extents(&op.src_extents, block_size)
-                        .with_context(|| format!("Failed to parse src_extents"))?,
-                )?)
-            })
-            .transpose()
-            .with_context(|| format!("Error while constructing src stream"))?
-            .flatten();
-        let mut dst = ExtentStream::new(
-            &mut *dst,
-            convert_extents(&op.dst_extents, block_size)
-                .with_context(|| format!("Failed to parse dst_extents"))?,
-        )
-        .with_context(|| format!("Error while constructing dst stream"))?
-        .ok_or_else(|| anyhow!("No dst extents"))?;
-        let mut data = op
-            .data_offset
-            .zip(op.data_length)
-            .map(|(data_offset, data_len)| {
-                ExtentStream::new_range(&mut *data, usize(data_offset), usize(data_len))
-            })
-            .transpose()
-            .with_context(|| format!("Error while constructing data stream"))?;
 
-        if !skip_hash {
-            if let (Some(src), Some(hash)) = (src.as_mut(), op.src_sha256_hash.as_deref()) {
-                check_hash(src, hash)
-                    .with_context(|| format!("Error ocurred while checking src hash"))?;
-            }
-            if let (Some(data), Some(hash)) = (data.as_mut(), op.data_sha256_hash.as_deref()) {
-                check_hash(data, hash)
-                    .with_context(|| format!("Error ocurred while checking data hash"))?;
+        let mut attempt = 0;
+        loop {
+            let result = if reads_in_place(op) {
+                let image = src.as_deref_mut().ok_or_else(|| anyhow!("No src given for op {}", i));
+                image.and_then(|image| {
+                    apply_in_place(part, i, op_type, data, image, dst, config, profile, &mut buffer)
+                })
+            } else {
+                apply_op(
+                    &part.partition_name,
+                    i,
+                    op,
+                    op_type,
+                    &mut *data,
+                    src.as_deref_mut(),
+                    &mut *dst,
+                    config,
+                    profile.times(op_type),
+                    &mut buffer,
+                )
+            };
+            match result {
+                Ok(()) => break,
+                Err(err) if attempt < args.op_retries && is_transient(&err) => {
+                    attempt += 1;
+                    status!("retrying operation #{} ({}/{})", i, attempt, args.op_retries);
+                    diagnostic::warn(
+                        Code::OpRetried,
+                        format!("{} op #{} was retried after: {:#}", part.partition_name, i, err),
+                    );
+                }
+                Err(err) => return Err(err),
             }
         }
+        if let Some(checkpoint) = checkpoint.as_deref_mut() {
+            dst.flush()?;
+            checkpoint.record(i + 1)?;
+        }
+    }
 
-        let dst_len = dst.len();
+    Ok(())
+}
 
-        match &op_type {
-            // replace: data -> dst
-            OperationType::Replace | OperationType::ReplaceBz | OperationType::ReplaceXz => {
-                let data = data.ok_or_else(|| anyhow!("No data given for replace operation"))?;
+/// checks the blocks operation `i` reads and writes against the ones previous operations wrote,
+/// and reports it as started, counting its bytes as done afterwards; returns its type
+fn prepare_op(
+    part: &PartitionUpdate,
+    i: usize,
+    written: Option<&mut WrittenBlocks>,
+    bytes: &mut BytesProgress,
+    config: &PartConfig,
+) -> Result<OperationType> {
+    if let Some(written) = written {
+        check_overlap(written, part, i, config.args.strict)?;
+    }
+    let op = &part.operations[i];
+    // Unsupported operations:
+    // - Discard: not sure what this does
+    // - Puffdiff, Zucchini, Lz4diffBsdiff, Lz4diffPuffdiff - TODO maybe
+    let op_type = OperationType::try_from(op.r#type)
+        .map_err(|_| anyhow!("Invalid operation type {} for op {}", op.r#type, i))?;
+    config.progress.on_operation(&part.partition_name, i, op_type, *bytes);
+    bytes.done = bytes.done.saturating_add(op_dst_bytes(op, config.block_size));
+    Ok(op_type)
+}
 
-                let mut data: Box<dyn Read> = match &op_type {
-                    OperationType::Replace => Box::new(data),
-                    OperationType::ReplaceBz => Box::new(BzDecoder::new(data)),
-                    OperationType::ReplaceXz => Box::new(XzDecoder::new(data)),
-                    _ => unreachable!(),
-                };
+/// applies in place operation `i`, whose src extents are read from `image`, the image `dst`
+/// writes: they're read before anything is written, since the operation may write the blocks
+/// it reads, like update_engine's in place operations
+#[allow(clippy::too_many_arguments)]
+fn apply_in_place(
+    part: &PartitionUpdate,
+    i: usize,
+    op_type: OperationType,
+    data: &mut (impl Read + Seek),
+    image: &mut (impl Read + Seek),
+    dst: &mut (impl Write + Seek),
+    config: &PartConfig,
+    profile: &mut Profile,
+    buffer: &mut [u8],
+) -> Result<()> {
+    // the image is read through another file handle, which only sees what was flushed
+    dst.flush()?;
+    let job = OpJob::read(part, i, op_type, data, Some(image), config, profile.times(op_type))?;
+    let output = job.apply(&part.partition_name, config, profile, buffer)?;
+    write_output(&part.operations[i], &output, dst, config, profile.times(op_type))
+}
+
+/// writes the output of an operation, its dst extents back to back, to the dst extents
+fn write_output(
+    op: &InstallOperation,
+    output: &[u8],
+    dst: &mut (impl Write + Seek),
+    config: &PartConfig,
+    times: &PhaseTimes,
+) -> Result<()> {
+    let mut part_dst = ExtentStream::new_disjoint(
+        &mut *dst,
+        convert_extents(&op.dst_extents, config.block_size)?,
+    )?
+    .ok_or_else(|| anyhow!("No dst extents"))?;
+    times
+        .time(&times.write, || part_dst.write_all(output))
+        .with_context(|| format!("Error while writing output"))
+}
+
+/// replaces extents with a single one starting at block 0 which covers as many blocks
+fn contiguous_extents(extents: &[RawExtent]) -> Vec<RawExtent> {
+    if extents.is_empty() {
+        return vec![];
+    }
+    let num_blocks = extents.iter().map(|extent| extent.num_blocks.unwrap_or(0)).sum();
+    vec![RawExtent { start_block: Some(0), num_blocks: Some(num_blocks) }]
+}
+
+/// an operation whose src and data were read ahead, so it can be applied on another thread
+struct OpJob {
+    i: usize,
+    op_type: OperationType,
+    /// the operation with its src and dst extents moved to the start of `src` and of the output,
+    /// and its data to the start of `data`
+    op: InstallOperation,
+    src: Option<Vec<u8>>,
+    data: Vec<u8>,
+}
+
+impl OpJob {
+    fn read(
+        part: &PartitionUpdate,
+        i: usize,
+        op_type: OperationType,
+        data: &mut (impl Read + Seek),
+        src: Option<&mut (impl Read + Seek)>,
+        config: &PartConfig,
+        times: &PhaseTimes,
+    ) -> Result<Self> {
+        let op = &part.operations[i];
+        // the dst extents are only used once the output is written, so check them up front
+        convert_extents(&op.dst_extents, config.block_size)
+            .with_context(|| format!("Failed to parse dst_extents"))?;
 
-                copy_padded(&mut data, &mut dst, dst_len)
-                    .with_context(|| format!("Error while writing output"))?;
+        let mut data_buf = vec![];
+        if let Some(extents) = data_extents(&config.layout, op) {
+            if let Some(mut data) = ExtentStream::new(&mut *data, extents)? {
+                times
+                    .time(&times.read, || data.read_to_end(&mut data_buf))
+                    .with_context(|| format!("Error ocurred while reading data"))?;
             }
-            // remove: _ -> dst
-            OperationType::Zero => {
-                copy_padded(&mut io::empty(), &mut dst, dst_len)
-                    .with_context(|| format!("Error while writing output"))?;
+        }
+        let mut src_buf = None;
+        if let Some(src) = src {
+            let extents = convert_extents(&op.src_extents, config.block_size)
+                .with_context(|| format!("Failed to parse src_extents"))?;
+            if let Some(mut src) = ExtentStream::new(src, extents)? {
+                let mut buf = vec![];
+                times
+                    .time(&times.read, || src.read_to_end(&mut buf))
+                    .with_context(|| format!("Error ocurred while reading src"))?;
+                src_buf = Some(buf);
+            }
+        }
+
+        let op = InstallOperation {
+            src_extents: contiguous_extents(&op.src_extents),
+            dst_extents: contiguous_extents(&op.dst_extents),
+            data_offset: op.data_offset.map(|_| 0),
+            ..op.clone()
+        };
+        Ok(Self { i, op_type, op, src: src_buf, data: data_buf })
+    }
+
+    /// applies the operation, returning its dst extents back to back
+    fn apply(
+        self,
+        name: &str,
+        config: &PartConfig,
+        profile: &mut Profile,
+        buffer: &mut [u8],
+    ) -> Result<Vec<u8>> {
+        let config = PartConfig { layout: PayloadLayout { data_offset: 0 }, ..*config };
+        let mut dst = Cursor::new(vec![]);
+        apply_op(
+            name,
+            self.i,
+            &self.op,
+            self.op_type,
+            &mut Cursor::new(self.data),
+            self.src.map(Cursor::new).as_mut(),
+            &mut dst,
+            &config,
+            profile.times(self.op_type),
+            buffer,
+        )?;
+        Ok(dst.into_inner())
+    }
+}
+
+/// operations of a partition which are left to apply
+struct OpRange<'a> {
+    part: &'a PartitionUpdate,
+    ops: Range<usize>,
+    written: Option<&'a mut WrittenBlocks>,
+    /// the progress up to the first operation of the range
+    bytes: BytesProgress,
+}
+
+/// applies operations on --threads workers, which only decompress and patch: the src and data
+/// are read and the outputs written in order on this thread, so operations which write the same
+/// blocks still end up in order and the dst image is only written from one place
+fn apply_ops_parallel(
+    ops: OpRange,
+    data: &mut (impl Read + Seek),
+    mut src: Option<&mut (impl Read + Seek)>,
+    dst: &mut (impl Write + Seek),
+    config: &PartConfig,
+    profile: &mut Profile,
+    mut checkpoint: Option<&mut Checkpoint>,
+) -> Result<()> {
+    let OpRange { part, ops, mut written, mut bytes } = ops;
+    let args = config.args;
+    let name = part.partition_name.as_str();
+    let (job_tx, job_rx) = mpsc::channel::<OpJob>();
+    let job_rx = Mutex::new(job_rx);
+    let (output_tx, output_rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        let workers = (0..args.threads)
+            .map(|_| {
+                let (job_rx, output_tx) = (&job_rx, output_tx.clone());
+                scope.spawn(move || {
+                    let mut profile = Profile::default();
+                    let mut buffer = vec![0; args.io_buffer_size];
+                    while let Ok(job) = job_rx.lock().unwrap().recv() {
+                        let (i, op_type) = (job.i, job.op_type);
+                        let output = job.apply(name, config, &mut profile, &mut buffer);
+                        if output_tx.send((i, op_type, output)).is_err() {
+                            break;
+                        }
+                    }
+                    profile
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(output_tx);
+
+        let mut apply_all = || -> Result<()> {
+            let mut outputs = BTreeMap::new();
+            let mut next_read = ops.start;
+            for i in ops.clone() {
+                // keep every worker busy with a couple of operations while waiting for this one
+                while next_read < ops.end && next_read < i + 2 * args.threads {
+                    let op_type =
+                        prepare_op(part, next_read, written.as_deref_mut(), &mut bytes, config)?;
+                    let times = profile.times(op_type);
+                    let job = OpJob::read(
+                        part,
+                        next_read,
+                        op_type,
+                        &mut *data,
+                        src.as_deref_mut(),
+                        config,
+                        times,
+                    )?;
+                    job_tx.send(job).map_err(|_| anyhow!("The operation workers stopped"))?;
+                    next_read += 1;
+                }
+                let (op_type, output) = loop {
+                    if let Some(output) = outputs.remove(&i) {
+                        break output;
+                    }
+                    let (done, op_type, output) = output_rx.recv()?;
+                    outputs.insert(done, (op_type, output));
+                };
+                let output = output?;
+                write_output(&part.operations[i], &output, dst, config, profile.times(op_type))?;
+                if let Some(checkpoint) = checkpoint.as_deref_mut() {
+                    dst.flush()?;
+                    checkpoint.record(i + 1)?;
+                }
             }
+            Ok(())
+        };
+        let result = apply_all();
+        // the workers stop once the queue is closed and empty
+        drop(job_tx);
+        for worker in workers {
+            profile.merge(worker.join().unwrap());
+        }
+        result
+    })
+}
 
-            // diff ops; require src
-            // copy: src -> dst
-            OperationType::SourceCopy => {
-                let mut src = src.ok_or_else(|| anyhow!("No src given for copy operation"))?;
+/// per partition settings from the name and block size maps
+struct PartOverrides {
+    names: HashMap<String, String>,
+    block_sizes: HashMap<String, usize>,
+}
 
-                copy_padded(&mut src, &mut dst, dst_len)
-                    .with_context(|| format!("Error while writing output"))?;
+impl PartOverrides {
+    fn new(args: &ExtractOptions) -> Result<Self> {
+        let mut mapped = args.name_map.iter().collect::<Vec<_>>();
+        mapped.sort_unstable();
+        let mut names = HashMap::new();
+        let mut owners = HashMap::new();
+        for (name, path) in mapped {
+            let path = relative_path(path, name)
+                .context("Invalid --name-map")?
+                .to_string_lossy()
+                .into_owned();
+            if let Some(other) = owners.insert(path.clone(), name) {
+                bail!("--name-map gives {} and {} the same path {}", other, name, path);
             }
-            // bsdiff: src + data -> dst
-            OperationType::SourceBsdiff | OperationType::BrotliBsdiff => {
-                let mut src = src.ok_or_else(|| anyhow!("No src given for bsdiff operation"))?;
-                let mut data_vec = vec![];
-                data.ok_or_else(|| anyhow!("No data given for bsdiff operation"))?
-                    .read_to_end(&mut data_vec)
-                    .with_context(|| format!("Error ocurred while reading patch data"))?;
+            names.insert(name.clone(), path);
+        }
+        Ok(Self { names, block_sizes: args.block_size_map.clone() })
+    }
+
+    /// the block size of a partition, from --block-size-map or else the manifest
+    fn block_size(&self, manifest: &DeltaArchiveManifest, name: &str) -> usize {
+        match self.block_sizes.get(name) {
+            Some(block_size) => *block_size,
+            None => usize(manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE)),
+        }
+    }
 
-                bspatch(&mut src, &mut dst, &data_vec)
-                    .with_context(|| format!("Error ocurred applying patch"))?;
+    /// names the selected partitions with the path template, making sure the paths are unique
+    fn apply_template(
+        &mut self,
+        template: &PathTemplate,
+        manifest: &DeltaArchiveManifest,
+        selected: &[&PartitionUpdate],
+    ) -> Result<()> {
+        let mut owners = HashMap::new();
+        for part in selected {
+            let name = &part.partition_name;
+            let update_type = format!("{:?}", part.get_update_type()).to_lowercase();
+            let fields = PathFields::new(name, partition_group(manifest, name), &update_type);
+            let path = template.render(&fields)?.to_string_lossy().into_owned();
+            if let Some(other) = owners.insert(path.clone(), name) {
+                bail!("--path-template gives {} and {} the same path {}", other, name, path);
             }
-            _ => bail!("Unsupported operation type {} for op {}", op.r#type, i),
+            self.names.insert(name.clone(), path);
         }
+        Ok(())
     }
-    println!();
+}
 
-    Ok(())
+/// an image written by `extract_part`
+struct ExtractedImage {
+    /// none if the image was piped to a command or written to stdout
+    path: Option<PathBuf>,
+    /// the hash of the image, if it was checked
+    hash: Option<Vec<u8>>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn extract_part(
     manifest: &DeltaArchiveManifest,
-    args: &ExtractArgs,
+    args: &ExtractOptions,
     data: &mut (impl Read + Seek),
+    layout: PayloadLayout,
     part: &PartitionUpdate,
-) -> Result<()> {
+    overrides: &PartOverrides,
+    profile: &mut Profile,
+    progress: &dyn ProgressSink,
+) -> Result<ExtractedImage> {
     let name = &part.partition_name;
-    println!("processing partition: {}", name);
+    let block_size = overrides.block_size(manifest, name);
+    progress.on_partition_start(name, part.operations.len(), part_dst_bytes(part, block_size));
     let name_img = format!("{}.img", name);
+    let dst_name = overrides.names.get(name).unwrap_or(&name_img);
 
-    let mut src = args
+    // bspatch reads the src image in many small pieces, which the buffer turns into fewer reads
+    let src = args
         .src
         .as_ref()
-        .map(|src_path| File::open(Path::new(src_path).join(&name_img)))
+        .map(|src_path| SrcImage::find(Path::new(src_path), name)?.open().map(BufReader::new))
         .transpose()?;
-    let mut dst = File::create(Path::new(&args.dst).join(&name_img))?;
-    process_part(manifest, part, data, src.as_mut(), &mut dst, args.skip_hash)
+    let config = PartConfig { args, layout, block_size, progress };
+
+    if args.pipe.is_some() || args.writes_to_stdout() {
+        // a command only gets an image once its hash checked out, so it never acts on a bad one
+        let streams = writes_forward(part) && !(args.pipe.is_some() && args.verifies_output());
+        let hash = if streams {
+            let mut hash = None;
+            write_out(args, |out| {
+                hash = stream_part(part, data, out, &config, profile)?;
+                Ok(())
+            })?;
+            hash
+        } else {
+            spool_part(part, data, src, &config, profile)?
+        };
+        progress.on_partition_done(name);
+        return Ok(ExtractedImage { path: None, hash });
+    }
+
+    let dst_dir = args.dst.as_ref().ok_or_else(|| anyhow!("No --dst given"))?;
+    let mut dst_dir = PathBuf::from(dst_dir);
+    if args.group_dirs {
+        if let Some(group) = partition_group(manifest, name) {
+            check_group_dir(group)?;
+            dst_dir.push(group);
+            fs::create_dir_all(&dst_dir)?;
+        }
+    }
+    let dst_path = dst_dir.join(dst_name);
+    if args.path_template.is_some() || !args.name_map.is_empty() {
+        if let Some(parent) = dst_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let mut checkpoint = args.resume.then(|| Checkpoint::open(&dst_path)).transpose()?;
+    if updates_in_place(part) {
+        // an in place operation which was interrupted after writing some of its blocks would
+        // read them back as src if it was applied again, so those partitions always start over
+        if let Some(checkpoint) = checkpoint.take() {
+            if checkpoint.next_op() > 0 {
+                status!("{} is updated in place, so it starts over instead of resuming", name);
+            }
+            checkpoint.finish()?;
+        }
+    }
+    if args.resume && checkpoint.as_ref().is_none_or(|checkpoint| checkpoint.next_op() == 0) {
+        if let Some(hash) = existing_image_hash(part, &dst_path)? {
+            status!("{} is already extracted, skipping it", dst_path.display());
+            progress.on_partition_done(name);
+            return Ok(ExtractedImage { path: Some(dst_path), hash: Some(hash) });
+        }
+    }
+    let resumed = checkpoint.as_ref().is_some_and(|checkpoint| checkpoint.next_op() > 0);
+    let mut dst = if resumed {
+        // keep the output of the operations that already completed
+        OpenOptions::new().write(true).open(&dst_path).with_context(|| {
+            format!("Failed to reopen partially written image {}", dst_path.display())
+        })?
+    } else {
+        File::create(&dst_path)?
+    };
+    if let Some(checkpoint) = checkpoint.as_mut() {
+        checkpoint.sync_image(&dst)?;
+    }
+    let mut src = image_src(part, src, &mut dst, &dst_path)?;
+
+    if args.max_ops.is_some() {
+        // the operations that aren't applied leave zeros behind instead of a short file
+        if let Some(size) = part.new_partition_info.as_ref().and_then(|info| info.size) {
+            dst.set_len(size)?;
+        }
+    }
+
+    let empty = args.emit_empty && is_empty_partition(part);
+    if empty {
+        // extending the file leaves a sparse run of zeros instead of writing them out
+        let size = part
+            .new_partition_info
+            .as_ref()
+            .and_then(|info| info.size)
+            .ok_or_else(|| anyhow!("No size given for the empty partition"))?;
+        dst.set_len(size)?;
+        status!("wrote empty image of {} bytes", size);
+    }
+
+    // operations with many small extents write in small pieces, so those are buffered; seeking
+    // flushes the buffer, so the pieces still land in the right place
+    let dst = BufWriter::with_capacity(args.io_buffer_size, dst);
+    let mut dst = HashingWriter::new(dst, args.verifies_output().then_some(HashKind::Sha256));
+    if !empty {
+        process_part(part, data, src.as_mut(), &mut dst, &config, profile, checkpoint.as_mut())?;
+    }
+    // flushed here since dropping the buffer would ignore errors
+    dst.flush()?;
+    let written_hash = dst.finalize(partition_size(part));
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.finish()?;
+    }
+    let mut hash = None;
+    if args.verifies_output() {
+        hash = verify_image(part, written_hash, || File::open(&dst_path))?;
+    }
+
+    if args.trim_trailing_zeros {
+        let len = trim_trailing_zeros(&dst_path, block_size)
+            .with_context(|| format!("Failed to trim trailing zeros"))?;
+        status!("trimmed image to {} bytes", len);
+    }
+    progress.on_partition_done(name);
+    Ok(ExtractedImage { path: Some(dst_path), hash })
 }
 
-pub fn extract(
-    manifest: &DeltaArchiveManifest,
-    args: &ExtractArgs,
-    data_offset: u64,
+/// the hash of an image left by a previous run, if there is one and it matches the new
+/// partition hash; images of partitions without a hash never match
+fn existing_image_hash(part: &PartitionUpdate, path: &Path) -> Result<Option<Vec<u8>>> {
+    let Some(expected_hash) =
+        part.new_partition_info.as_ref().and_then(|info| info.hash.as_deref())
+    else {
+        return Ok(None);
+    };
+    let mut image = match File::open(path) {
+        Ok(image) => image,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Failed to open {}", path.display())),
+    };
+    let hash = hash_stream(&mut image, HashKind::Sha256)?;
+    Ok((hash == expected_hash).then_some(hash))
+}
+
+/// whether the partition has no operations or only ones which zero its blocks
+fn is_empty_partition(part: &PartitionUpdate) -> bool {
+    part.operations.iter().all(|op| {
+        op.r#type == OperationType::Zero as i32 || op.r#type == OperationType::Discard as i32
+    })
+}
+
+/// makes sure a group name from the manifest is a single folder name, so --group-dirs can't
+/// write outside of --dst
+fn check_group_dir(group: &str) -> Result<()> {
+    ensure!(
+        !group.is_empty()
+            && group != "."
+            && group != ".."
+            && !group.contains(std::path::is_separator),
+        "Group name {:?} can't be used as a folder name",
+        group
+    );
+    Ok(())
+}
+
+/// the name of the dynamic partition group the partition belongs to, if any
+fn partition_group<'a>(manifest: &'a DeltaArchiveManifest, name: &str) -> Option<&'a str> {
+    let groups = &manifest.dynamic_partition_metadata.as_ref()?.groups;
+    groups
+        .iter()
+        .find(|group| group.partition_names.iter().any(|part| part == name))
+        .map(|group| group.name.as_str())
+}
+
+fn partition_size(part: &PartitionUpdate) -> u64 {
+    part.new_partition_info.as_ref().and_then(|info| info.size).unwrap_or(u64::MAX)
+}
+
+/// checks the extracted image against the new partition hash, preferring the hash computed while
+/// the image was written and only reading the image back if it wasn't written in order
+///
+/// returns the hash of the image, unless it couldn't be checked and wasn't computed while writing
+fn verify_image(
+    part: &PartitionUpdate,
+    written_hash: Option<Vec<u8>>,
+    reopen: impl FnOnce() -> io::Result<File>,
+) -> Result<Option<Vec<u8>>> {
+    let Some(expected_hash) =
+        part.new_partition_info.as_ref().and_then(|info| info.hash.as_deref())
+    else {
+        status!("payload has no hash for the new partition, not verifying the image");
+        return Ok(written_hash);
+    };
+    let hash = match written_hash {
+        Some(hash) => hash,
+        None => {
+            status!("image wasn't written in order, reading it back to verify");
+            let mut image = reopen()?;
+            image.rewind()?;
+            hash_stream(&mut image, HashKind::Sha256)?
+        }
+    };
+    compare_hash(&hash, expected_hash)
+        .with_context(|| format!("Extracted image doesn't match the new partition hash"))?;
+    status!("verified image hash");
+    Ok(Some(hash))
+}
+
+/// runs `command` with the shell, with `write` feeding its stdin
+fn pipe_to_command(command: &str, write: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", command))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let written = write(&mut stdin);
+    // close stdin so the command sees the end of its input
+    drop(stdin);
+    let status = child.wait()?;
+    written.with_context(|| format!("Failed to write image to {:?}", command))?;
+    ensure!(status.success(), "{:?} exited with {}", command, status);
+    Ok(())
+}
+
+/// hands `write` the --pipe command's stdin, or else stdout
+fn write_out(
+    args: &ExtractOptions,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
 ) -> Result<()> {
-    let parts = parse_parts(&args.parts);
-    let mut data = ExtentStream::new_suffix(File::open(&args.file)?, usize(data_offset))?;
-    fs::create_dir_all(&args.dst)?;
-    for part in &manifest.partitions {
-        if let Some(parts) = &parts {
-            if !parts.contains(&part.partition_name.as_str()) {
-                continue;
+    match &args.pipe {
+        Some(command) => pipe_to_command(command, write),
+        None => {
+            let mut stdout = io::stdout().lock();
+            write(&mut stdout).with_context(|| format!("Failed to write image to stdout"))?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// whether the image can be written without seeking back: the partition only has full
+/// operations, and each of their dst extents starts after the ones before it end
+fn writes_forward(part: &PartitionUpdate) -> bool {
+    use OperationType::*;
+    let mut end = 0;
+    for op in &part.operations {
+        if !matches!(
+            OperationType::try_from(op.r#type),
+            Ok(Replace | ReplaceBz | ReplaceXz | ReplaceZstd | Zero)
+        ) {
+            return false;
+        }
+        for extent in &op.dst_extents {
+            let (Some(start), Some(num)) = (extent.start_block, extent.num_blocks) else {
+                return false;
+            };
+            match start.checked_add(num) {
+                Some(extent_end) if start >= end && start != u64::MAX => end = extent_end,
+                _ => return false,
             }
         }
+    }
+    true
+}
+
+/// applies the operations straight to `out`, with zeros in the gaps between the extents and up
+/// to the partition size, which only works if the partition `writes_forward`
+///
+/// returns the hash of the image if it's verified, though by then it was already written
+fn stream_part(
+    part: &PartitionUpdate,
+    data: &mut (impl Read + Seek),
+    out: &mut dyn Write,
+    config: &PartConfig,
+    profile: &mut Profile,
+) -> Result<Option<Vec<u8>>> {
+    let args = config.args;
+    let out = BufWriter::with_capacity(args.io_buffer_size, out);
+    let hashing = HashingWriter::new(out, args.verifies_output().then_some(HashKind::Sha256));
+    let mut dst = ForwardWriter::new(hashing);
+    process_part(part, data, None::<&mut File>, &mut dst, config, profile, None)?;
+    if let Some(size) = part.new_partition_info.as_ref().and_then(|info| info.size) {
+        dst.pad_to(size)?;
+    }
+    dst.flush()?;
+    // everything was written in order, so the whole image was hashed and isn't read back
+    let len = dst.stream_position()?;
+    let written_hash = dst.into_inner().finalize(len);
+    if !args.verifies_output() {
+        return Ok(None);
+    }
+    verify_image(part, written_hash, || Err(io::ErrorKind::Unsupported.into()))
+}
+
+/// applies the operations to a temp file, since they need to seek, and then copies it to the
+/// --pipe command or stdout once it's verified
+fn spool_part(
+    part: &PartitionUpdate,
+    data: &mut (impl Read + Seek),
+    src: Option<BufReader<Box<dyn StreamRead>>>,
+    config: &PartConfig,
+    profile: &mut Profile,
+) -> Result<Option<Vec<u8>>> {
+    let args = config.args;
+    // named, so partitions updated in place can read it while it's written
+    let mut spool = tempfile::NamedTempFile::new()?;
+    let spool_path = spool.path().to_path_buf();
+    let mut src = image_src(part, src, spool.as_file_mut(), &spool_path)?;
+    let spool_writer = BufWriter::with_capacity(args.io_buffer_size, spool.as_file_mut());
+    let mut dst =
+        HashingWriter::new(spool_writer, args.verifies_output().then_some(HashKind::Sha256));
+    process_part(part, data, src.as_mut(), &mut dst, config, profile, None)?;
+    dst.flush()?;
+    let written_hash = dst.finalize(partition_size(part));
+    let mut hash = None;
+    if args.verifies_output() {
+        hash = verify_image(part, written_hash, || spool.reopen())?;
+    }
+    spool.rewind()?;
+    write_out(args, |out| {
+        io::copy(&mut spool, out)?;
+        Ok(())
+    })?;
+    Ok(hash)
+}
+
+/// truncates the file after its last block which isn't all zeros, returning the new length
+fn trim_trailing_zeros(path: &Path, block_size: usize) -> Result<u64> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut end = file.metadata()?.len();
+    let mut block = vec![0; block_size];
+    while end > 0 {
+        // the first block looked at can be partial if the file isn't block aligned
+        let start = (end - 1) / u64(block_size) * u64(block_size);
+        let block = &mut block[..usize(end - start)];
+        file.seek(io::SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+        if block.iter().any(|byte| *byte != 0) {
+            break;
+        }
+        end = start;
+    }
+    file.set_len(end)?;
+    Ok(end)
+}
+
+fn print_boot_header(name: &str, path: &Path) -> Result<()> {
+    let header = BootImageHeader::read(&mut File::open(path)?)
+        .with_context(|| format!("Failed to read the boot image header of {}", name))?;
+    println!("{} boot image header:", name);
+    println!("{}", header);
+    Ok(())
+}
+
+/// lists the files which differ between the src and dst images, if they are ext4 filesystems
+fn print_changed_files(name: &str, src: &SrcImage, dst_path: &Path) -> Result<()> {
+    let old = Ext4::open(BufReader::new(src.open()?))?;
+    let new = Ext4::open(BufReader::new(File::open(dst_path)?))?;
+    let (Some(mut old), Some(mut new)) = (old, new) else {
+        println!("{} isn't an ext4 image, not listing changed files", name);
+        return Ok(());
+    };
+    let changes = changed_files(&old.files()?, &new.files()?);
+    println!("{} changed files in {}:", changes.len(), name);
+    for (change, path) in changes {
+        println!("{} {}", change, path);
+    }
+    println!();
+    Ok(())
+}
 
-        extract_part(manifest, args, &mut data, part).with_context(|| {
+/// what every thread extracting partitions needs
+struct ExtractJobs<'a> {
+    manifest: &'a DeltaArchiveManifest,
+    args: &'a ExtractOptions,
+    scratch_args: Option<&'a ExtractOptions>,
+    layout: PayloadLayout,
+    overrides: PartOverrides,
+    progress: &'a dyn ProgressSink,
+}
+
+impl<'a> ExtractJobs<'a> {
+    fn extract(
+        &self,
+        data: &mut File,
+        part: &PartitionUpdate,
+        profile: &mut Profile,
+    ) -> Result<(ExtractedImage, Duration)> {
+        let start = Instant::now();
+        let image = self.extract_part(self.args, data, part, profile).with_context(|| {
             format!("Error ocurred while processing partition {}", part.partition_name)
         })?;
+        if let (Some(path), Some(scratch_args)) = (&image.path, self.scratch_args) {
+            self.check_deterministic(scratch_args, data, part, path).with_context(|| {
+                format!("Extraction of partition {} isn't deterministic", part.partition_name)
+            })?;
+        }
+        Ok((image, start.elapsed()))
+    }
+
+    fn extract_part(
+        &self,
+        args: &ExtractOptions,
+        data: &mut File,
+        part: &PartitionUpdate,
+        profile: &mut Profile,
+    ) -> Result<ExtractedImage> {
+        let (manifest, layout, overrides) = (self.manifest, self.layout, &self.overrides);
+        extract_part(manifest, args, data, layout, part, overrides, profile, self.progress)
+    }
+
+    /// extracts the partition again with `scratch_args` and compares the result with the image
+    /// at `path`
+    fn check_deterministic(
+        &self,
+        scratch_args: &ExtractOptions,
+        data: &mut File,
+        part: &PartitionUpdate,
+        path: &Path,
+    ) -> Result<()> {
+        status!("extracting {} again to check it's deterministic", part.partition_name);
+        let mut profile = Profile::default();
+        let again = self
+            .extract_part(scratch_args, data, part, &mut profile)?
+            .path
+            .ok_or_else(|| anyhow!("Second extraction didn't produce an image"))?;
+        let difference = first_difference(
+            &mut BufReader::new(File::open(path)?),
+            &mut BufReader::new(File::open(&again)?),
+        )?;
+        fs::remove_file(&again)?;
+        if let Some(offset) = difference {
+            bail!("Images first differ at byte 0x{:x}", offset);
+        }
+        status!("both extractions are identical");
+        Ok(())
+    }
+
+    /// extracts the partitions on --jobs threads, each reading the payload through its own file,
+    /// and returns the images in the order of `parts`; a partition only starts once its memory
+    /// fits in --max-total-memory and its files in --max-open-files
+    ///
+    /// once a partition fails no new ones are started, and the first failure in order is returned
+    fn run(
+        &self,
+        parts: &[&PartitionUpdate],
+        profile: &mut Profile,
+    ) -> Result<Vec<(ExtractedImage, Duration)>> {
+        let jobs = self
+            .args
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let memory = self.args.max_total_memory.map(Budget::new);
+        let files = self.args.max_open_files.map(Budget::new);
+        let worker = || -> Result<_> {
+            let mut profile = Profile::default();
+            let mut done = vec![];
+            while !failed.load(Ordering::Relaxed) {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(part) = parts.get(i) else {
+                    break;
+                };
+                let block_size = self.overrides.block_size(self.manifest, &part.partition_name);
+                // held until the partition is extracted
+                let _memory = memory
+                    .as_ref()
+                    .map(|memory| memory.reserve(part_memory(part, block_size, self.args)));
+                let _files = files.as_ref().map(|files| files.reserve(part_files(part, self.args)));
+                // the payload is opened for each partition, so it counts towards its files
+                let mut data = File::open(&self.args.data_file)?;
+                let result = self.extract(&mut data, part, &mut profile);
+                failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                done.push((i, result));
+            }
+            Ok((done, profile))
+        };
+        let workers = thread::scope(|scope| {
+            let handles =
+                (0..jobs.min(parts.len())).map(|_| scope.spawn(worker)).collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("extraction thread panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut results = parts.iter().map(|_| None).collect::<Vec<_>>();
+        for worker in workers {
+            let (done, worker_profile) = worker?;
+            profile.merge(worker_profile);
+            for (i, result) in done {
+                results[i] = Some(result);
+            }
+        }
+        // parts are started in order, so the ones which never started come after a failure
+        results.into_iter().map_while(|result| result).collect()
+    }
+}
+
+/// the offset of the first byte at which the two streams differ, including one ending early
+fn first_difference(a: &mut impl Read, b: &mut impl Read) -> io::Result<Option<u64>> {
+    let mut buf_a = vec![0; 1 << 16];
+    let mut buf_b = vec![0; 1 << 16];
+    let mut offset = 0;
+    loop {
+        let read = a.read(&mut buf_a)?;
+        let mut filled = 0;
+        while filled < read {
+            match b.read(&mut buf_b[filled..read])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if let Some(i) = (0..filled).find(|&i| buf_a[i] != buf_b[i]) {
+            return Ok(Some(offset + u64(i)));
+        }
+        if filled < read {
+            return Ok(Some(offset + u64(filled)));
+        }
+        if read == 0 {
+            // a ended, so b must end too
+            return Ok((b.read(&mut buf_b[..1])? > 0).then_some(offset));
+        }
+        offset += u64(read);
+    }
+}
+
+/// formats a duration in whole seconds, like `3m42s`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, secs) => format!("{}s", secs),
+        (0, mins, secs) => format!("{}m{:02}s", mins, secs),
+        (hours, mins, secs) => format!("{}h{:02}m{:02}s", hours, mins, secs),
+    }
+}
+
+/// makes sure every incremental partition has a readable src image before anything is written
+fn check_src_available(args: &ExtractOptions, parts: &[&PartitionUpdate]) -> Result<()> {
+    let mut missing = vec![];
+    for part in parts {
+        if part.get_update_type() != UpdateType::Incremental {
+            continue;
+        }
+        match &args.src {
+            Some(src_path) => {
+                if let Err(err) = SrcImage::find(Path::new(src_path), &part.partition_name) {
+                    missing.push(format!("{:#}", err));
+                }
+            }
+            None => missing.push(format!("{}.img", part.partition_name)),
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!(
+            "Missing src images for incremental partitions{}:\n{}",
+            if args.src.is_none() { " (use --src to specify them)" } else { "" },
+            missing.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// the formats images can be written in
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum ImageFormat {
+    /// plain images with every block of the partition
+    #[default]
+    Raw,
+    /// Android sparse images, like img2simg writes
+    Sparse,
+}
+
+/// the hash checks which can be skipped
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum HashCheck {
+    /// the src extents of each operation
+    Src,
+    /// the payload data of each operation
+    Data,
+    /// the extracted images, against the new partition hashes
+    Output,
+}
+
+/// what to extract from a payload and how; the fields mirror the flags of the `extract`
+/// subcommand, and the defaults extract every partition the way it does without flags
+#[derive(Clone, Debug)]
+pub struct ExtractOptions {
+    /// the manifest of the payload, e.g. from [`crate::Payload::open`]
+    pub manifest: DeltaArchiveManifest,
+    /// the file the operation data is read from, usually the payload file itself
+    pub data_file: String,
+    /// where the data region starts in `data_file`, see [`PayloadLayout::data_offset`]
+    pub data_offset: u64,
+    /// the folder or zip with the images before the update, for incremental payloads
+    pub src: Option<String>,
+    /// the folder the images are written to, or `-` to write the single selected image to stdout
+    pub dst: Option<String>,
+    /// the partitions to extract, which may be globs like `system*`; all of them if `None`
+    pub parts: Option<Vec<String>>,
+    /// the partitions to leave out, also allowing globs; applied after `parts`
+    pub exclude: Vec<String>,
+    /// the hash checks to leave out
+    pub skip_hash: Vec<HashCheck>,
+    /// paths relative to `dst` overriding the image paths of partitions, by partition name
+    pub name_map: HashMap<String, String>,
+    /// block sizes overriding the manifest block size, by partition name
+    pub block_size_map: HashMap<String, usize>,
+    /// refuse payloads with an older security patch level
+    pub min_patch_level: Option<PatchLevel>,
+    /// refuse incremental payloads
+    pub require_full: bool,
+    /// print the time spent in each phase per operation type
+    pub profile: bool,
+    /// also write the output of each operation to `{partition}_op{N}.bin` in this folder
+    pub per_op_output: Option<String>,
+    /// record completed operations next to each image and continue from them
+    pub resume: bool,
+    /// truncate trailing all-zero blocks from the images
+    pub trim_trailing_zeros: bool,
+    /// flash each image to the connected device with `fastboot flash`
+    pub flash: bool,
+    /// don't ask for confirmation before flashing
+    pub yes: bool,
+    /// only apply the first N operations of each partition
+    pub max_ops: Option<usize>,
+    /// feed the single selected image to this shell command instead of a file
+    pub pipe: Option<String>,
+    /// print the header of extracted boot images
+    pub boot_header: bool,
+    /// list the files that changed between the src and extracted ext4 images
+    pub changed_files: bool,
+    /// the size of the buffer used to copy data into the images
+    pub io_buffer_size: usize,
+    /// put the images of dynamic partitions in a subfolder named after their group
+    pub group_dirs: bool,
+    /// lay out the images inside `dst` with this template
+    pub path_template: Option<PathTemplate>,
+    /// extract every image a second time and fail if the two differ
+    pub verify_deterministic: bool,
+    /// finish with a line summarizing the extracted images
+    pub summary: bool,
+    /// write partitions without data as zero filled images
+    pub emit_empty: bool,
+    /// warn when operations write overlapping dst blocks
+    pub check_overlap: bool,
+    /// fail instead of warning when operations write overlapping dst blocks
+    pub strict: bool,
+    /// write the raw data of the operations to this cpio archive instead of extracting
+    pub archive_ops: Option<String>,
+    /// check and list the operations without writing any images
+    pub dry_run: bool,
+    /// how many partitions to extract at the same time; the number of CPUs if `None`
+    pub jobs: Option<usize>,
+    /// the memory the partitions extracted at the same time may use, as estimated
+    pub max_total_memory: Option<u64>,
+    /// the files the partitions extracted at the same time may keep open
+    pub max_open_files: Option<u64>,
+    /// write a JSON report of the run to this file
+    pub report: Option<String>,
+    /// the format of the written images
+    pub output_format: ImageFormat,
+    /// write a care map of the extracted partitions to this file
+    pub care_map: Option<String>,
+    /// write the sha256 hashes of the images to this file in the format of sha256sum
+    pub checksums: Option<String>,
+    /// write the dynamic partitions into one super image at this path
+    pub combined: Option<String>,
+    /// the size of the super partition for `combined`
+    pub super_size: Option<u64>,
+    /// how many operations of a partition to apply at the same time
+    pub threads: usize,
+    /// how often to apply an operation again when it fails with a transient IO error
+    pub op_retries: usize,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            manifest: DeltaArchiveManifest::default(),
+            data_file: String::new(),
+            data_offset: 0,
+            src: None,
+            dst: None,
+            parts: None,
+            exclude: vec![],
+            skip_hash: vec![],
+            name_map: HashMap::new(),
+            block_size_map: HashMap::new(),
+            min_patch_level: None,
+            require_full: false,
+            profile: false,
+            per_op_output: None,
+            resume: false,
+            trim_trailing_zeros: false,
+            flash: false,
+            yes: false,
+            max_ops: None,
+            pipe: None,
+            boot_header: false,
+            changed_files: false,
+            io_buffer_size: 1 << 20,
+            group_dirs: false,
+            path_template: None,
+            verify_deterministic: false,
+            summary: false,
+            emit_empty: false,
+            check_overlap: false,
+            strict: false,
+            archive_ops: None,
+            dry_run: false,
+            jobs: None,
+            max_total_memory: None,
+            max_open_files: None,
+            report: None,
+            output_format: ImageFormat::Raw,
+            care_map: None,
+            checksums: None,
+            combined: None,
+            super_size: None,
+            threads: 1,
+            op_retries: 0,
+        }
+    }
+}
+
+impl ExtractOptions {
+    fn skips_hash(&self, check: HashCheck) -> bool {
+        self.skip_hash.contains(&check)
+    }
+
+    /// whether the extracted images are checked against the new partition hashes
+    fn verifies_output(&self) -> bool {
+        // images cut short by --max-ops can't match
+        !self.skips_hash(HashCheck::Output) && self.max_ops.is_none()
+    }
+
+    /// whether the image is written to stdout with `--dst -`
+    fn writes_to_stdout(&self) -> bool {
+        self.dst.as_deref() == Some("-")
+    }
+}
+
+/// extracts the selected partitions of the payload, telling `progress` how each one goes;
+/// warnings are recorded with [`diagnostic::warn`] rather than failing the extraction
+pub fn extract(args: &ExtractOptions, progress: &dyn ProgressSink) -> Result<()> {
+    let manifest = &args.manifest;
+    let Some(report_path) = &args.report else {
+        return extract_selected(manifest, args, None, progress);
+    };
+    let start = Instant::now();
+    let mut report = Report::new(manifest, &args.data_file, args.data_offset);
+    let result = extract_selected(manifest, args, Some(&mut report), progress);
+    // the report is most useful when something went wrong, so it's written either way
+    let written = report.finish(report_path, start.elapsed(), &result);
+    if let (Err(_), Err(err)) = (&result, &written) {
+        // the extraction error is the one returned, so this one is only printed
+        eprintln!("{:#}", err);
+    }
+    result.and(written)
+}
+
+/// matches partition names against a list of globs like `system*`; plain names match themselves
+fn part_globs(parts: &[String]) -> Result<GlobSet> {
+    let mut globs = GlobSetBuilder::new();
+    for part in parts {
+        globs.add(Glob::new(part).with_context(|| format!("Invalid part pattern {}", part))?);
+    }
+    Ok(globs.build()?)
+}
+
+/// the partitions picked by --parts, minus those named by --exclude
+fn select_parts<'a>(
+    manifest: &'a DeltaArchiveManifest,
+    args: &ExtractOptions,
+) -> Result<Vec<&'a PartitionUpdate>> {
+    let parts = args.parts.as_deref().map(part_globs).transpose()?;
+    let exclude = part_globs(&args.exclude)?;
+    Ok(manifest
+        .partitions
+        .iter()
+        .filter(|part| match &parts {
+            Some(parts) => parts.is_match(&part.partition_name),
+            None => true,
+        })
+        .filter(|part| !exclude.is_match(&part.partition_name))
+        .collect())
+}
+
+fn extract_selected(
+    manifest: &DeltaArchiveManifest,
+    args: &ExtractOptions,
+    mut report: Option<&mut Report>,
+    progress: &dyn ProgressSink,
+) -> Result<()> {
+    ensure!(args.io_buffer_size > 0, "--io-buffer-size must be at least 1 byte");
+    ensure!(args.jobs != Some(0), "--jobs must be at least 1");
+    ensure!(args.threads != 0, "--threads must be at least 1");
+    if args.writes_to_stdout() {
+        STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+    let update_type = manifest.get_update_type();
+    if args.require_full {
+        ensure!(
+            update_type == UpdateType::Full,
+            "Payload is {:?} rather than a full payload",
+            update_type
+        );
+    }
+    status!("update type: {:?}", update_type);
+    if update_type == UpdateType::Incremental {
+        status!(
+            "incremental payloads are applied on top of the base build, which --src must point at"
+        );
+    }
+    if let Some(min_level) = &args.min_patch_level {
+        check_min_patch_level(manifest.security_patch_level.as_deref(), min_level)?;
+    }
+
+    let selected = select_parts(manifest, args)?;
+    if args.combined.is_some() {
+        ensure!(
+            manifest.dynamic_partition_metadata.is_some(),
+            "--combined requires a payload with dynamic partitions"
+        );
+    }
+    if let Some(archive) = &args.archive_ops {
+        let mut data = File::open(&args.data_file)?;
+        let mut out = BufWriter::new(
+            File::create(archive).with_context(|| format!("Failed to create {}", archive))?,
+        );
+        let archived = archive_ops(
+            &selected,
+            &mut data,
+            &PayloadLayout { data_offset: args.data_offset },
+            &mut out,
+        )?;
+        println!("archived the data of {} operations to {}", archived, archive);
+        return Ok(());
+    }
+    if args.dry_run {
+        let overrides = PartOverrides::new(args)?;
+        return dry_run(
+            manifest,
+            args,
+            &selected,
+            &overrides,
+            &PayloadLayout { data_offset: args.data_offset },
+        );
+    }
+    check_src_available(args, &selected)?;
+    if args.flash {
+        flash::check_fastboot()?;
+    }
+
+    let mut overrides = PartOverrides::new(args)?;
+    if let Some(template) = &args.path_template {
+        overrides.apply_template(template, manifest, &selected)?;
+    }
+    let mut profile = Profile::default();
+    let layout = PayloadLayout { data_offset: args.data_offset };
+    if args.pipe.is_some() {
+        ensure!(
+            selected.len() == 1,
+            "--pipe requires selecting exactly one partition with --parts"
+        );
+    } else if args.writes_to_stdout() {
+        ensure!(
+            selected.len() == 1,
+            "--dst - requires selecting exactly one partition with --parts"
+        );
+        ensure!(
+            !args.resume
+                && !args.trim_trailing_zeros
+                && !args.flash
+                && !args.verify_deterministic
+                && args.output_format == ImageFormat::Raw
+                && args.combined.is_none(),
+            "--dst - can't be combined with --resume, --trim-trailing-zeros, --flash, \
+             --verify-deterministic, --output-format sparse or --combined"
+        );
+    } else if let Some(dst) = &args.dst {
+        fs::create_dir_all(dst)?;
+    }
+    if let Some(dir) = &args.per_op_output {
+        fs::create_dir_all(dir)?;
+    }
+    // the second extraction goes to a scratch folder and is compared with the first
+    let scratch = args.verify_deterministic.then(tempfile::tempdir).transpose()?;
+    let scratch_args = scratch.as_ref().map(|scratch| ExtractOptions {
+        dst: Some(scratch.path().to_string_lossy().into_owned()),
+        ..args.clone()
+    });
+    let start = Instant::now();
+    let mut extracted_bytes = 0;
+    let extracted_parts = selected.len();
+    let mut images = vec![];
+    // the images which go into the --combined super image
+    let mut logical = vec![];
+    // the images on disk and their hashes for --checksums
+    let mut checksums = vec![];
+    let jobs = ExtractJobs {
+        manifest,
+        args,
+        scratch_args: scratch_args.as_ref(),
+        layout,
+        overrides,
+        progress,
+    };
+    let extracted = jobs.run(&selected, &mut profile)?;
+    if let Some(path) = &args.care_map {
+        write_care_map(path, &selected)?;
+    }
+    for (part, (image, elapsed)) in selected.into_iter().zip(extracted) {
+        let path = image.path;
+        let size = match &path {
+            Some(path) => Some(fs::metadata(path)?.len()),
+            None => part.new_partition_info.as_ref().and_then(|info| info.size),
+        };
+        extracted_bytes += size.unwrap_or(partition_size(part));
+        if let Some(report) = report.as_deref_mut() {
+            report.partitions.push(PartReport::new(
+                part,
+                path.as_deref(),
+                size,
+                image.hash.as_deref(),
+                elapsed,
+            ));
+        }
+        if let Some(path) = path {
+            if args.boot_header && is_boot_partition(&part.partition_name) {
+                print_boot_header(&part.partition_name, &path)?;
+            }
+            if let (true, Some(src_dir)) = (args.changed_files, &args.src) {
+                if part.get_update_type() == UpdateType::Incremental {
+                    let src = SrcImage::find(Path::new(src_dir), &part.partition_name)?;
+                    print_changed_files(&part.partition_name, &src, &path).with_context(|| {
+                        format!("Failed to list changed files of {}", part.partition_name)
+                    })?;
+                }
+            }
+            let block_size = jobs.overrides.block_size(manifest, &part.partition_name);
+            if args.output_format == ImageFormat::Sparse {
+                convert_to_sparse(&path, block_size, &written_ranges(part)).with_context(|| {
+                    format!("Failed to write a sparse image of {}", part.partition_name)
+                })?;
+            }
+            if args.combined.is_some() && partition_group(manifest, &part.partition_name).is_some()
+            {
+                // the logical partition keeps its full size even if the image was trimmed
+                let size = match part.new_partition_info.as_ref().and_then(|info| info.size) {
+                    Some(size) => size,
+                    None if args.trim_trailing_zeros => written_ranges(part)
+                        .last()
+                        .map_or(0, |blocks| blocks.end.saturating_mul(u64(block_size))),
+                    None => size.unwrap_or(partition_size(part)),
+                };
+                logical.push((&part.partition_name, path.clone(), size));
+            } else if args.checksums.is_some() {
+                // the hash computed while writing the image is only of the file on disk if
+                // nothing changed the file afterwards
+                let hash = match image.hash {
+                    Some(hash)
+                        if args.output_format == ImageFormat::Raw && !args.trim_trailing_zeros =>
+                    {
+                        hash
+                    }
+                    _ => hash_stream(&mut File::open(&path)?, HashKind::Sha256)?,
+                };
+                checksums.push((path.clone(), hash));
+            }
+            images.push((part.partition_name.clone(), path));
+        }
+    }
+    if let Some(combined) = &args.combined {
+        let metadata = manifest.dynamic_partition_metadata.as_ref().expect("checked above");
+        let logical_images = logical
+            .iter()
+            .map(|(name, path, size)| LogicalImage { name, path, size: *size })
+            .collect::<Vec<_>>();
+        // virtual A/B devices keep a third metadata slot for merging snapshots
+        let metadata_slots = if metadata.snapshot_enabled() { 3 } else { 2 };
+        let size = write_super_image(
+            Path::new(combined),
+            &metadata.groups,
+            &logical_images,
+            metadata_slots,
+            args.super_size,
+        )
+        .with_context(|| format!("Failed to write the super image {}", combined))?;
+        for (name, path, size) in &logical {
+            if *size == 0 {
+                status!("{} is empty, so it has no extents in the super image", name);
+            }
+            fs::remove_file(path)?;
+        }
+        if args.checksums.is_some() {
+            let path = PathBuf::from(combined);
+            let hash = hash_stream(&mut File::open(&path)?, HashKind::Sha256)?;
+            checksums.push((path, hash));
+        }
+        status!(
+            "wrote {} partitions into the {} super image {}",
+            logical.len(),
+            format_size(size),
+            combined
+        );
+    }
+    if let Some(path) = &args.checksums {
+        write_checksums(path, &checksums)?;
+    }
+    if args.profile {
+        profile.print();
+    }
+    if args.summary {
+        status!(
+            "extracted {} partitions ({}) in {}; {}",
+            extracted_parts,
+            format_size(extracted_bytes),
+            format_duration(start.elapsed()),
+            if args.verifies_output() {
+                "all image hashes verified"
+            } else {
+                "image hashes not verified"
+            }
+        );
+    }
+    if args.flash {
+        flash::flash(images, args.yes)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use cast::{u64, usize};
+    use std::{
+        collections::HashMap,
+        fs,
+        io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+        path::Path,
+        time::Duration,
+    };
+
+    use super::{
+        check_group_dir, existing_image_hash, extract, first_difference, format_duration,
+        format_size, hash::Sha256Hasher, image_src, is_empty_partition, part_dst_bytes, part_files,
+        part_memory, process_part, select_parts, stream_part, writes_forward, ExtractJobs,
+        ExtractOptions, HashCheck, PartConfig, PartOverrides, PayloadLayout, Profile, StreamRead,
+    };
+    use crate::{
+        progress::StderrProgress,
+        update_metadata::{
+            install_operation::Type as OperationType, DeltaArchiveManifest, Extent as RawExtent,
+            InstallOperation, PartitionInfo, PartitionUpdate,
+        },
+    };
+
+    /// the options extracting the partitions of `manifest` from `payload` into `dst`
+    fn options_in(manifest: DeltaArchiveManifest, payload: &Path, dst: &Path) -> ExtractOptions {
+        ExtractOptions {
+            manifest,
+            data_file: payload.to_string_lossy().into_owned(),
+            dst: Some(dst.to_string_lossy().into_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// the jobs extracting the partitions of the manifest, with the payload data at the start of
+    /// the payload file
+    fn jobs(args: &ExtractOptions) -> ExtractJobs<'_> {
+        ExtractJobs {
+            manifest: &args.manifest,
+            args,
+            scratch_args: None,
+            layout: PayloadLayout { data_offset: 0 },
+            overrides: PartOverrides::new(args).unwrap(),
+            progress: &StderrProgress,
+        }
+    }
+
+    fn options() -> ExtractOptions {
+        options_in(DeltaArchiveManifest::default(), Path::new("payload.bin"), Path::new("out"))
+    }
+
+    #[test]
+    fn verifies_output_test() {
+        assert!(options().verifies_output());
+        let skip_output = ExtractOptions { skip_hash: vec![HashCheck::Output], ..options() };
+        assert!(!skip_output.verifies_output());
+        // images cut short by max_ops can't match their hash
+        assert!(!ExtractOptions { max_ops: Some(1), ..options() }.verifies_output());
+    }
+
+    #[test]
+    fn existing_image_hash_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("boot.img");
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(b"image");
+        let hash = hasher.finalize();
+        let mut part = PartitionUpdate {
+            new_partition_info: Some(PartitionInfo { size: Some(5), hash: Some(hash.clone()) }),
+            ..Default::default()
+        };
+
+        assert_eq!(existing_image_hash(&part, &path).unwrap(), None);
+        fs::write(&path, b"imag").unwrap();
+        assert_eq!(existing_image_hash(&part, &path).unwrap(), None);
+        fs::write(&path, b"image").unwrap();
+        assert_eq!(existing_image_hash(&part, &path).unwrap(), Some(hash));
+        part.new_partition_info = None;
+        assert_eq!(existing_image_hash(&part, &path).unwrap(), None);
+    }
+
+    #[test]
+    fn select_parts_test() {
+        let manifest = DeltaArchiveManifest {
+            partitions: ["boot", "system", "vendor"]
+                .map(|name| PartitionUpdate {
+                    partition_name: name.to_string(),
+                    ..Default::default()
+                })
+                .to_vec(),
+            ..Default::default()
+        };
+        let names = |parts: Option<&[&str]>, exclude: &[&str]| {
+            let strings = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+            let args = ExtractOptions {
+                parts: parts.map(strings),
+                exclude: strings(exclude),
+                ..options()
+            };
+            select_parts(&manifest, &args)
+                .unwrap()
+                .iter()
+                .map(|part| part.partition_name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(None, &[]), ["boot", "system", "vendor"]);
+        assert_eq!(names(None, &["system", "vendor"]), ["boot"]);
+        // the exclude list wins over the include list
+        assert_eq!(names(Some(&["boot", "system"]), &["system"]), ["boot"]);
+        assert_eq!(names(Some(&["*o*"]), &["?endor"]), ["boot"]);
+        assert_eq!(names(Some(&["sys*"]), &[]), ["system"]);
+    }
+
+    fn replace_op(data_offset: u64, data: &[u8], start_block: u64) -> InstallOperation {
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(data);
+        InstallOperation {
+            r#type: OperationType::Replace.into(),
+            data_offset: Some(data_offset),
+            data_length: Some(u64(data.len())),
+            dst_extents: vec![RawExtent { start_block: Some(start_block), num_blocks: Some(1) }],
+            data_sha256_hash: Some(hasher.finalize()),
+            ..Default::default()
+        }
+    }
+
+    /// a partition named `test` with the operations
+    fn test_part(operations: Vec<InstallOperation>) -> PartitionUpdate {
+        PartitionUpdate { partition_name: "test".to_string(), operations, ..Default::default() }
+    }
+
+    /// the settings partitions are applied with in tests: 4 byte blocks, with the data of the
+    /// operations starting at the beginning of the payload
+    fn test_config(args: &ExtractOptions) -> PartConfig<'_> {
+        PartConfig {
+            args,
+            layout: PayloadLayout { data_offset: 0 },
+            block_size: 4,
+            progress: &StderrProgress,
+        }
+    }
+
+    /// applies the partition to an image in memory, reading its data from `data` and its src
+    /// from `src_data`
+    fn apply_part(
+        part: &PartitionUpdate,
+        args: &ExtractOptions,
+        data: &mut (impl Read + Seek),
+        src_data: Option<&[u8]>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut dst = Cursor::new(vec![]);
+        process_part(
+            part,
+            data,
+            src_data.map(Cursor::new).as_mut(),
+            &mut dst,
+            &test_config(args),
+            &mut Profile::default(),
+            None,
+        )?;
+        Ok(dst.into_inner())
+    }
+
+    #[test]
+    fn process_part_data_offset_zero_test() {
+        let payload_data = [1, 2, 3, 4, 5, 6, 7, 8];
+        // the first op's data legitimately starts at the beginning of the data region
+        let part = test_part(vec![
+            replace_op(0, &payload_data[..4], 1),
+            replace_op(4, &payload_data[4..], 0),
+        ]);
+
+        let image = apply_part(&part, &options(), &mut Cursor::new(&payload_data), None);
+        assert_eq!(image.unwrap(), [5, 6, 7, 8, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn stream_part_test() {
+        let payload_data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut zero = in_place_op(OperationType::Zero, 0, 3);
+        zero.src_extents.clear();
+        // a gap at block 2 and a partition size past the last extent
+        let mut part = PartitionUpdate {
+            new_partition_info: Some(PartitionInfo { size: Some(20), hash: None }),
+            ..test_part(vec![
+                replace_op(0, &payload_data[..4], 0),
+                replace_op(4, &payload_data[4..], 1),
+                zero,
+            ])
+        };
+        assert!(writes_forward(&part));
+
+        let args = options();
+        let mut out = vec![];
+        let mut data = Cursor::new(&payload_data);
+        stream_part(&part, &mut data, &mut out, &test_config(&args), &mut Profile::default())
+            .unwrap();
+        assert_eq!(out, [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        part.operations.swap(0, 1);
+        assert!(!writes_forward(&part));
+        part.operations.swap(0, 1);
+        part.operations[2] = in_place_op(OperationType::SourceCopy, 0, 3);
+        assert!(!writes_forward(&part));
+    }
+
+    #[test]
+    fn replace_zstd_test() {
+        let image = (0..16).collect::<Vec<u8>>();
+        let payload_data = zstd::encode_all(&image[..], 0).unwrap();
+        let op = InstallOperation {
+            r#type: OperationType::ReplaceZstd.into(),
+            dst_extents: vec![RawExtent { start_block: Some(0), num_blocks: Some(4) }],
+            ..replace_op(0, &payload_data, 0)
+        };
+        let part = test_part(vec![op]);
+
+        let extracted = apply_part(&part, &options(), &mut Cursor::new(&payload_data), None);
+        assert_eq!(extracted.unwrap(), image);
+    }
+
+    #[test]
+    fn replace_data_hash_test() {
+        let payload_data = [1, 2, 3, 4];
+        let mut op = replace_op(0, &[5, 6, 7, 8], 0);
+        op.r#type = OperationType::ReplaceXz.into();
+        let part = test_part(vec![op]);
+        let err = apply_part(&part, &options(), &mut Cursor::new(&payload_data), None).unwrap_err();
+        // the data isn't xz either, but the hash is checked while decompressing and explains it
+        assert!(format!("{:#}", err).contains("checking data hash"), "{:#}", err);
+    }
+
+    /// fails the first `failures` reads with a timeout, like a flaky network filesystem
+    struct FlakyReader<R> {
+        inner: R,
+        failures: usize,
+    }
+
+    impl<R: Read> Read for FlakyReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl<R: Seek> Seek for FlakyReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn op_retries_test() {
+        let payload_data = [1, 2, 3, 4];
+        let part = test_part(vec![replace_op(0, &payload_data, 0)]);
+        let run = |args: &ExtractOptions| {
+            let mut data = FlakyReader { inner: Cursor::new(&payload_data), failures: 2 };
+            apply_part(&part, args, &mut data, None)
+        };
+
+        assert!(run(&ExtractOptions { op_retries: 1, ..options() }).is_err());
+        assert_eq!(run(&ExtractOptions { op_retries: 2, ..options() }).unwrap(), payload_data);
+    }
+
+    #[test]
+    fn part_dst_bytes_test() {
+        let op = |start, num| InstallOperation {
+            dst_extents: vec![RawExtent { start_block: Some(start), num_blocks: Some(num) }],
+            ..Default::default()
+        };
+        let mut part =
+            PartitionUpdate { operations: vec![op(0, 2), op(4, 1)], ..Default::default() };
+        assert_eq!(part_dst_bytes(&part, 4096), 3 * 4096);
+        // malformed extents saturate instead of overflowing
+        part.operations.push(op(8, u64::MAX / 2));
+        assert_eq!(part_dst_bytes(&part, 4096), u64::MAX);
+    }
+
+    #[test]
+    fn name_map_test() {
+        let overrides = |mapping: &[(&str, &str)]| {
+            let name_map = mapping
+                .iter()
+                .map(|(name, path)| (name.to_string(), path.to_string()))
+                .collect::<HashMap<_, _>>();
+            PartOverrides::new(&ExtractOptions { name_map, ..options() })
+                .map(|overrides| overrides.names)
+        };
+        let names =
+            overrides(&[("boot", "./kernel.img"), ("system", "images/system.img")]).unwrap();
+        assert_eq!(names["boot"], "kernel.img");
+        assert_eq!(names["system"], Path::new("images/system.img").to_string_lossy());
+
+        for path in ["../boot.img", "/tmp/boot.img", ".", "a/../../b"] {
+            let err = overrides(&[("boot", path)]).err().unwrap();
+            assert!(format!("{:#}", err).contains("Invalid --name-map"), "{:#}", err);
+        }
+        let err = overrides(&[("boot", "same.img"), ("system", "./same.img")]).err().unwrap();
+        assert_eq!(err.to_string(), "--name-map gives boot and system the same path same.img");
+    }
+
+    #[test]
+    fn part_memory_test() {
+        let bsdiff = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(100),
+            ..in_place_op(OperationType::SourceBsdiff, 0, 0)
+        };
+        let part = test_part(vec![replace_op(0, &[0; 4096], 0), bsdiff]);
+        let args = ExtractOptions { io_buffer_size: 1000, ..options() };
+        // the replace streams, the bsdiff holds its patch and src
+        assert_eq!(part_memory(&part, 4096, &args), 100 + 4096 + 1000);
+        assert_eq!(part_memory(&test_part(vec![replace_op(0, &[0; 4096], 0)]), 4096, &args), 1000);
+        // every worker has a couple of operations with their output in memory
+        let args = ExtractOptions { threads: 2, ..args };
+        assert_eq!(part_memory(&part, 4096, &args), 4 * (4096 + 100 + 4096) + 2 * 1000);
+    }
+
+    #[test]
+    fn part_files_test() {
+        let full = test_part(vec![replace_op(0, &[0; 4], 0)]);
+        assert_eq!(part_files(&full, &options()), 3);
+        let src = ExtractOptions { src: Some("old".to_string()), ..options() };
+        let args = ExtractOptions { per_op_output: Some("ops".to_string()), ..src.clone() };
+        assert_eq!(part_files(&full, &args), 5);
+        let in_place = test_part(vec![in_place_op(OperationType::Move, 0, 1)]);
+        assert_eq!(part_files(&in_place, &src), 5);
+    }
+
+    #[test]
+    fn check_group_dir_test() {
+        assert!(check_group_dir("qti_dynamic_partitions").is_ok());
+        for group in ["", ".", "..", "../../x", "/etc", "a/b"] {
+            assert!(check_group_dir(group).is_err(), "{:?}", group);
+        }
+    }
+
+    #[test]
+    fn first_difference_test() {
+        let diff = |a: &[u8], b: &[u8]| first_difference(&mut &*a, &mut &*b).unwrap();
+        assert_eq!(diff(b"same", b"same"), None);
+        assert_eq!(diff(b"same", b"sane"), Some(2));
+        assert_eq!(diff(b"same", b"sam"), Some(3));
+        assert_eq!(diff(b"sam", b"same"), Some(3));
+    }
+
+    #[test]
+    fn summary_format_test() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3 << 20), "3.0 MiB");
+        assert_eq!(format_size(13_314_398_618), "12.4 GiB");
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(222)), "3m42s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h02m05s");
+    }
+
+    #[test]
+    fn strict_overlap_test() {
+        let payload_data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let part = test_part(vec![
+            replace_op(0, &payload_data[..4], 0),
+            replace_op(4, &payload_data[4..], 0),
+        ]);
+        let run =
+            |args: &ExtractOptions| apply_part(&part, args, &mut Cursor::new(&payload_data), None);
+
+        assert!(run(&ExtractOptions { check_overlap: true, ..options() }).is_ok());
+        assert!(run(&ExtractOptions { strict: true, ..options() }).is_err());
+    }
+
+    #[test]
+    fn parallel_jobs_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = dir.path().join("payload.bin");
+        let payload_data = [1, 2, 3, 4, 5, 6, 7, 8];
+        fs::write(&payload, payload_data).unwrap();
+        let dst = dir.path().join("out");
+        fs::create_dir(&dst).unwrap();
+        let args = ExtractOptions {
+            jobs: Some(3),
+            ..options_in(DeltaArchiveManifest::default(), &payload, &dst)
+        };
+
+        let mut parts = (0..5)
+            .map(|i: usize| PartitionUpdate {
+                partition_name: format!("part{}", i),
+                operations: vec![replace_op(u64(i % 2 * 4), &payload_data[i % 2 * 4..][..4], 0)],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let jobs = jobs(&args);
+        let selected = parts.iter().collect::<Vec<_>>();
+        let images = jobs.run(&selected, &mut Profile::default()).unwrap();
+        // the images come back in the order of the partitions, whichever thread wrote them
+        for (i, (image, _)) in images.iter().enumerate() {
+            let path = image.path.as_ref().unwrap();
+            assert!(path.ends_with(format!("part{}.img", i)));
+            assert_eq!(fs::read(path).unwrap()[..4], payload_data[i % 2 * 4..][..4]);
+        }
+
+        parts[3].operations = vec![replace_op(100, &payload_data[..4], 0)];
+        let selected = parts.iter().collect::<Vec<_>>();
+        let err = jobs.run(&selected, &mut Profile::default()).err().unwrap();
+        assert!(format!("{}", err).contains("part3"));
+    }
+
+    #[test]
+    fn emit_empty_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = dir.path().join("payload.bin");
+        fs::write(&payload, []).unwrap();
+        let args = ExtractOptions {
+            emit_empty: true,
+            ..options_in(DeltaArchiveManifest::default(), &payload, dir.path())
+        };
+
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(&[0; 16]);
+        let info = PartitionInfo { size: Some(16), hash: Some(hasher.finalize()) };
+        let mut zero = in_place_op(OperationType::Zero, 0, 0);
+        zero.src_extents.clear();
+        let mut parts = [
+            PartitionUpdate { new_partition_info: Some(info.clone()), ..test_part(vec![]) },
+            PartitionUpdate {
+                partition_name: "zeroed".to_string(),
+                new_partition_info: Some(info),
+                ..test_part(vec![zero])
+            },
+        ];
+        assert!(parts.iter().all(is_empty_partition));
+        assert!(!is_empty_partition(&test_part(vec![replace_op(0, &[1, 2, 3, 4], 0)])));
+
+        let jobs = jobs(&args);
+        let selected = parts.iter().collect::<Vec<_>>();
+        for (image, _) in jobs.run(&selected, &mut Profile::default()).unwrap() {
+            assert_eq!(fs::read(image.path.unwrap()).unwrap(), [0; 16]);
+        }
+
+        // the size of the image can't come from anywhere else
+        parts[0].new_partition_info = None;
+        let err =
+            jobs.run(&parts.iter().collect::<Vec<_>>(), &mut Profile::default()).err().unwrap();
+        assert!(format!("{:#}", err).contains("No size given"), "{:#}", err);
+    }
+
+    #[test]
+    fn missing_src_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out");
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![
+                PartitionUpdate {
+                    partition_name: "boot".to_string(),
+                    operations: vec![replace_op(0, &[0; 4], 0)],
+                    ..Default::default()
+                },
+                PartitionUpdate {
+                    partition_name: "system".to_string(),
+                    operations: vec![in_place_op(OperationType::SourceCopy, 0, 0)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let args = options_in(manifest, &dir.path().join("payload.bin"), &dst);
+        let err = extract(&args, &StderrProgress).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("--src") && message.contains("system.img"), "{}", message);
+        // nothing was written, not even the image of the full partition
+        assert!(!dst.exists());
+    }
+
+    #[test]
+    fn require_full_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("out");
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![PartitionUpdate {
+                partition_name: "system".to_string(),
+                operations: vec![in_place_op(OperationType::SourceCopy, 0, 0)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let args = ExtractOptions {
+            src: Some(dir.path().to_string_lossy().into_owned()),
+            require_full: true,
+            ..options_in(manifest, &dir.path().join("payload.bin"), &dst)
+        };
+        let err = extract(&args, &StderrProgress).unwrap_err();
+        assert_eq!(format!("{:#}", err), "Payload is Incremental rather than a full payload");
+        assert!(!dst.exists());
+    }
+
+    fn in_place_op(op_type: OperationType, src_block: u64, dst_block: u64) -> InstallOperation {
+        InstallOperation {
+            r#type: op_type.into(),
+            src_extents: vec![RawExtent { start_block: Some(src_block), num_blocks: Some(1) }],
+            dst_extents: vec![RawExtent { start_block: Some(dst_block), num_blocks: Some(1) }],
+            ..Default::default()
+        }
+    }
+
+    /// applies the operations with 4 byte blocks and their data in `data` to the src image
+    /// `src_data`, writing the image to a file like `extract_part` does
+    fn apply_to_src(
+        operations: Vec<InstallOperation>,
+        data: &[u8],
+        src_data: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let part = test_part(operations);
+        let args = options();
+        let mut image = tempfile::NamedTempFile::new()?;
+        let path = image.path().to_path_buf();
+        let src: Box<dyn StreamRead> = Box::new(Cursor::new(src_data.to_vec()));
+        let mut src = image_src(&part, Some(BufReader::new(src)), image.as_file_mut(), &path)?;
+        let mut dst = BufWriter::new(image.as_file_mut());
+        process_part(
+            &part,
+            &mut Cursor::new(data),
+            src.as_mut(),
+            &mut dst,
+            &test_config(&args),
+            &mut Profile::default(),
+            None,
+        )?;
+        dst.flush()?;
+        Ok(fs::read(path)?)
+    }
+
+    #[test]
+    fn move_op_test() {
+        let src_data = [1, 2, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0];
+        let move_op = |src_block, dst_block| in_place_op(OperationType::Move, src_block, dst_block);
+        // swaps the first two blocks through the third, each move reading what the one before wrote
+        let ops = vec![move_op(0, 2), move_op(1, 0), move_op(2, 1)];
+        assert_eq!(
+            apply_to_src(ops, &[], &src_data).unwrap(),
+            [5, 6, 7, 8, 1, 2, 3, 4, 1, 2, 3, 4]
+        );
+        // a move onto blocks it reads itself still copies what they held before
+        let shift = InstallOperation {
+            src_extents: vec![RawExtent { start_block: Some(0), num_blocks: Some(2) }],
+            dst_extents: vec![RawExtent { start_block: Some(1), num_blocks: Some(2) }],
+            ..move_op(0, 0)
+        };
+        assert_eq!(
+            apply_to_src(vec![shift], &[], &src_data).unwrap(),
+            [1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        // the src image is overwritten, so A/B operations can't read it
+        let ops = vec![move_op(1, 0), in_place_op(OperationType::SourceCopy, 0, 1)];
+        assert!(apply_to_src(ops, &[], &src_data).is_err());
+    }
+
+    #[test]
+    fn parallel_ops_test() {
+        let payload_data = (0..64).collect::<Vec<u8>>();
+        let src_data = (100..132).collect::<Vec<u8>>();
+        let mut operations = (0..12)
+            .map(|i| replace_op(i * 4, &payload_data[usize(i * 4)..][..4], (i * 5) % 12))
+            .collect::<Vec<_>>();
+        // a copy with scattered extents, and an operation overwriting blocks of earlier ones
+        operations.push(InstallOperation {
+            src_extents: vec![
+                RawExtent { start_block: Some(6), num_blocks: Some(2) },
+                RawExtent { start_block: Some(0), num_blocks: Some(1) },
+            ],
+            dst_extents: vec![
+                RawExtent { start_block: Some(13), num_blocks: Some(1) },
+                RawExtent { start_block: Some(12), num_blocks: Some(1) },
+                RawExtent { start_block: Some(14), num_blocks: Some(1) },
+            ],
+            ..in_place_op(OperationType::SourceCopy, 0, 0)
+        });
+        operations.push(InstallOperation {
+            dst_extents: vec![RawExtent { start_block: Some(3), num_blocks: Some(2) }],
+            ..replace_op(48, &payload_data[48..56], 0)
+        });
+        let part = test_part(operations);
+
+        let apply = |part: &PartitionUpdate, threads: usize| {
+            let args = ExtractOptions { threads, ..options() };
+            apply_part(part, &args, &mut Cursor::new(&payload_data), Some(&src_data))
+        };
+        let images = [apply(&part, 1).unwrap(), apply(&part, 4).unwrap()];
+        assert_eq!(images[0], images[1]);
+        assert_eq!(images[1][12..20], payload_data[48..56]);
+        assert_eq!(images[1][48..], [128, 129, 130, 131, 124, 125, 126, 127, 100, 101, 102, 103]);
+
+        // a failing operation stops the others instead of leaving the workers waiting
+        let mut part = part;
+        part.operations[5].data_sha256_hash = Some(vec![0; 32]);
+        assert!(apply(&part, 4).is_err());
+    }
+
+    #[test]
+    fn in_place_bsdiff_test() {
+        // like move, bsdiff reads the partition in place, so it patches what the move wrote to
+        // block 0 rather than the block the src image has there
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(&[1, 2, 3, 4]);
+        let bsdiff = InstallOperation {
+            src_sha256_hash: Some(hasher.finalize()),
+            ..in_place_op(OperationType::Bsdiff, 0, 1)
+        };
+        let ops = vec![in_place_op(OperationType::Move, 1, 0), bsdiff];
+        let err = apply_to_src(ops, &[], &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap_err();
+        assert!(format!("{:#}", err).contains("checking src hash"));
+
+        // bin1 is moved into place, then patched into bin2 where the move left it
+        let (bin1, patch, bin2) = (
+            fs::read("test/bin1").unwrap(),
+            fs::read("test/patch").unwrap(),
+            fs::read("test/bin2").unwrap(),
+        );
+        let blocks = u64(bin1.len() / 4);
+        let extents = |start_block| {
+            vec![RawExtent { start_block: Some(start_block), num_blocks: Some(blocks) }]
+        };
+        let shift = InstallOperation {
+            src_extents: extents(1),
+            dst_extents: extents(0),
+            ..in_place_op(OperationType::Move, 0, 0)
+        };
+        let bsdiff = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(u64(patch.len())),
+            src_extents: extents(0),
+            dst_extents: extents(0),
+            ..in_place_op(OperationType::Bsdiff, 0, 0)
+        };
+        let src_data = [&[0xff; 4][..], &bin1].concat();
+        let image = apply_to_src(vec![shift, bsdiff], &patch, &src_data).unwrap();
+        assert!(image[..bin2.len()] == bin2);
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{bail, Context, Result};
+use cast::{u64, usize};
+use serde::Serialize;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{
+    payload::{absolute_data_range, PayloadLayout},
+    update_metadata::{
+        install_operation::Type as OperationType, Extent as RawExtent, PartitionUpdate,
+    },
+};
+
+/// one operation in the index of an operation archive
+#[derive(Serialize)]
+struct IndexEntry<'a> {
+    partition: &'a str,
+    op: usize,
+    #[serde(rename = "type")]
+    op_type: String,
+    data_offset: Option<u64>,
+    data_length: Option<u64>,
+    src_extents: &'a [RawExtent],
+    dst_extents: &'a [RawExtent],
+    /// the archive member holding the operation's data, if it has any
+    file: Option<String>,
+}
+
+/// writes a member of a `newc` cpio archive, with fixed metadata so archives are reproducible
+fn write_cpio_entry(out: &mut impl Write, ino: usize, name: &str, len: u64) -> Result<()> {
+    let (Ok(ino), Ok(len)) = (u32::try_from(ino), u32::try_from(len)) else {
+        bail!("{} doesn't fit in a cpio archive", name);
+    };
+    let mode = if name == "TRAILER!!!" { 0 } else { 0o100644 };
+    let name_size = u32::try_from(name.len() + 1)?;
+    let fields = [ino, mode, 0, 0, 1, 0, len, 0, 0, 0, 0, name_size, 0];
+    write!(out, "070701")?;
+    for field in fields {
+        write!(out, "{:08X}", field)?;
+    }
+    out.write_all(name.as_bytes())?;
+    // the name is nul terminated and padded so the data starts at a multiple of 4
+    let header_len = 6 + 8 * fields.len() + name.len() + 1;
+    out.write_all(&[0; 4][..1 + (4 - header_len % 4) % 4])?;
+    Ok(())
+}
+
+/// pads the data of a cpio member to a multiple of 4 bytes
+fn pad_cpio_data(out: &mut impl Write, len: u64) -> io::Result<()> {
+    out.write_all(&[0; 3][..usize((4 - len % 4) % 4)])
+}
+
+/// writes a cpio archive with an `index.json` describing the operations of `parts` followed by
+/// the raw data of every operation which has some, without applying anything
+pub fn archive_ops(
+    parts: &[&PartitionUpdate],
+    data: &mut (impl Read + Seek),
+    layout: &PayloadLayout,
+    out: &mut impl Write,
+) -> Result<usize> {
+    let mut index = vec![];
+    for part in parts {
+        for (i, op) in part.operations.iter().enumerate() {
+            let op_type = OperationType::try_from(op.r#type)
+                .map_or_else(|_| format!("{}", op.r#type), |op_type| format!("{:?}", op_type));
+            let file = absolute_data_range(layout, op)
+                .map(|_| format!("{}/op{}.bin", part.partition_name, i));
+            index.push(IndexEntry {
+                partition: &part.partition_name,
+                op: i,
+                op_type,
+                data_offset: op.data_offset,
+                data_length: op.data_length,
+                src_extents: &op.src_extents,
+                dst_extents: &op.dst_extents,
+                file,
+            });
+        }
+    }
+
+    let index_json = serde_json::to_vec_pretty(&index)?;
+    write_cpio_entry(out, 1, "index.json", u64(index_json.len()))?;
+    out.write_all(&index_json)?;
+    pad_cpio_data(out, u64(index_json.len()))?;
+
+    let mut ino = 1;
+    let ops = parts.iter().flat_map(|part| part.operations.iter());
+    for (entry, op) in index.iter().zip(ops) {
+        let (Some(file), Some(range)) = (&entry.file, absolute_data_range(layout, op)) else {
+            continue;
+        };
+        ino += 1;
+        let len = range.end - range.start;
+        write_cpio_entry(out, ino, file, len)?;
+        data.seek(SeekFrom::Start(range.start))?;
+        let copied = io::copy(&mut data.by_ref().take(len), out)?;
+        if copied != len {
+            bail!("Payload ended inside the data of {}", file);
+        }
+        pad_cpio_data(out, len).with_context(|| format!("Failed to write {}", file))?;
+    }
+    write_cpio_entry(out, 0, "TRAILER!!!", 0)?;
+    out.flush()?;
+    Ok(ino - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::archive_ops;
+    use crate::{
+        payload::PayloadLayout,
+        update_metadata::{InstallOperation, PartitionUpdate},
+    };
+
+    #[test]
+    fn archive_ops_test() {
+        let part = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![
+                InstallOperation {
+                    data_offset: Some(1),
+                    data_length: Some(3),
+                    ..Default::default()
+                },
+                InstallOperation::default(),
+            ],
+            ..Default::default()
+        };
+        let mut payload = Cursor::new(b"xxabc".to_vec());
+        let mut out = vec![];
+        let archived =
+            archive_ops(&[&part], &mut payload, &PayloadLayout { data_offset: 1 }, &mut out)
+                .unwrap();
+        assert_eq!(archived, 1);
+
+        let out = String::from_utf8_lossy(&out);
+        assert!(out.starts_with("070701") && out.contains("index.json"));
+        // the data member follows its name, padded to a multiple of 4; the index names it first
+        let name_end = out.rfind("boot/op0.bin").unwrap() + "boot/op0.bin".len();
+        let data_start = name_end + out[name_end..].find(|c| c != '\0').unwrap();
+        assert_eq!(data_start % 4, 0);
+        assert_eq!(&out[data_start..data_start + 3], "abc");
+        assert!(out.contains("TRAILER!!!"));
+        assert_eq!(out.len() % 4, 0);
+    }
+}
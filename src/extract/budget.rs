@@ -0,0 +1,65 @@
+use std::sync::{Condvar, Mutex};
+
+/// an amount of something the --jobs partitions share, like memory, which each partition
+/// reserves its part of while it's extracted
+pub struct Budget {
+    total: u64,
+    used: Mutex<u64>,
+    freed: Condvar,
+}
+
+/// a part of a budget, which is given back when it's dropped
+pub struct Reservation<'a> {
+    budget: &'a Budget,
+    amount: u64,
+}
+
+impl Budget {
+    pub fn new(total: u64) -> Self {
+        Self { total, used: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    /// waits until `amount` is free and reserves it; more than the whole budget is reserved once
+    /// nothing else is, so a big partition still runs, just on its own
+    pub fn reserve(&self, amount: u64) -> Reservation<'_> {
+        let amount = amount.min(self.total);
+        let used = self.used.lock().unwrap();
+        let mut used = self.freed.wait_while(used, |used| *used + amount > self.total).unwrap();
+        *used += amount;
+        Reservation { budget: self, amount }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= self.amount;
+        self.budget.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::Budget;
+
+    #[test]
+    fn budget_test() {
+        let budget = Budget::new(10);
+        let first = budget.reserve(6);
+        // more than the whole budget only waits for everything else to be given back
+        let (reserved_tx, reserved_rx) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _all = budget.reserve(100);
+                reserved_tx.send(()).unwrap();
+            });
+            assert!(reserved_rx.recv_timeout(Duration::from_millis(50)).is_err());
+            drop(first);
+            reserved_rx.recv().unwrap();
+        });
+        let _small = budget.reserve(4);
+        let _rest = budget.reserve(6);
+        assert_eq!(*budget.used.lock().unwrap(), 10);
+    }
+}
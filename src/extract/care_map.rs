@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use std::{fmt::Write, fs, ops::Range};
+
+use super::overlap::WrittenBlocks;
+use crate::update_metadata::PartitionUpdate;
+
+/// the blocks the operations of the partition write, leaving out holes
+pub fn written_ranges(part: &PartitionUpdate) -> Vec<Range<u64>> {
+    let mut written = WrittenBlocks::default();
+    for extent in part.operations.iter().flat_map(|op| &op.dst_extents) {
+        match (extent.start_block, extent.num_blocks) {
+            (Some(u64::MAX), _) | (None, _) | (_, None) => {}
+            (Some(start), Some(num)) => {
+                written.insert(start..start.saturating_add(num));
+            }
+        }
+    }
+    written.ranges().collect()
+}
+
+/// the care map in AOSP's text format: the name of each partition on one line, and its written
+/// blocks on the next as the number of values followed by the start and end of each range
+fn care_map(parts: &[&PartitionUpdate]) -> String {
+    let mut care_map = String::new();
+    for part in parts {
+        let ranges = written_ranges(part);
+        let mut line = (ranges.len() * 2).to_string();
+        for range in ranges {
+            let _ = write!(line, ",{},{}", range.start, range.end);
+        }
+        let _ = writeln!(care_map, "{}\n{}", part.partition_name, line);
+    }
+    care_map
+}
+
+/// writes the care map of `parts` to `path`, so the unwritten blocks can be skipped when flashing
+pub fn write_care_map(path: &str, parts: &[&PartitionUpdate]) -> Result<()> {
+    fs::write(path, care_map(parts)).with_context(|| format!("Failed to write care map {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::care_map;
+    use crate::update_metadata::{Extent, InstallOperation, PartitionUpdate};
+
+    #[test]
+    fn care_map_test() {
+        let extent = |start, len| Extent { start_block: Some(start), num_blocks: Some(len) };
+        let part = |name: &str, dst_extents: Vec<Vec<Extent>>| PartitionUpdate {
+            partition_name: name.to_string(),
+            operations: dst_extents
+                .into_iter()
+                .map(|dst_extents| InstallOperation { dst_extents, ..Default::default() })
+                .collect(),
+            ..Default::default()
+        };
+        let system = part(
+            "system",
+            vec![vec![extent(10, 5), extent(0, 2)], vec![extent(2, 3), extent(u64::MAX, 4)]],
+        );
+        let empty = part("odm", vec![]);
+        assert_eq!(care_map(&[&system, &empty]), "system\n4,0,5,10,15\nodm\n0\n");
+    }
+}
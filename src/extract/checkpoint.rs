@@ -0,0 +1,95 @@
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// records the index of the next operation to apply to a partition in a sidecar file,
+/// so that an interrupted partition can be resumed without redoing the earlier operations
+pub struct Checkpoint {
+    path: PathBuf,
+    next_op: usize,
+    /// synced before each record, so a power loss can't keep the record but lose the writes
+    image: Option<File>,
+}
+
+impl Checkpoint {
+    /// loads the checkpoint for the image at `img_path`, starting at 0 if there is none
+    pub fn open(img_path: &Path) -> Result<Self> {
+        let mut path = OsString::from(img_path);
+        path.push(".progress");
+        let path = PathBuf::from(path);
+
+        let next_op = match fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid checkpoint file {}", path.display()))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => Err(err)
+                .with_context(|| format!("Failed to read checkpoint file {}", path.display()))?,
+        };
+        Ok(Self { path, next_op, image: None })
+    }
+
+    /// makes each record wait for the image's data to reach the disk
+    pub fn sync_image(&mut self, image: &File) -> Result<()> {
+        self.image = Some(image.try_clone()?);
+        Ok(())
+    }
+
+    pub fn next_op(&self) -> usize {
+        self.next_op
+    }
+
+    /// records that the operations before `next_op` are done; their output must already be
+    /// flushed to the image
+    pub fn record(&mut self, next_op: usize) -> Result<()> {
+        if let Some(image) = &self.image {
+            image.sync_data().context("Failed to sync the image before the checkpoint")?;
+        }
+        self.next_op = next_op;
+        // renaming replaces the old checkpoint in one step, so an interrupted write can't leave
+        // a torn one behind
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, next_op.to_string())
+            .and_then(|()| fs::rename(&tmp_path, &self.path))
+            .with_context(|| format!("Failed to write checkpoint file {}", self.path.display()))
+    }
+
+    /// removes the sidecar file once the partition is complete
+    pub fn finish(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err).with_context(|| {
+                format!("Failed to remove checkpoint file {}", self.path.display())
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::Checkpoint;
+
+    #[test]
+    fn checkpoint_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let img_path = dir.path().join("system.img");
+        let mut checkpoint = Checkpoint::open(&img_path).unwrap();
+        assert_eq!(checkpoint.next_op(), 0);
+        checkpoint.sync_image(&File::create(&img_path).unwrap()).unwrap();
+        checkpoint.record(3).unwrap();
+        assert!(!dir.path().join("system.img.progress.tmp").exists());
+
+        let checkpoint = Checkpoint::open(&img_path).unwrap();
+        assert_eq!(checkpoint.next_op(), 3);
+        checkpoint.finish().unwrap();
+        assert!(!dir.path().join("system.img.progress").exists());
+    }
+}
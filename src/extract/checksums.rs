@@ -0,0 +1,48 @@
+use anyhow::{Context, Result};
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// the images and their sha256 hashes in sha256sum's format, with the paths of the images inside
+/// `dir` relative to it
+fn checksums(dir: &Path, images: &[(PathBuf, Vec<u8>)]) -> String {
+    let mut checksums = String::new();
+    for (path, hash) in images {
+        let path = path.strip_prefix(dir).unwrap_or(path);
+        for byte in hash {
+            let _ = write!(checksums, "{:02x}", byte);
+        }
+        let _ = writeln!(checksums, "  {}", path.display());
+    }
+    checksums
+}
+
+/// writes the checksums of the images to `path`, so they can be checked with `sha256sum -c` from
+/// the folder `path` is in
+pub fn write_checksums(path: &str, images: &[(PathBuf, Vec<u8>)]) -> Result<()> {
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    fs::write(path, checksums(dir, images))
+        .with_context(|| format!("Failed to write checksums {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::checksums;
+
+    #[test]
+    fn checksums_test() {
+        let images = [
+            (PathBuf::from("out/boot.img"), vec![0x00, 0xab]),
+            (PathBuf::from("out/main/system.img"), vec![0xff, 0x10]),
+            (PathBuf::from("elsewhere/vendor.img"), vec![0x01, 0x02]),
+        ];
+        assert_eq!(
+            checksums(Path::new("out"), &images),
+            "00ab  boot.img\nff10  main/system.img\n0102  elsewhere/vendor.img\n"
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use cast::usize;
+use std::{fs, io::Cursor, path::Path};
+
+use super::{
+    check_overlap, op_inputs, overlap::WrittenBlocks, reads_separate_src, src_image::SrcImage,
+    updates_in_place, ExtractOptions, PartOverrides,
+};
+use crate::{
+    extent::{convert_extents, ExtentStream},
+    payload::{absolute_data_range, PayloadLayout},
+    update_metadata::{
+        install_operation::Type as OperationType, DeltaArchiveManifest, Extent as RawExtent,
+        InstallOperation, PartitionUpdate, DEFAULT_BLOCK_SIZE,
+    },
+};
+
+/// builds the stream the operation would use over `extents`, which checks them the same way;
+/// only the dst extents have to be disjoint
+fn check_extents(extents: &[RawExtent], block_size: usize, dst: bool) -> Result<()> {
+    let extents = convert_extents(extents, block_size)?;
+    let stream = match dst {
+        true => ExtentStream::new_disjoint(Cursor::new([0_u8; 0]), extents)?,
+        false => ExtentStream::new(Cursor::new([0_u8; 0]), extents)?,
+    };
+    stream.ok_or_else(|| anyhow!("No extents"))?;
+    Ok(())
+}
+
+/// fails with the reason the operation couldn't be applied, without reading any of its data
+///
+/// `src` is the reason there's no src image, if there isn't one, and `in_place` is whether the
+/// partition `updates_in_place`
+fn check_op(
+    op: &InstallOperation,
+    block_size: usize,
+    src: Option<&str>,
+    in_place: bool,
+    layout: &PayloadLayout,
+    payload_len: u64,
+) -> Result<()> {
+    let op_type = OperationType::try_from(op.r#type)
+        .map_err(|_| anyhow!("Invalid operation type {}", op.r#type))?;
+    let (reads_src, reads_data) =
+        op_inputs(op_type).ok_or_else(|| anyhow!("Unsupported operation type {:?}", op_type))?;
+    check_extents(&op.dst_extents, block_size, true).context("Invalid dst_extents")?;
+    if reads_src {
+        if let Some(reason) = src {
+            bail!("No src image: {}", reason);
+        }
+        ensure!(
+            !(in_place && reads_separate_src(op)),
+            "Reads a separate src image, but the partition is updated in place"
+        );
+        check_extents(&op.src_extents, block_size, false).context("Invalid src_extents")?;
+    }
+    if reads_data {
+        let range = absolute_data_range(layout, op).ok_or_else(|| anyhow!("No data given"))?;
+        ensure!(
+            range.end <= payload_len,
+            "Data ends at 0x{:x}, past the end of the payload at 0x{:x}",
+            range.end,
+            payload_len
+        );
+    }
+    Ok(())
+}
+
+/// walks the operations of `parts` like extracting them would and prints them, checking
+/// everything which can be checked without reading their data, but doesn't write any images
+pub fn dry_run(
+    manifest: &DeltaArchiveManifest,
+    args: &ExtractOptions,
+    parts: &[&PartitionUpdate],
+    overrides: &PartOverrides,
+    layout: &PayloadLayout,
+) -> Result<()> {
+    let payload_len = fs::metadata(&args.data_file)?.len();
+    let (mut total, mut failing) = (0, 0);
+    for part in parts {
+        let name = &part.partition_name;
+        let block_size = match overrides.block_sizes.get(name) {
+            Some(block_size) => *block_size,
+            None => usize(manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE)),
+        };
+        let src = match &args.src {
+            Some(src_path) => {
+                SrcImage::find(Path::new(src_path), name).err().map(|err| format!("{:#}", err))
+            }
+            None => Some(format!("no --src given")),
+        };
+
+        let in_place = updates_in_place(part);
+        // the same overlap checks as process_part, which go through the operations in order
+        let mut written = (args.check_overlap || args.strict).then(WrittenBlocks::default);
+
+        println!("{}: {} operations", name, part.operations.len());
+        for (i, op) in part.operations.iter().enumerate() {
+            let op_type = OperationType::try_from(op.r#type)
+                .map_or_else(|_| format!("{}", op.r#type), |op_type| format!("{:?}", op_type));
+            let checked = check_op(op, block_size, src.as_deref(), in_place, layout, payload_len)
+                .and_then(|()| match written.as_mut() {
+                    Some(written) => check_overlap(written, part, i, args.strict),
+                    None => Ok(()),
+                });
+            match checked {
+                Ok(()) => println!("- #{} {}", i, op_type),
+                Err(err) => {
+                    println!("- #{} {}: would fail: {:#}", i, op_type, err);
+                    failing += 1;
+                }
+            }
+        }
+        total += part.operations.len();
+    }
+    if failing > 0 {
+        bail!("{} of {} operations would fail", failing, total);
+    }
+    println!("all {} operations would be applied", total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_op;
+    use crate::{
+        payload::PayloadLayout,
+        update_metadata::{
+            install_operation::Type as OperationType, Extent as RawExtent, InstallOperation,
+        },
+    };
+
+    #[test]
+    fn check_op_test() {
+        let extent = |start| vec![RawExtent { start_block: Some(start), num_blocks: Some(1) }];
+        let op = |op_type: OperationType, data_length: Option<u64>| InstallOperation {
+            r#type: op_type.into(),
+            data_offset: data_length.map(|_| 0),
+            data_length,
+            src_extents: extent(0),
+            dst_extents: extent(1),
+            ..Default::default()
+        };
+        let layout = PayloadLayout { data_offset: 10 };
+        let check = |op: &InstallOperation, src| check_op(op, 4096, src, false, &layout, 100);
+
+        assert!(check(&op(OperationType::Replace, Some(90)), None).is_ok());
+        assert!(check(&op(OperationType::Replace, Some(91)), None).is_err());
+        assert!(check(&op(OperationType::Replace, None), None).is_err());
+        assert!(check(&op(OperationType::Zero, None), Some("no --src given")).is_ok());
+        assert!(check(&op(OperationType::SourceCopy, None), None).is_ok());
+        let err = check(&op(OperationType::SourceCopy, None), Some("no --src given")).unwrap_err();
+        assert_eq!(err.to_string(), "No src image: no --src given");
+        assert!(check(&op(OperationType::Puffdiff, Some(1)), None).is_err());
+
+        let mut overlapping = op(OperationType::Zero, None);
+        overlapping.dst_extents.extend(extent(1));
+        assert!(check(&overlapping, None).is_err());
+
+        // an in place partition overwrites the src image A/B operations would read
+        assert!(check_op(&op(OperationType::Move, None), 4096, None, true, &layout, 100).is_ok());
+        let source_copy = op(OperationType::SourceCopy, None);
+        assert!(check_op(&source_copy, 4096, None, true, &layout, 100).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::{bail, ensure, Context, Result};
+use cast::{u64, usize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use super::hash::Sha256Hasher;
+
+// a limited read only ext4 reader, just enough to list the files of an image and hash them
+// unsupported: inline data and encrypted files
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT4_MAGIC: u16 = 0xef53;
+const EXTENT_MAGIC: u16 = 0xf30a;
+const ROOT_INODE: u32 = 2;
+/// the most levels of index nodes an extent tree can have
+const MAX_EXTENT_DEPTH: u16 = 5;
+
+const INCOMPAT_64BIT: u32 = 0x80;
+const EXTENTS_FL: u32 = 0x80000;
+const INLINE_DATA_FL: u32 = 0x10000000;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    block: [u8; 60],
+}
+
+/// `len` blocks starting at logical block `logical` of a file, which read as zeros if `physical`
+/// is none (uninitialized extents)
+struct Run {
+    logical: u64,
+    physical: Option<u64>,
+    len: u64,
+}
+
+/// what a path in the image is compared by
+#[derive(PartialEq, Eq, Debug)]
+pub struct FileSummary {
+    mode: u16,
+    /// the sha256 of the contents of regular files, the target of symlinks and empty otherwise
+    digest: Vec<u8>,
+}
+
+pub struct Ext4<R> {
+    inner: R,
+    block_size: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    desc_start: u64,
+    desc_size: u64,
+    is_64bit: bool,
+}
+
+impl<R: Read + Seek> Ext4<R> {
+    /// returns none if `inner` doesn't contain an ext4 filesystem
+    pub fn open(mut inner: R) -> Result<Option<Self>> {
+        let mut sb = [0; 1024];
+        inner.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        match inner.read_exact(&mut sb) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        if le_u16(&sb, 0x38) != EXT4_MAGIC {
+            return Ok(None);
+        }
+
+        let log_block_size = le_u32(&sb, 0x18);
+        ensure!(log_block_size <= 6, "Invalid block size 1024 << {}", log_block_size);
+        let block_size = 1024 << log_block_size;
+        let first_data_block = u64(le_u32(&sb, 0x14));
+        let inode_size = if le_u32(&sb, 0x4c) >= 1 { u64(le_u16(&sb, 0x58)) } else { 128 };
+        let is_64bit = le_u32(&sb, 0x60) & INCOMPAT_64BIT != 0;
+        let desc_size = if is_64bit { u64(le_u16(&sb, 0xfe)) } else { 32 };
+        ensure!(
+            (32..=block_size).contains(&desc_size),
+            "Invalid group descriptor size {}",
+            desc_size
+        );
+        let inodes_per_group = u64(le_u32(&sb, 0x28));
+        ensure!(inodes_per_group > 0, "Invalid inode count of 0 per group");
+        Ok(Some(Self {
+            inner,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            desc_start: (first_data_block + 1) * block_size,
+            desc_size,
+            is_64bit,
+        }))
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.read_exact(buf)
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let offset = block
+            .checked_mul(self.block_size)
+            .with_context(|| format!("Invalid block number {}", block))?;
+        let mut buf = vec![0; usize(self.block_size)];
+        self.read_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn inode(&mut self, n: u32) -> Result<Inode> {
+        ensure!(n > 0, "Invalid inode number 0");
+        let group = u64(n - 1) / self.inodes_per_group;
+        let index = u64(n - 1) % self.inodes_per_group;
+
+        let mut desc = vec![0; usize(self.desc_size)];
+        self.read_at(self.desc_start + group * self.desc_size, &mut desc)?;
+        let mut inode_table = u64(le_u32(&desc, 0x08));
+        if self.is_64bit && self.desc_size >= 64 {
+            inode_table |= u64(le_u32(&desc, 0x28)) << 32;
+        }
+
+        let mut raw = [0; 128];
+        self.read_at(inode_table * self.block_size + index * self.inode_size, &mut raw)
+            .with_context(|| format!("Failed to read inode {}", n))?;
+        Ok(Inode {
+            mode: le_u16(&raw, 0x00),
+            size: u64(le_u32(&raw, 0x04)) | (u64(le_u32(&raw, 0x6c)) << 32),
+            flags: le_u32(&raw, 0x20),
+            block: raw[0x28..0x28 + 60].try_into().unwrap(),
+        })
+    }
+
+    /// walks an extent tree node, whose depth is at most `max_depth` so that a corrupt tree which
+    /// points back at itself still ends
+    fn walk_extents(&mut self, node: &[u8], max_depth: u16, runs: &mut Vec<Run>) -> Result<()> {
+        ensure!(le_u16(node, 0) == EXTENT_MAGIC, "Invalid extent header");
+        let entries = usize(le_u16(node, 2));
+        let depth = le_u16(node, 6);
+        ensure!(depth <= max_depth, "Invalid extent tree depth {}", depth);
+        ensure!(12 * (entries + 1) <= node.len(), "Too many extent entries");
+        for entry in node[12..12 * (entries + 1)].chunks_exact(12) {
+            if depth == 0 {
+                let len = le_u16(entry, 4);
+                // lengths above 32768 mark uninitialized extents, which read as zeros
+                let (len, initialized) =
+                    if len > 32768 { (len - 32768, false) } else { (len, true) };
+                let start = u64(le_u32(entry, 8)) | (u64(le_u16(entry, 6)) << 32);
+                runs.push(Run {
+                    logical: u64(le_u32(entry, 0)),
+                    physical: initialized.then_some(start),
+                    len: u64(len),
+                });
+            } else {
+                let leaf = u64(le_u32(entry, 4)) | (u64(le_u16(entry, 8)) << 32);
+                let block = self.read_block(leaf)?;
+                self.walk_extents(&block, depth - 1, runs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// walks a block map with `depth` levels of indirection, skipping over holes
+    fn walk_indirect(
+        &mut self,
+        block: u32,
+        depth: u32,
+        logical: &mut u64,
+        runs: &mut Vec<Run>,
+    ) -> Result<()> {
+        let per_block = self.block_size / 4;
+        if block == 0 {
+            *logical += per_block.pow(depth);
+            return Ok(());
+        }
+        if depth == 0 {
+            runs.push(Run { logical: *logical, physical: Some(u64(block)), len: 1 });
+            *logical += 1;
+            return Ok(());
+        }
+        let pointers = self.read_block(u64(block))?;
+        for i in 0..usize(per_block) {
+            self.walk_indirect(le_u32(&pointers, 4 * i), depth - 1, logical, runs)?;
+        }
+        Ok(())
+    }
+
+    fn runs(&mut self, inode: &Inode) -> Result<Vec<Run>> {
+        ensure!(inode.flags & INLINE_DATA_FL == 0, "Inline data isn't supported");
+        let mut runs = vec![];
+        if inode.flags & EXTENTS_FL != 0 {
+            self.walk_extents(&inode.block, MAX_EXTENT_DEPTH, &mut runs)?;
+        } else {
+            let mut logical = 0;
+            let end = inode.size.div_ceil(self.block_size);
+            for (i, depth) in (0..15).zip([0; 12].into_iter().chain([1, 2, 3])) {
+                if logical >= end {
+                    break;
+                }
+                self.walk_indirect(le_u32(&inode.block, 4 * i), depth, &mut logical, &mut runs)?;
+            }
+        }
+        runs.sort_by_key(|run| run.logical);
+        Ok(runs)
+    }
+
+    /// passes the contents of the file to `f` in order, filling holes with zeros
+    fn read_file(&mut self, inode: &Inode, mut f: impl FnMut(&[u8])) -> Result<()> {
+        let block_size = self.block_size;
+        let zeros = vec![0; usize(block_size)];
+        let mut pos = 0;
+        let fill = |f: &mut dyn FnMut(&[u8]), pos: &mut u64, end: u64| {
+            while *pos < end {
+                let len = (end - *pos).min(block_size);
+                f(&zeros[..usize(len)]);
+                *pos += len;
+            }
+        };
+
+        for run in self.runs(inode)? {
+            let start = (run.logical * self.block_size).min(inode.size);
+            let end = ((run.logical + run.len) * self.block_size).min(inode.size);
+            fill(&mut f, &mut pos, start);
+            match run.physical {
+                Some(physical) => {
+                    for i in 0..run.len {
+                        if pos >= end {
+                            break;
+                        }
+                        let block = self.read_block(physical + i)?;
+                        let len = (end - pos).min(self.block_size);
+                        f(&block[..usize(len)]);
+                        pos += len;
+                    }
+                }
+                None => fill(&mut f, &mut pos, end),
+            }
+        }
+        fill(&mut f, &mut pos, inode.size);
+        Ok(())
+    }
+
+    fn read_dir(&mut self, inode: &Inode) -> Result<Vec<(String, u32)>> {
+        let mut data = vec![];
+        self.read_file(inode, |buf| data.extend_from_slice(buf))?;
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let entry_inode = le_u32(&data, offset);
+            let rec_len = usize(le_u16(&data, offset + 4));
+            let name_len = usize(data[offset + 6]);
+            if rec_len < 8 || offset + 8 + name_len > data.len() {
+                bail!("Corrupt directory entry at offset {}", offset);
+            }
+            let name = String::from_utf8_lossy(&data[offset + 8..offset + 8 + name_len]);
+            // inode 0 marks unused entries and the checksum tail
+            if entry_inode != 0 && name != "." && name != ".." {
+                entries.push((name.into_owned(), entry_inode));
+            }
+            offset += rec_len;
+        }
+        Ok(entries)
+    }
+
+    fn summarize(&mut self, inode: &Inode) -> Result<FileSummary> {
+        let digest = match inode.mode & S_IFMT {
+            S_IFREG => {
+                let mut hasher = Sha256Hasher::new();
+                self.read_file(inode, |buf| hasher.update(buf))?;
+                hasher.finalize()
+            }
+            // fast symlinks store their target in place of the block map
+            S_IFLNK if inode.size < 60 && inode.flags & EXTENTS_FL == 0 => {
+                inode.block[..usize(inode.size)].to_vec()
+            }
+            S_IFLNK => {
+                let mut target = vec![];
+                self.read_file(inode, |buf| target.extend_from_slice(buf))?;
+                target
+            }
+            _ => vec![],
+        };
+        Ok(FileSummary { mode: inode.mode, digest })
+    }
+
+    /// summarizes every path in the filesystem
+    pub fn files(&mut self) -> Result<BTreeMap<String, FileSummary>> {
+        let mut files = BTreeMap::new();
+        let mut visited = HashSet::new();
+        let mut dirs = vec![(String::new(), ROOT_INODE)];
+        while let Some((path, n)) = dirs.pop() {
+            if !visited.insert(n) {
+                continue;
+            }
+            let inode = self.inode(n)?;
+            for (name, entry) in self.read_dir(&inode).with_context(|| format!("In {}/", path))? {
+                let entry_path = format!("{}/{}", path, name);
+                let entry_inode = self.inode(entry)?;
+                let summary = self
+                    .summarize(&entry_inode)
+                    .with_context(|| format!("Failed to read {}", entry_path))?;
+                if entry_inode.mode & S_IFMT == S_IFDIR {
+                    dirs.push((entry_path.clone(), entry));
+                }
+                files.insert(entry_path, summary);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// lists the paths which were added (`A`), deleted (`D`) or modified (`M`), sorted by path
+pub fn changed_files(
+    old: &BTreeMap<String, FileSummary>,
+    new: &BTreeMap<String, FileSummary>,
+) -> Vec<(char, String)> {
+    let mut changes = vec![];
+    for (path, summary) in new {
+        match old.get(path) {
+            None => changes.push(('A', path.clone())),
+            Some(old_summary) if old_summary != summary => changes.push(('M', path.clone())),
+            _ => {}
+        }
+    }
+    changes
+        .extend(old.keys().filter(|path| !new.contains_key(*path)).map(|path| ('D', path.clone())));
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, io::Cursor};
+
+    use super::{changed_files, Ext4, FileSummary};
+    use crate::extract::hash::Sha256Hasher;
+
+    const BLOCK: usize = 1024;
+
+    fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+        buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// writes an inode whose extent tree is one node of `depth` with a single entry pointing at
+    /// `block`, which is a leaf extent of one block if depth is 0 and an index node otherwise
+    fn put_inode(image: &mut [u8], n: usize, mode: u16, size: u32, depth: u16, block: u32) {
+        let inode = &mut image[3 * BLOCK + (n - 1) * 128..][..128];
+        put_u16(inode, 0x00, mode);
+        put_u32(inode, 0x04, size);
+        put_u32(inode, 0x20, 0x80000);
+        let node = &mut inode[0x28..0x28 + 60];
+        node.fill(0);
+        put_u16(node, 0, 0xf30a);
+        put_u16(node, 2, 1);
+        put_u16(node, 4, 4);
+        put_u16(node, 6, depth);
+        if depth == 0 {
+            put_u16(node, 12 + 4, 1);
+            put_u32(node, 12 + 8, block);
+        } else {
+            put_u32(node, 12 + 4, block);
+        }
+    }
+
+    /// a filesystem with 1 KiB blocks which only contains /hello
+    fn small_image() -> Vec<u8> {
+        let mut image = vec![0; 8 * BLOCK];
+        let sb = &mut image[1024..2048];
+        put_u32(sb, 0x14, 1); // first data block
+        put_u32(sb, 0x18, 0); // 1 KiB blocks
+        put_u32(sb, 0x28, 16); // inodes per group
+        put_u16(sb, 0x38, 0xef53);
+        put_u32(sb, 0x4c, 1); // dynamic revision
+        put_u16(sb, 0x58, 128); // inode size
+                                // the group descriptor, with the inode table at block 3
+        put_u32(&mut image[2 * BLOCK..], 0x08, 3);
+        put_inode(&mut image, 2, 0o40755, BLOCK as u32, 0, 6);
+        put_inode(&mut image, 12, 0o100644, 5, 0, 7);
+
+        let dir = &mut image[6 * BLOCK..7 * BLOCK];
+        put_u32(dir, 0, 2);
+        put_u16(dir, 4, 12);
+        dir[6] = 1;
+        dir[8] = b'.';
+        put_u32(dir, 12, 12);
+        put_u16(dir, 16, (BLOCK - 12) as u16);
+        dir[18] = 5;
+        dir[20..25].copy_from_slice(b"hello");
+        image[7 * BLOCK..7 * BLOCK + 5].copy_from_slice(b"hello");
+        image
+    }
+
+    #[test]
+    fn ext4_files_test() {
+        let mut ext4 = Ext4::open(Cursor::new(small_image())).unwrap().unwrap();
+        let files = ext4.files().unwrap();
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(b"hello");
+        let expected = FileSummary { mode: 0o100644, digest: hasher.finalize() };
+        assert_eq!(files, BTreeMap::from([("/hello".to_string(), expected)]));
+
+        assert!(Ext4::open(Cursor::new(vec![0; 4096])).unwrap().is_none());
+    }
+
+    #[test]
+    fn ext4_corrupt_test() {
+        let mut image = small_image();
+        put_u32(&mut image[1024..], 0x28, 0);
+        assert!(Ext4::open(Cursor::new(image)).is_err());
+
+        // 64 bit group descriptors too short to hold the inode table
+        let mut image = small_image();
+        put_u32(&mut image[1024..], 0x60, 0x80);
+        put_u16(&mut image[1024..], 0xfe, 8);
+        assert!(Ext4::open(Cursor::new(image)).is_err());
+
+        // an index node which points at itself
+        let mut image = small_image();
+        put_inode(&mut image, 12, 0o100644, 5, 1, 5);
+        let node = &mut image[5 * BLOCK..6 * BLOCK];
+        put_u16(node, 0, 0xf30a);
+        put_u16(node, 2, 1);
+        put_u16(node, 6, 1);
+        put_u32(node, 12 + 4, 5);
+        let mut ext4 = Ext4::open(Cursor::new(image)).unwrap().unwrap();
+        let err = ext4.files().unwrap_err();
+        assert!(format!("{:#}", err).contains("extent tree depth"), "{:#}", err);
+    }
+
+    #[test]
+    fn changed_files_test() {
+        let summary = |digest: &[u8]| FileSummary { mode: 0o100644, digest: digest.to_vec() };
+        let old = BTreeMap::from([
+            ("/a".to_string(), summary(b"1")),
+            ("/b".to_string(), summary(b"2")),
+            ("/c".to_string(), summary(b"3")),
+        ]);
+        let new = BTreeMap::from([
+            ("/a".to_string(), summary(b"1")),
+            ("/b".to_string(), summary(b"4")),
+            ("/d".to_string(), summary(b"5")),
+        ]);
+        assert_eq!(
+            changed_files(&old, &new),
+            vec![('M', "/b".to_string()), ('D', "/c".to_string()), ('A', "/d".to_string())]
+        );
+    }
+}
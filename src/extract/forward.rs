@@ -0,0 +1,80 @@
+use cast::u64;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// lets operations write to a stream which can't seek, like stdout or a pipe, as long as they
+/// only move forward: seeking ahead writes zeros up to the new position, and seeking back fails
+pub struct ForwardWriter<W> {
+    inner: W,
+    pos: u64,
+}
+
+impl<W: Write> ForwardWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    /// writes zeros up to `pos`, failing if that's before what was already written
+    pub fn pad_to(&mut self, pos: u64) -> io::Result<()> {
+        if pos < self.pos {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't seek back to {} after writing up to {}", pos, self.pos),
+            ));
+        }
+        let padded = io::copy(&mut io::repeat(0).take(pos - self.pos), &mut self.inner)?;
+        self.pos += padded;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ForwardWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.pos += u64(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for ForwardWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::Current(offset) => self
+                .pos
+                .checked_add_signed(offset)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?,
+            SeekFrom::End(_) => return Err(io::ErrorKind::Unsupported.into()),
+        };
+        self.pad_to(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use super::ForwardWriter;
+
+    #[test]
+    fn forward_writer_test() {
+        let mut writer = ForwardWriter::new(vec![]);
+        writer.write_all(&[1, 2]).unwrap();
+        assert_eq!(writer.seek(SeekFrom::Start(2)).unwrap(), 2);
+        assert_eq!(writer.seek(SeekFrom::Start(4)).unwrap(), 4);
+        writer.write_all(&[3]).unwrap();
+        assert_eq!(writer.seek(SeekFrom::Current(1)).unwrap(), 6);
+        assert!(writer.seek(SeekFrom::Start(5)).is_err());
+        assert!(writer.seek(SeekFrom::End(0)).is_err());
+        writer.pad_to(8).unwrap();
+        assert_eq!(writer.into_inner(), [1, 2, 0, 0, 3, 0, 0, 0]);
+    }
+}
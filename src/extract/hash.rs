@@ -0,0 +1,217 @@
+use cast::u64;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[cfg(feature = "ring")]
+use ring::digest::{Context, SHA256};
+#[cfg(not(feature = "ring"))]
+use sha2::{Digest, Sha256};
+
+/// sha256 hasher which uses ring's hardware accelerated implementation when the `ring` feature
+/// is enabled, and the sha2 crate otherwise
+pub struct Sha256Hasher {
+    #[cfg(feature = "ring")]
+    inner: Context,
+    #[cfg(not(feature = "ring"))]
+    inner: Sha256,
+}
+
+impl Sha256Hasher {
+    pub fn new() -> Self {
+        #[cfg(feature = "ring")]
+        let inner = Context::new(&SHA256);
+        #[cfg(not(feature = "ring"))]
+        let inner = Sha256::new();
+        Self { inner }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        #[cfg(feature = "ring")]
+        return self.inner.finish().as_ref().to_vec();
+        #[cfg(not(feature = "ring"))]
+        return self.inner.finalize().to_vec();
+    }
+}
+
+impl Write for Sha256Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// the digests hashes in the manifest can be made with; update_engine only uses sha256 so far,
+/// so supporting another one only needs a variant here and in `Hasher`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HashKind {
+    Sha256,
+}
+
+impl HashKind {
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashKind::Sha256 => Hasher::Sha256(Sha256Hasher::new()),
+        }
+    }
+}
+
+/// a hasher for one of the hash kinds
+pub enum Hasher {
+    Sha256(Sha256Hasher),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// passes writes through to the inner stream while hashing them, which only works as long as
+/// everything is written in order from the start of the stream
+pub struct HashingWriter<W> {
+    inner: W,
+    /// none when disabled or once something was written out of order
+    hasher: Option<Hasher>,
+    pos: u64,
+    hashed: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    /// hashes with `kind`, or doesn't hash at all without one
+    pub fn new(inner: W, kind: Option<HashKind>) -> Self {
+        Self { inner, hasher: kind.map(HashKind::hasher), pos: 0, hashed: 0 }
+    }
+
+    /// returns the hash of the first `len` bytes if all of them were written in order
+    pub fn finalize(self, len: u64) -> Option<Vec<u8>> {
+        self.hasher.filter(|_| self.hashed == len).map(Hasher::finalize)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if self.pos != self.hashed {
+            self.hasher = None;
+        }
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..written]);
+            self.hashed += u64(written);
+        }
+        self.pos += u64(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for HashingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// hashes everything read through it, so a stream can be checked while it's used instead of
+/// being read once more beforehand
+pub struct HashingReader<R> {
+    inner: R,
+    /// none when disabled
+    hasher: Option<Hasher>,
+}
+
+impl<R: Read> HashingReader<R> {
+    /// hashes with `kind`, or doesn't hash at all without one
+    pub fn new(inner: R, kind: Option<HashKind>) -> Self {
+        Self { inner, hasher: kind.map(HashKind::hasher) }
+    }
+
+    /// reads the rest of the stream, since whatever read it may have stopped before its end, and
+    /// returns the hash of all of it
+    pub fn finalize(mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.hasher.is_some() {
+            io::copy(&mut self, &mut io::sink())?;
+        }
+        Ok(self.hasher.map(Hasher::finalize))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use super::{HashKind, HashingReader, HashingWriter, Sha256Hasher};
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    #[test]
+    fn hashing_writer_test() {
+        let mut writer = HashingWriter::new(Cursor::new(vec![]), Some(HashKind::Sha256));
+        writer.write_all(b"hello ").unwrap();
+        writer.seek(SeekFrom::Start(6)).unwrap();
+        writer.write_all(b"world").unwrap();
+        assert_eq!(writer.finalize(11), Some(sha256(b"hello world")));
+
+        let mut writer = HashingWriter::new(Cursor::new(vec![]), Some(HashKind::Sha256));
+        writer.seek(SeekFrom::Start(6)).unwrap();
+        writer.write_all(b"world").unwrap();
+        writer.rewind().unwrap();
+        writer.write_all(b"hello ").unwrap();
+        assert_eq!(writer.finalize(11), None);
+    }
+
+    #[test]
+    fn hashing_reader_test() {
+        let mut reader = HashingReader::new(Cursor::new(b"hello world"), Some(HashKind::Sha256));
+        let mut hello = [0; 5];
+        reader.read_exact(&mut hello).unwrap();
+        assert_eq!(&hello, b"hello");
+        // the part which wasn't read is still hashed
+        assert_eq!(reader.finalize().unwrap(), Some(sha256(b"hello world")));
+
+        let reader = HashingReader::new(Cursor::new(b"hello world"), None);
+        assert_eq!(reader.finalize().unwrap(), None);
+    }
+}
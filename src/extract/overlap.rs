@@ -0,0 +1,70 @@
+use std::{collections::BTreeMap, ops::Range};
+
+/// the dst blocks written so far, kept as disjoint ranges so operations which write a block a
+/// previous operation already wrote can be detected
+#[derive(Default)]
+pub struct WrittenBlocks {
+    /// maps the start of each range to its end
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl WrittenBlocks {
+    /// some of `blocks` which were already written, if any were
+    pub fn overlap(&self, blocks: &Range<u64>) -> Option<Range<u64>> {
+        if blocks.is_empty() {
+            return None;
+        }
+        // the range starting last before the end is the only one which can tell if any overlap
+        self.ranges
+            .range(..blocks.end)
+            .next_back()
+            .map(|(&start, &end)| start.max(blocks.start)..end.min(blocks.end))
+            .filter(|overlap| !overlap.is_empty())
+    }
+
+    /// marks `blocks` as written, returning some of the blocks which were already written
+    pub fn insert(&mut self, blocks: Range<u64>) -> Option<Range<u64>> {
+        if blocks.is_empty() {
+            return None;
+        }
+        let overlap = self.overlap(&blocks);
+
+        // merge every range touching the new one into it
+        let mut merged = blocks;
+        while let Some((&start, &end)) = self.ranges.range(..=merged.end).next_back() {
+            if end < merged.start {
+                break;
+            }
+            self.ranges.remove(&start);
+            merged = start.min(merged.start)..end.max(merged.end);
+        }
+        self.ranges.insert(merged.start, merged.end);
+        overlap
+    }
+
+    /// the written blocks as sorted ranges, where touching ranges are merged
+    pub fn ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.ranges.iter().map(|(&start, &end)| start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WrittenBlocks;
+
+    #[test]
+    fn written_blocks_test() {
+        let mut written = WrittenBlocks::default();
+        assert_eq!(written.insert(0..4), None);
+        assert_eq!(written.insert(8..10), None);
+        // adjacent ranges don't overlap
+        assert_eq!(written.insert(4..8), None);
+        assert_eq!(written.insert(12..16), None);
+        assert_eq!(written.insert(9..13), Some(12..13));
+        assert_eq!(written.insert(16..20), None);
+        assert_eq!(written.insert(2..3), Some(2..3));
+        assert_eq!(written.overlap(&(18..30)), Some(18..20));
+        assert_eq!(written.overlap(&(20..30)), None);
+        assert_eq!(written.ranges.len(), 1);
+    }
+}
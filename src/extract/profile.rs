@@ -0,0 +1,138 @@
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+use crate::update_metadata::install_operation::Type as OperationType;
+
+fn add_elapsed<R>(elapsed: &Cell<Duration>, f: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let result = f();
+    elapsed.set(elapsed.get() + start.elapsed());
+    result
+}
+
+/// time spent in each phase of applying operations
+/// read and write are measured at the stream level, everything else is measured
+/// around the work itself with the stream time taken out
+#[derive(Default)]
+pub struct PhaseTimes {
+    pub read: Cell<Duration>,
+    pub decompress: Cell<Duration>,
+    pub patch: Cell<Duration>,
+    pub write: Cell<Duration>,
+    pub hash: Cell<Duration>,
+}
+
+impl PhaseTimes {
+    pub fn time<R>(&self, phase: &Cell<Duration>, f: impl FnOnce() -> R) -> R {
+        add_elapsed(phase, f)
+    }
+
+    /// like `time`, but doesn't count time recorded to read or write while f runs
+    pub fn time_excluding_io<R>(&self, phase: &Cell<Duration>, f: impl FnOnce() -> R) -> R {
+        let io_before = self.read.get() + self.write.get();
+        let start = Instant::now();
+        let result = f();
+        let io = self.read.get() + self.write.get() - io_before;
+        phase.set(phase.get() + start.elapsed().saturating_sub(io));
+        result
+    }
+
+    fn add(&self, other: &PhaseTimes) {
+        for (total, time) in self.phases().into_iter().zip(other.phases()) {
+            total.1.set(total.1.get() + time.1.get());
+        }
+    }
+
+    fn phases(&self) -> [(&'static str, &Cell<Duration>); 5] {
+        [
+            ("read", &self.read),
+            ("decompress", &self.decompress),
+            ("patch", &self.patch),
+            ("write", &self.write),
+            ("hash", &self.hash),
+        ]
+    }
+}
+
+impl Display for PhaseTimes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let phases = self
+            .phases()
+            .into_iter()
+            .filter(|(_, time)| !time.get().is_zero())
+            .map(|(name, time)| format!("{} {:.2?}", name, time.get()))
+            .collect::<Vec<_>>();
+        if phases.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", phases.join(", "))
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Profile {
+    ops: BTreeMap<OperationType, PhaseTimes>,
+}
+
+impl Profile {
+    pub fn times(&mut self, op_type: OperationType) -> &PhaseTimes {
+        self.ops.entry(op_type).or_default()
+    }
+
+    /// adds the times of a profile recorded on another thread
+    pub fn merge(&mut self, other: Profile) {
+        for (op_type, times) in other.ops {
+            self.times(op_type).add(&times);
+        }
+    }
+
+    pub fn print(&self) {
+        let total = PhaseTimes::default();
+        status!("profile:");
+        for (op_type, times) in &self.ops {
+            status!("- {:?}: {}", op_type, times);
+            total.add(times);
+        }
+        status!("total: {}", total);
+    }
+}
+
+/// adds the time spent in calls on the inner stream to `elapsed`
+pub struct Timed<'a, T> {
+    inner: T,
+    elapsed: &'a Cell<Duration>,
+}
+
+impl<'a, T> Timed<'a, T> {
+    pub fn new(inner: T, elapsed: &'a Cell<Duration>) -> Self {
+        Self { inner, elapsed }
+    }
+}
+
+impl<'a, T: Read> Read for Timed<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        add_elapsed(self.elapsed, || self.inner.read(buf))
+    }
+}
+
+impl<'a, T: Write> Write for Timed<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        add_elapsed(self.elapsed, || self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        add_elapsed(self.elapsed, || self.inner.flush())
+    }
+}
+
+impl<'a, T: Seek> Seek for Timed<'a, T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        add_elapsed(self.elapsed, || self.inner.seek(pos))
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    diagnostic::{self, Diagnostic},
+    update_metadata::{DeltaArchiveManifest, PartitionUpdate, DEFAULT_BLOCK_SIZE},
+    HasUpdateType,
+};
+
+#[derive(Serialize)]
+struct PayloadSummary {
+    file: String,
+    update_type: String,
+    block_size: u32,
+    minor_version: Option<u32>,
+    security_patch_level: Option<String>,
+    data_offset: u64,
+    num_partitions: usize,
+}
+
+/// what happened to one extracted partition; hashes are base64 like everywhere else
+#[derive(Serialize)]
+pub struct PartReport {
+    name: String,
+    /// where the image was written, or none if it was piped to a command
+    path: Option<String>,
+    expected_size: Option<u64>,
+    produced_size: Option<u64>,
+    expected_hash: Option<String>,
+    /// none when the image wasn't hashed, e.g. with --skip-hash=output
+    produced_hash: Option<String>,
+    seconds: f64,
+}
+
+impl PartReport {
+    pub fn new(
+        part: &PartitionUpdate,
+        path: Option<&Path>,
+        produced_size: Option<u64>,
+        produced_hash: Option<&[u8]>,
+        elapsed: Duration,
+    ) -> Self {
+        let info = part.new_partition_info.as_ref();
+        Self {
+            name: part.partition_name.clone(),
+            path: path.map(|path| path.display().to_string()),
+            expected_size: info.and_then(|info| info.size),
+            produced_size,
+            expected_hash: info
+                .and_then(|info| info.hash.as_deref())
+                .map(|hash| BASE64_STANDARD.encode(hash)),
+            produced_hash: produced_hash.map(|hash| BASE64_STANDARD.encode(hash)),
+            seconds: elapsed.as_secs_f64(),
+        }
+    }
+}
+
+/// everything about an extraction run in one file, to share results or attach to a bug report
+#[derive(Serialize)]
+pub struct Report {
+    tool_version: &'static str,
+    payload: PayloadSummary,
+    pub partitions: Vec<PartReport>,
+    seconds: f64,
+    diagnostics: Vec<Diagnostic>,
+    /// the error the run stopped with, if it didn't succeed
+    error: Option<String>,
+}
+
+impl Report {
+    pub fn new(manifest: &DeltaArchiveManifest, file: &str, data_offset: u64) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION"),
+            payload: PayloadSummary {
+                file: file.to_string(),
+                update_type: format!("{:?}", manifest.get_update_type()),
+                block_size: manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE),
+                minor_version: manifest.minor_version,
+                security_patch_level: manifest.security_patch_level.clone(),
+                data_offset,
+                num_partitions: manifest.partitions.len(),
+            },
+            partitions: vec![],
+            seconds: 0.0,
+            diagnostics: vec![],
+            error: None,
+        }
+    }
+
+    /// fills in how the run ended and writes the report to `path`
+    pub fn finish(mut self, path: &str, elapsed: Duration, result: &Result<()>) -> Result<()> {
+        self.seconds = elapsed.as_secs_f64();
+        self.diagnostics = diagnostic::collected();
+        self.error = result.as_ref().err().map(|err| format!("{:#}", err));
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &self)
+            .with_context(|| format!("Failed to write the report to {}", path))?;
+        // flushed here since dropping the buffer would ignore errors
+        writer.flush().with_context(|| format!("Failed to write the report to {}", path))
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::{ensure, Context, Result};
+use cast::{u64, usize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::Path,
+};
+
+const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const FILE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+
+/// the most blocks a raw chunk holds, so only this much of the image is buffered at a time
+const MAX_RAW_BLOCKS: usize = 1024;
+
+/// a run of blocks which become one chunk
+enum Chunk {
+    Raw(Vec<u8>),
+    Fill(u32, u32),
+    DontCare(u32),
+}
+
+/// writes chunks after the header, which is written last since it counts the chunks
+struct SparseWriter<W> {
+    out: W,
+    block_size: usize,
+    chunks: u32,
+    current: Option<Chunk>,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    fn new(mut out: W, block_size: usize) -> Result<Self> {
+        out.write_all(&[0; FILE_HEADER_SIZE as usize])?;
+        Ok(Self { out, block_size, chunks: 0, current: None })
+    }
+
+    fn write_chunk(&mut self, chunk: Chunk) -> Result<()> {
+        let (chunk_type, blocks, body) = match &chunk {
+            Chunk::Raw(data) => (CHUNK_RAW, data.len() / self.block_size, &data[..]),
+            Chunk::Fill(value, blocks) => (CHUNK_FILL, usize(*blocks), &value.to_le_bytes()[..]),
+            Chunk::DontCare(blocks) => (CHUNK_DONT_CARE, usize(*blocks), &[][..]),
+        };
+        let total_size = u32::try_from(usize::from(CHUNK_HEADER_SIZE) + body.len())?;
+        self.out.write_all(&chunk_type.to_le_bytes())?;
+        self.out.write_all(&0_u16.to_le_bytes())?;
+        self.out.write_all(&u32::try_from(blocks)?.to_le_bytes())?;
+        self.out.write_all(&total_size.to_le_bytes())?;
+        self.out.write_all(body)?;
+        self.chunks += 1;
+        Ok(())
+    }
+
+    /// adds a block to the current chunk if it's of the same kind, or else starts a new chunk
+    fn push(&mut self, block: Option<&[u8]>) -> Result<()> {
+        let fill = block.and_then(fill_value);
+        match (&mut self.current, block, fill) {
+            (Some(Chunk::DontCare(blocks)), None, _) => *blocks += 1,
+            (Some(Chunk::Fill(value, blocks)), Some(_), Some(fill)) if *value == fill => {
+                *blocks += 1
+            }
+            (Some(Chunk::Raw(data)), Some(block), None)
+                if data.len() < MAX_RAW_BLOCKS * self.block_size =>
+            {
+                data.extend_from_slice(block)
+            }
+            _ => {
+                let next = match (block, fill) {
+                    (None, _) => Chunk::DontCare(1),
+                    (Some(_), Some(fill)) => Chunk::Fill(fill, 1),
+                    (Some(block), None) => Chunk::Raw(block.to_vec()),
+                };
+                if let Some(chunk) = self.current.replace(next) {
+                    self.write_chunk(chunk)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, total_blocks: u32) -> Result<W> {
+        if let Some(chunk) = self.current.take() {
+            self.write_chunk(chunk)?;
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&SPARSE_MAGIC.to_le_bytes())?;
+        // version 1.0
+        self.out.write_all(&1_u16.to_le_bytes())?;
+        self.out.write_all(&0_u16.to_le_bytes())?;
+        self.out.write_all(&FILE_HEADER_SIZE.to_le_bytes())?;
+        self.out.write_all(&CHUNK_HEADER_SIZE.to_le_bytes())?;
+        self.out.write_all(&u32::try_from(self.block_size)?.to_le_bytes())?;
+        self.out.write_all(&total_blocks.to_le_bytes())?;
+        self.out.write_all(&self.chunks.to_le_bytes())?;
+        // no checksum
+        self.out.write_all(&0_u32.to_le_bytes())?;
+        Ok(self.out)
+    }
+}
+
+/// the value the block repeats, if it's the same 4 bytes over and over
+fn fill_value(block: &[u8]) -> Option<u32> {
+    let (first, rest) = block.split_first_chunk::<4>()?;
+    rest.chunks(4).all(|word| word == first).then(|| u32::from_le_bytes(*first))
+}
+
+/// writes the `len` byte raw image as a sparse image, with the blocks outside of the sorted
+/// `written` ranges as don't care chunks; a partial last block is padded with zeros
+fn write_sparse(
+    image: &mut (impl Read + Seek),
+    len: u64,
+    block_size: usize,
+    written: &[Range<u64>],
+    out: impl Write + Seek,
+) -> Result<()> {
+    let total_blocks = len.div_ceil(u64(block_size));
+    let total_blocks_u32 = u32::try_from(total_blocks)
+        .with_context(|| format!("Image has too many blocks for a sparse image"))?;
+    let mut writer = SparseWriter::new(out, block_size)?;
+    let mut buffer = vec![0; block_size];
+    let mut ranges = written.iter().peekable();
+    // whether the image is positioned at the current block, which it isn't after skipping some
+    let mut positioned = false;
+    for block in 0..total_blocks {
+        while ranges.next_if(|range| range.end <= block).is_some() {}
+        if ranges.peek().is_none_or(|range| range.start > block) {
+            writer.push(None)?;
+            positioned = false;
+            continue;
+        }
+        if !positioned {
+            image.seek(SeekFrom::Start(block * u64(block_size)))?;
+            positioned = true;
+        }
+        buffer.fill(0);
+        let mut filled = 0;
+        while filled < block_size {
+            match image.read(&mut buffer[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        writer.push(Some(&buffer))?;
+    }
+    writer.finish(total_blocks_u32)?.flush()?;
+    Ok(())
+}
+
+/// replaces the raw image at `path` with a sparse image of it
+pub fn convert_to_sparse(path: &Path, block_size: usize, written: &[Range<u64>]) -> Result<()> {
+    ensure!(
+        block_size > 0 && block_size.is_multiple_of(4),
+        "Sparse images need a block size which is a multiple of 4, not {}",
+        block_size
+    );
+    let mut image = BufReader::new(File::open(path)?);
+    let len = image.get_ref().metadata()?.len();
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut sparse = tempfile::NamedTempFile::new_in(dir)?;
+    write_sparse(&mut image, len, block_size, written, BufWriter::new(sparse.as_file_mut()))?;
+    // temporary files are only accessible to the owner
+    fs::set_permissions(sparse.path(), image.get_ref().metadata()?.permissions())?;
+    sparse.persist(path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, slice};
+
+    use super::{convert_to_sparse, write_sparse};
+
+    /// expands a sparse image, with don't care blocks as zeros
+    fn expand(sparse: &[u8]) -> (Vec<u16>, Vec<u8>) {
+        let le_u16 = |at: usize| u16::from_le_bytes([sparse[at], sparse[at + 1]]);
+        let le_u32 = |at: usize| u32::from_le_bytes(sparse[at..at + 4].try_into().unwrap());
+        assert_eq!(le_u32(0), 0xed26ff3a);
+        let block_size = le_u32(12) as usize;
+        let (total_blocks, total_chunks) = (le_u32(16) as usize, le_u32(20));
+        let (mut types, mut image, mut pos) = (vec![], vec![], 28);
+        for _ in 0..total_chunks {
+            let (chunk_type, blocks, size) = (le_u16(pos), le_u32(pos + 4), le_u32(pos + 8));
+            let body = &sparse[pos + 12..pos + size as usize];
+            let len = blocks as usize * block_size;
+            match chunk_type {
+                0xcac1 => image.extend_from_slice(body),
+                0xcac2 => image.extend(body.iter().cycle().take(len)),
+                0xcac3 => image.resize(image.len() + len, 0),
+                _ => panic!("unexpected chunk type {:x}", chunk_type),
+            }
+            types.push(chunk_type);
+            pos += size as usize;
+        }
+        assert_eq!(pos, sparse.len());
+        assert_eq!(image.len(), total_blocks * block_size);
+        (types, image)
+    }
+
+    #[test]
+    fn write_sparse_test() {
+        let block_size = 16;
+        let mut image = vec![];
+        image.extend([0; 16]); // fill
+        image.extend([7; 16]); // not written
+        image.extend(0..32_u8); // raw
+        image.extend([1, 2, 3, 4].repeat(8)); // fill, then fill with another value
+        image.extend([9; 5]); // a partial raw block
+
+        let mut sparse = Cursor::new(vec![]);
+        let written = [0..1, 2..4, 4..20];
+        write_sparse(
+            &mut Cursor::new(&image),
+            image.len() as u64,
+            block_size,
+            &written,
+            &mut sparse,
+        )
+        .unwrap();
+        let (types, expanded) = expand(sparse.get_ref());
+        assert_eq!(types, [0xcac2, 0xcac3, 0xcac1, 0xcac2, 0xcac1]);
+        let mut expected = image.clone();
+        expected[16..32].fill(0);
+        expected.resize(7 * block_size, 0);
+        assert_eq!(expanded, expected);
+    }
+
+    #[test]
+    fn convert_to_sparse_block_size_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.img");
+        std::fs::write(&path, [1; 12]).unwrap();
+        assert!(convert_to_sparse(&path, 6, slice::from_ref(&(0..2))).is_err());
+        // the image is left as it was
+        assert_eq!(std::fs::read(&path).unwrap(), [1; 12]);
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{anyhow, bail, Context, Result};
+use cast::usize;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{self, Seek},
+    path::{Path, PathBuf},
+};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::extent::ExtentStream;
+
+use super::StreamRead;
+
+/// where the src image of a partition is, either in the src folder or in a zip of images
+pub enum SrcImage {
+    File(PathBuf),
+    ZipEntry { zip: PathBuf, entry: String },
+}
+
+impl SrcImage {
+    /// finds the `{name}.img` image in `src`, which is either a folder or a zip of images
+    ///
+    /// in a zip, the image may be in a subfolder as long as there's only one with its name
+    pub fn find(src: &Path, name: &str) -> Result<Self> {
+        let file_name = format!("{}.img", name);
+        if !src.is_file() {
+            let path = src.join(&file_name);
+            File::open(&path).with_context(|| format!("{}", path.display()))?;
+            return Ok(SrcImage::File(path));
+        }
+
+        let archive = open_zip(src)?;
+        let mut matches = archive.file_names().filter(|entry| {
+            *entry == file_name || entry.rsplit_once('/').is_some_and(|(_, base)| base == file_name)
+        });
+        let entry = matches
+            .next()
+            .ok_or_else(|| anyhow!("{}: no {} in the zip", src.display(), file_name))?
+            .to_string();
+        if let Some(other) = matches.next() {
+            bail!("{}: both {} and {} could be the src image", src.display(), entry, other);
+        }
+        Ok(SrcImage::ZipEntry { zip: src.to_path_buf(), entry })
+    }
+
+    /// opens the image, reading stored zip entries in place and decompressing the others to a
+    /// temporary file since the operations need to seek in the src image
+    pub fn open(&self) -> Result<Box<dyn StreamRead>> {
+        let (zip, entry) = match self {
+            SrcImage::File(path) => return Ok(Box::new(File::open(path)?)),
+            SrcImage::ZipEntry { zip, entry } => (zip, entry),
+        };
+        let mut archive = open_zip(zip)?;
+        let mut file = archive.by_name(entry)?;
+        if file.compression() == CompressionMethod::Stored {
+            let (start, len) = (file.data_start(), file.size());
+            return Ok(Box::new(ExtentStream::new_range(
+                File::open(zip)?,
+                usize(start),
+                usize(len),
+            )?));
+        }
+
+        status!("decompressing {} to a temporary file", self);
+        let mut spool = tempfile::tempfile()?;
+        io::copy(&mut file, &mut spool)
+            .with_context(|| format!("Failed to decompress {}", self))?;
+        spool.rewind()?;
+        Ok(Box::new(spool))
+    }
+}
+
+impl Display for SrcImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SrcImage::File(path) => write!(f, "{}", path.display()),
+            SrcImage::ZipEntry { zip, entry } => write!(f, "{}:{}", zip.display(), entry),
+        }
+    }
+}
+
+fn open_zip(path: &Path) -> Result<ZipArchive<File>> {
+    ZipArchive::new(File::open(path)?)
+        .with_context(|| format!("Failed to read {} as a zip of src images", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use tempfile::NamedTempFile;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::SrcImage;
+
+    #[test]
+    fn src_zip_test() {
+        let mut zip = ZipWriter::new(NamedTempFile::new().unwrap());
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("images/system.img", stored).unwrap();
+        zip.write_all(b"system image").unwrap();
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("vendor.img", deflated).unwrap();
+        zip.write_all(b"vendor image").unwrap();
+        let file = zip.finish().unwrap();
+
+        for name in ["system", "vendor"] {
+            let mut image = SrcImage::find(file.path(), name).unwrap().open().unwrap();
+            let mut contents = String::new();
+            image.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, format!("{} image", name));
+        }
+        assert!(SrcImage::find(file.path(), "boot").is_err());
+    }
+}
@@ -0,0 +1,438 @@
+use anyhow::{bail, ensure, Context, Result};
+use binrw::BinWrite;
+use cast::usize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Cursor, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{extent::ExtentStream, update_metadata::DynamicPartitionGroup};
+
+const GEOMETRY_MAGIC: u32 = 0x616c4467;
+const HEADER_MAGIC: u32 = 0x414c5030;
+const SECTOR_SIZE: u64 = 512;
+/// zeros before the geometry, so a boot sector or partition table can't overwrite it
+const RESERVED_BYTES: u64 = 4096;
+const GEOMETRY_SIZE: u64 = 4096;
+/// the defaults of lpmake and BOARD_SUPER_PARTITION_METADATA_DEVICE
+const METADATA_MAX_SIZE: u32 = 65536;
+const LOGICAL_BLOCK_SIZE: u64 = 4096;
+const ALIGNMENT: u64 = 1 << 20;
+const ATTR_READONLY: u32 = 1;
+const TARGET_TYPE_LINEAR: u32 = 0;
+/// the size of a name in the metadata, which isn't terminated when it fills all of it
+const NAME_SIZE: usize = 36;
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct Geometry {
+    magic: u32,
+    struct_size: u32,
+    checksum: [u8; 32],
+    metadata_max_size: u32,
+    metadata_slot_count: u32,
+    logical_block_size: u32,
+}
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct TableDescriptor {
+    offset: u32,
+    num_entries: u32,
+    entry_size: u32,
+}
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct Header {
+    magic: u32,
+    major_version: u16,
+    minor_version: u16,
+    header_size: u32,
+    header_checksum: [u8; 32],
+    tables_size: u32,
+    tables_checksum: [u8; 32],
+    partitions: TableDescriptor,
+    extents: TableDescriptor,
+    groups: TableDescriptor,
+    block_devices: TableDescriptor,
+}
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct Partition {
+    name: [u8; NAME_SIZE],
+    attributes: u32,
+    first_extent_index: u32,
+    num_extents: u32,
+    group_index: u32,
+}
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct LinearExtent {
+    num_sectors: u64,
+    target_type: u32,
+    target_data: u64,
+    target_source: u32,
+}
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct Group {
+    name: [u8; NAME_SIZE],
+    flags: u32,
+    maximum_size: u64,
+}
+
+#[derive(BinWrite)]
+#[bw(little)]
+struct BlockDevice {
+    first_logical_sector: u64,
+    alignment: u32,
+    alignment_offset: u32,
+    size: u64,
+    partition_name: [u8; NAME_SIZE],
+    flags: u32,
+}
+
+/// an extracted image of a logical partition, to be placed in the super image
+pub struct LogicalImage<'a> {
+    pub name: &'a str,
+    pub path: &'a Path,
+    pub size: u64,
+}
+
+/// where a logical partition goes in the super image
+struct Placement {
+    /// the index of the image
+    image: usize,
+    /// the partition's name with the slot suffix
+    name: String,
+    group_index: u32,
+    start: u64,
+    size: u64,
+}
+
+/// where lpmake would place the partitions and how big the super image has to be; the images
+/// become the `_a` slot, and the groups are added for both slots
+struct Layout {
+    group_names: Vec<(String, u64)>,
+    placements: Vec<Placement>,
+    first_logical_sector: u64,
+    size: u64,
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+fn name_field(name: &str) -> Result<[u8; NAME_SIZE]> {
+    ensure!(name.len() <= NAME_SIZE, "{} is too long for a super image", name);
+    let mut field = [0; NAME_SIZE];
+    field[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(field)
+}
+
+fn to_bytes(value: &impl for<'a> BinWrite<Args<'a> = ()>) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(vec![]);
+    value.write_le(&mut out)?;
+    Ok(out.into_inner())
+}
+
+fn layout(
+    groups: &[DynamicPartitionGroup],
+    images: &[LogicalImage],
+    metadata_slots: u32,
+    super_size: Option<u64>,
+) -> Result<Layout> {
+    let metadata_end = RESERVED_BYTES
+        + 2 * GEOMETRY_SIZE
+        + 2 * u64::from(metadata_slots) * u64::from(METADATA_MAX_SIZE);
+    let first_logical = align_up(metadata_end, ALIGNMENT);
+    // the default group always comes first
+    let mut group_names = vec![("default".to_string(), 0)];
+    let mut placements = vec![];
+    let mut end = first_logical;
+    for group in groups {
+        let group_index = u32::try_from(group_names.len())?;
+        let max_size = group.size.unwrap_or(0);
+        group_names.push((format!("{}_a", group.name), max_size));
+        group_names.push((format!("{}_b", group.name), max_size));
+        let mut group_size = 0;
+        for name in &group.partition_names {
+            let Some(i) = images.iter().position(|image| image.name == name) else {
+                continue;
+            };
+            let image = &images[i];
+            let size = align_up(image.size, LOGICAL_BLOCK_SIZE);
+            // like liblp, an empty partition gets no extent, so it doesn't take up any space
+            let start = if size == 0 { end } else { align_up(end, ALIGNMENT) };
+            placements.push(Placement {
+                image: i,
+                name: format!("{}_a", image.name),
+                group_index,
+                start,
+                size,
+            });
+            group_size += size;
+            end = start + size;
+        }
+        ensure!(
+            max_size == 0 || group_size <= max_size,
+            "The partitions of group {} take {} bytes, more than its maximum size of {}",
+            group.name,
+            group_size,
+            max_size
+        );
+    }
+    for (i, image) in images.iter().enumerate() {
+        if !placements.iter().any(|placement| placement.image == i) {
+            bail!("{} isn't a dynamic partition", image.name);
+        }
+    }
+
+    let size = match super_size {
+        Some(size) => {
+            ensure!(size >= end, "The partitions need a super image of at least {} bytes", end);
+            ensure!(
+                size % LOGICAL_BLOCK_SIZE == 0,
+                "The super size must be a multiple of {} bytes",
+                LOGICAL_BLOCK_SIZE
+            );
+            size
+        }
+        None => align_up(end, ALIGNMENT),
+    };
+    Ok(Layout { group_names, placements, first_logical_sector: first_logical / SECTOR_SIZE, size })
+}
+
+/// the geometry, padded to the space it takes up in the image
+fn geometry_bytes(metadata_slots: u32) -> Result<Vec<u8>> {
+    let mut geometry = Geometry {
+        magic: GEOMETRY_MAGIC,
+        struct_size: 52,
+        checksum: [0; 32],
+        metadata_max_size: METADATA_MAX_SIZE,
+        metadata_slot_count: metadata_slots,
+        logical_block_size: u32::try_from(LOGICAL_BLOCK_SIZE)?,
+    };
+    geometry.checksum = Sha256::digest(to_bytes(&geometry)?).into();
+    let mut bytes = to_bytes(&geometry)?;
+    bytes.resize(usize(GEOMETRY_SIZE), 0);
+    Ok(bytes)
+}
+
+/// the metadata header followed by its tables, which every metadata slot gets a copy of
+fn metadata_bytes(layout: &Layout) -> Result<Vec<u8>> {
+    let mut tables = vec![];
+    let mut table = |entries: Vec<Vec<u8>>, entry_size: u32| -> Result<TableDescriptor> {
+        let descriptor = TableDescriptor {
+            offset: u32::try_from(tables.len())?,
+            num_entries: u32::try_from(entries.len())?,
+            entry_size,
+        };
+        tables.extend(entries.concat());
+        Ok(descriptor)
+    };
+    let mut first_extent_index = 0;
+    let partitions = layout
+        .placements
+        .iter()
+        .map(|placement| {
+            let num_extents = u32::from(placement.size > 0);
+            let partition = to_bytes(&Partition {
+                name: name_field(&placement.name)?,
+                attributes: ATTR_READONLY,
+                first_extent_index,
+                num_extents,
+                group_index: placement.group_index,
+            });
+            first_extent_index += num_extents;
+            partition
+        })
+        .collect::<Result<_>>()?;
+    let partitions = table(partitions, 52)?;
+    let extents = layout
+        .placements
+        .iter()
+        .filter(|placement| placement.size > 0)
+        .map(|placement| {
+            to_bytes(&LinearExtent {
+                num_sectors: placement.size / SECTOR_SIZE,
+                target_type: TARGET_TYPE_LINEAR,
+                target_data: placement.start / SECTOR_SIZE,
+                target_source: 0,
+            })
+        })
+        .collect::<Result<_>>()?;
+    let extents = table(extents, 24)?;
+    let groups = layout
+        .group_names
+        .iter()
+        .map(|(name, maximum_size)| {
+            to_bytes(&Group { name: name_field(name)?, flags: 0, maximum_size: *maximum_size })
+        })
+        .collect::<Result<_>>()?;
+    let groups = table(groups, 48)?;
+    let block_device = to_bytes(&BlockDevice {
+        first_logical_sector: layout.first_logical_sector,
+        alignment: u32::try_from(ALIGNMENT)?,
+        alignment_offset: 0,
+        size: layout.size,
+        partition_name: name_field("super")?,
+        flags: 0,
+    })?;
+    let block_devices = table(vec![block_device], 64)?;
+
+    let mut header = Header {
+        magic: HEADER_MAGIC,
+        major_version: 10,
+        minor_version: 0,
+        header_size: 128,
+        header_checksum: [0; 32],
+        tables_size: u32::try_from(tables.len())?,
+        tables_checksum: Sha256::digest(&tables).into(),
+        partitions,
+        extents,
+        groups,
+        block_devices,
+    };
+    header.header_checksum = Sha256::digest(to_bytes(&header)?).into();
+    let mut bytes = to_bytes(&header)?;
+    bytes.extend(tables);
+    ensure!(
+        bytes.len() <= usize(METADATA_MAX_SIZE),
+        "The super image metadata doesn't fit in {} bytes",
+        METADATA_MAX_SIZE
+    );
+    Ok(bytes)
+}
+
+/// writes the images into one super image at `path` with the metadata lpmake would write for
+/// them, returning its size
+///
+/// virtual A/B devices need a third metadata slot for the snapshot merge
+pub fn write_super_image(
+    path: &Path,
+    groups: &[DynamicPartitionGroup],
+    images: &[LogicalImage],
+    metadata_slots: u32,
+    super_size: Option<u64>,
+) -> Result<u64> {
+    let layout = layout(groups, images, metadata_slots, super_size)?;
+    let mut out =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    out.set_len(layout.size)?;
+
+    let geometry = geometry_bytes(metadata_slots)?;
+    out.seek(SeekFrom::Start(RESERVED_BYTES))?;
+    out.write_all(&geometry)?;
+    out.write_all(&geometry)?;
+    let metadata = metadata_bytes(&layout)?;
+    // the primary slots followed by the backup ones
+    for slot in 0..2 * u64::from(metadata_slots) {
+        out.seek(SeekFrom::Start(
+            RESERVED_BYTES + 2 * GEOMETRY_SIZE + slot * u64::from(METADATA_MAX_SIZE),
+        ))?;
+        out.write_all(&metadata)?;
+    }
+
+    for placement in layout.placements.iter().filter(|placement| placement.size > 0) {
+        let image = &images[placement.image];
+        let region =
+            ExtentStream::new_range(&mut out, usize(placement.start), usize(placement.size))?;
+        let mut region = BufWriter::with_capacity(1 << 20, region);
+        let mut file = BufReader::new(File::open(image.path)?);
+        io::copy(&mut file, &mut region)
+            .with_context(|| format!("Failed to copy {} into the super image", image.name))?;
+        region.flush()?;
+    }
+    Ok(layout.size)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use std::fs;
+
+    use super::{write_super_image, LogicalImage};
+    use crate::update_metadata::DynamicPartitionGroup;
+
+    #[test]
+    fn write_super_image_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (system, vendor) = (dir.path().join("system.img"), dir.path().join("vendor.img"));
+        fs::write(&system, [1; 8192]).unwrap();
+        fs::write(&vendor, [2; 100]).unwrap();
+        let groups = [DynamicPartitionGroup {
+            name: "main".to_string(),
+            size: Some(1 << 30),
+            partition_names: vec!["system".to_string(), "vendor".to_string()],
+        }];
+        let images = [
+            LogicalImage { name: "vendor", path: &vendor, size: 100 },
+            LogicalImage { name: "system", path: &system, size: 8192 },
+        ];
+        let path = dir.path().join("super.img");
+        let size = write_super_image(&path, &groups, &images, 2, None).unwrap();
+        let image = fs::read(&path).unwrap();
+        assert_eq!(size, 3 << 20);
+        assert_eq!(image.len(), 3 << 20);
+
+        let le_u32 = |at: usize| u32::from_le_bytes(image[at..at + 4].try_into().unwrap());
+        assert_eq!(le_u32(4096), 0x616c4467);
+        let mut geometry = image[4096..4096 + 52].to_vec();
+        geometry[8..40].fill(0);
+        assert_eq!(&image[4096 + 8..4096 + 40], &Sha256::digest(&geometry)[..]);
+        // the backup geometry and the backup of the first metadata slot
+        assert_eq!(image[4096..8192], image[8192..12288]);
+        assert_eq!(le_u32(12288), 0x414c5030);
+        assert_eq!(image[12288..12288 + 65536], image[12288 + 2 * 65536..12288 + 3 * 65536]);
+        let tables_size = le_u32(12288 + 44) as usize;
+        let tables = &image[12288 + 128..12288 + 128 + tables_size];
+        assert_eq!(&image[12288 + 48..12288 + 80], &Sha256::digest(tables)[..]);
+        assert_eq!(&tables[..8], b"system_a");
+
+        // partitions follow the order of the group and start on 1 MiB boundaries
+        assert!(image[1 << 20..(1 << 20) + 8192].iter().all(|&byte| byte == 1));
+        assert!(image[2 << 20..(2 << 20) + 100].iter().all(|&byte| byte == 2));
+        assert!(image[(2 << 20) + 100..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn empty_partition_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (empty, system) = (dir.path().join("empty.img"), dir.path().join("system.img"));
+        fs::write(&empty, []).unwrap();
+        fs::write(&system, [1; 4096]).unwrap();
+        let groups = [DynamicPartitionGroup {
+            name: "main".to_string(),
+            size: None,
+            partition_names: vec!["empty".to_string(), "system".to_string()],
+        }];
+        let images = [
+            LogicalImage { name: "empty", path: &empty, size: 0 },
+            LogicalImage { name: "system", path: &system, size: 4096 },
+        ];
+        let path = dir.path().join("super.img");
+        assert_eq!(write_super_image(&path, &groups, &images, 2, None).unwrap(), 2 << 20);
+        let image = fs::read(&path).unwrap();
+
+        let le_u32 = |at: usize| u32::from_le_bytes(image[at..at + 4].try_into().unwrap());
+        let le_u64 = |at: usize| u64::from_le_bytes(image[at..at + 8].try_into().unwrap());
+        let tables = 12288 + 128;
+        let (partitions, extents) = (tables + le_u32(12288 + 80) as usize, le_u32(12288 + 92));
+        let extents = tables + extents as usize;
+        assert_eq!(le_u32(12288 + 84), 2);
+        assert_eq!(le_u32(12288 + 96), 1);
+        // the empty partition has no extents, and system gets the first one
+        assert_eq!(&image[partitions..partitions + 7], b"empty_a");
+        assert_eq!((le_u32(partitions + 40), le_u32(partitions + 44)), (0, 0));
+        assert_eq!((le_u32(partitions + 52 + 40), le_u32(partitions + 52 + 44)), (0, 1));
+        assert_eq!((le_u64(extents), le_u64(extents + 12)), (8, (1 << 20) / 512));
+        assert!(image[1 << 20..(1 << 20) + 4096].iter().all(|&byte| byte == 1));
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+/// makes sure fastboot is installed and exactly one device is connected
+pub fn check_fastboot() -> Result<()> {
+    let output = Command::new("fastboot")
+        .arg("devices")
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run fastboot, is it installed and on the PATH?"))?;
+    ensure!(output.status.success(), "fastboot devices exited with {}", output.status);
+
+    let devices = String::from_utf8_lossy(&output.stdout);
+    let devices = devices.lines().filter(|line| !line.trim().is_empty()).collect::<Vec<_>>();
+    match devices.len() {
+        0 => bail!("No device in fastboot mode is connected"),
+        1 => println!("found fastboot device: {}", devices[0]),
+        _ => bail!("Multiple fastboot devices are connected:\n{}", devices.join("\n")),
+    }
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// flashes each `(partition, image)` pair with fastboot, asking first unless `yes` is set
+///
+/// vbmeta partitions are flashed last, so an interrupted run never leaves
+/// new verification metadata in place for partitions that weren't flashed yet
+pub fn flash(mut images: Vec<(String, PathBuf)>, yes: bool) -> Result<()> {
+    for (name, _) in &images {
+        // the name is passed to fastboot as is, so only allow plain partition names
+        ensure!(
+            !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+            "Refusing to flash partition with unexpected name {:?}",
+            name
+        );
+    }
+    images.sort_by_key(|(name, _)| name.starts_with("vbmeta"));
+
+    for (name, path) in images {
+        if !yes && !confirm(&format!("flash {} with {}?", name, path.display()))? {
+            println!("skipping {}", name);
+            continue;
+        }
+
+        println!("flashing {}", name);
+        let status = Command::new("fastboot")
+            .arg("flash")
+            .arg(&name)
+            .arg(&path)
+            .status()
+            .with_context(|| format!("Failed to run fastboot"))?;
+        ensure!(status.success(), "fastboot flash {} exited with {}", name, status);
+    }
+    Ok(())
+}
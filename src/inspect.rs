@@ -1,15 +1,34 @@
 use base64::prelude::*;
-use std::fmt::{self, Debug, Display, Formatter};
+use serde::Serialize;
+use std::{
+    fmt::{self, Debug, Display, Formatter, Write as _},
+    fs::{self, File},
+    io::{self, Read, Write},
+};
 
 use crate::{
+    output::{Output, OutputFormat, Style},
     parse_parts,
     update_metadata::{
-        install_operation::Type as OperationType, DeltaArchiveManifest, Extent as RawExtent,
-        InstallOperation, DEFAULT_BLOCK_SIZE,
+        install_operation::Type as OperationType, ApexInfo, DeltaArchiveManifest,
+        DynamicPartitionGroup, Extent as RawExtent, InstallOperation, PartitionUpdate,
+        DEFAULT_BLOCK_SIZE,
     },
-    HasUpdateType, InspectArgs,
+    HasUpdateType, InspectArgs, ListArgs, ManifestFormat, UpdateType,
+};
+use android_ota_extractor::{
+    boot_image::is_boot_partition,
+    diagnostic::{self, Code},
+    extent::ExtentStream,
+    has_copy_hash_mismatch,
+    payload::{absolute_data_range, PayloadLayout},
+    progress::format_size,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use cast::{u64, usize};
+use prost::Message;
+
+mod prototext;
 
 fn print_option<T: Display>(val: Option<&T>, unknown: &str) -> String {
     val.map(|v| format!("{}", v)).unwrap_or_else(|| unknown.to_string())
@@ -19,12 +38,14 @@ fn print_option_hash(hash: Option<&[u8]>) -> String {
     hash.map(|hash| BASE64_STANDARD.encode(hash)).unwrap_or_else(|| "none".to_string())
 }
 
+/// the text form of an extent; json output serializes the raw extent instead
 struct PrettyExtent<'a>(&'a RawExtent);
 
 impl<'a> Debug for PrettyExtent<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match (self.0.start_block, self.0.num_blocks) {
-            (Some(start), Some(len)) if start != u64::MAX => {
+            (Some(u64::MAX), Some(len)) => write!(f, "hole ({} blks)", len)?,
+            (Some(start), Some(len)) => {
                 write!(f, "blk{}..blk{} ({} blks)", start, start + len, len)?
             }
             _ => write!(f, "invalid")?,
@@ -33,10 +54,13 @@ impl<'a> Debug for PrettyExtent<'a> {
     }
 }
 
-impl Display for InstallOperation {
+struct PrettyOperation<'a>(&'a InstallOperation);
+
+impl<'a> Display for PrettyOperation<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let op = self.0;
         write!(f, "{{ ")?;
-        if let Ok(op_type) = OperationType::try_from(self.r#type) {
+        if let Ok(op_type) = OperationType::try_from(op.r#type) {
             write!(f, "type: {:?} ({:?}), ", op_type, op_type.get_update_type())?;
         } else {
             write!(f, "type: invalid, ",)?;
@@ -44,53 +68,124 @@ impl Display for InstallOperation {
         write!(
             f,
             "data: {}, ",
-            if let (Some(offset), Some(len)) = (self.data_offset, self.data_length) {
+            if let (Some(offset), Some(len)) = (op.data_offset, op.data_length) {
                 format!("0x{:x}..0x{:x} ({} B)", offset, offset + len, len)
-            } else if self.data_offset.or(self.data_length).is_some() {
+            } else if op.data_offset.or(op.data_length).is_some() {
                 "Invalid".to_string()
             } else {
                 "None".to_string()
             }
         )?;
-        write!(f, "src_sha256: {}, ", print_option_hash(self.src_sha256_hash.as_deref()))?;
-        write!(f, "data_sha256: {}, ", print_option_hash(self.data_sha256_hash.as_deref()))?;
+        write!(f, "src_sha256: {}, ", print_option_hash(op.src_sha256_hash.as_deref()))?;
+        write!(f, "data_sha256: {}, ", print_option_hash(op.data_sha256_hash.as_deref()))?;
         write!(
             f,
             "src_extents: {:?}, ",
-            self.src_extents.iter().map(PrettyExtent).collect::<Vec<_>>()
+            op.src_extents.iter().map(PrettyExtent).collect::<Vec<_>>()
         )?;
         write!(
             f,
             "dst_extents: {:?}",
-            self.dst_extents.iter().map(PrettyExtent).collect::<Vec<_>>()
+            op.dst_extents.iter().map(PrettyExtent).collect::<Vec<_>>()
         )?;
         write!(f, " }}")?;
         Ok(())
     }
 }
 
-pub fn inspect(
+fn paint_update_type(output: &Output, update_type: UpdateType) -> String {
+    let style = match update_type {
+        UpdateType::Full => Style::Green,
+        UpdateType::Incremental => Style::Yellow,
+        UpdateType::Unknown => Style::Red,
+    };
+    output.paint(format!("{:?}", update_type), style)
+}
+
+/// summarizes whether the payload uses Virtual A/B compression, e.g. `yes (algorithm: gz, ...)`
+fn describe_vabc(manifest: &DeltaArchiveManifest) -> String {
+    let Some(metadata) = manifest.dynamic_partition_metadata.as_ref() else {
+        return "no (no dynamic partitions)".to_string();
+    };
+    if metadata.vabc_enabled != Some(true) {
+        let snapshots = if metadata.snapshot_enabled == Some(true) { "yes" } else { "no" };
+        return format!("no (snapshots: {})", snapshots);
+    }
+    let mut details = vec![format!(
+        "algorithm: {}",
+        metadata.vabc_compression_param.as_deref().unwrap_or("unknown")
+    )];
+    if let Some(cow_version) = metadata.cow_version {
+        details.push(format!("cow_version: {}", cow_version));
+    }
+    if let Some(minor_version) = manifest.minor_version {
+        details.push(format!("minor_version: {}", minor_version));
+    }
+    let cow_size =
+        manifest.partitions.iter().filter_map(|partition| partition.estimate_cow_size).sum::<u64>();
+    if cow_size > 0 {
+        details.push(format!("estimated cow size: {}", cow_size));
+    }
+    format!("yes ({})", details.join(", "))
+}
+
+/// the size of the extracted image, from the partition info or else from the last dst block
+fn partition_size(partition: &PartitionUpdate, block_size: u32) -> u64 {
+    partition.new_partition_info.as_ref().and_then(|info| info.size).unwrap_or_else(|| {
+        let end = partition
+            .operations
+            .iter()
+            .flat_map(|op| &op.dst_extents)
+            .filter(|extent| extent.start_block != Some(u64::MAX))
+            .map(|extent| {
+                extent.start_block.unwrap_or(0).saturating_add(extent.num_blocks.unwrap_or(0))
+            })
+            .max()
+            .unwrap_or(0);
+        end.saturating_mul(u64(block_size))
+    })
+}
+
+/// how much of a dynamic partition group the images in the payload take up; partitions of the
+/// group which aren't in the payload aren't counted
+fn group_used_size(
     manifest: &DeltaArchiveManifest,
-    args: &InspectArgs,
+    group: &DynamicPartitionGroup,
+    block_size: u32,
+) -> u64 {
+    manifest
+        .partitions
+        .iter()
+        .filter(|partition| group.partition_names.contains(&partition.partition_name))
+        .map(|partition| partition_size(partition, block_size))
+        .sum()
+}
+
+fn print_text(
+    manifest: &DeltaArchiveManifest,
+    list_ops: Option<Vec<&str>>,
     data_offset: u64,
-) -> Result<()> {
-    let list_ops = parse_parts(&args.dump_ops);
-    println!("update_type: {:?}", manifest.get_update_type());
+    output: &Output,
+) {
+    println!("update_type: {}", paint_update_type(output, manifest.get_update_type()));
     println!("block_size: {0} (0x{0:x})", manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE));
     println!("minor_version: {}", print_option(manifest.minor_version.as_ref(), "unknown"));
     println!(
         "security_patch_level: {}",
         print_option(manifest.security_patch_level.as_ref(), "unknown")
     );
+    println!("virtual_ab_compression: {}", describe_vabc(manifest));
     println!("data_offset: 0x{:x}", data_offset);
     println!();
     println!("==========");
     println!();
 
+    let block_size = manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let mut total_size = 0;
     for partition in &manifest.partitions {
         let name = &partition.partition_name;
-        println!("name: {}", name);
-        println!("update_type: {:?}", partition.get_update_type());
+        println!("name: {}", output.paint(name, Style::Bold));
+        println!("update_type: {}", paint_update_type(output, partition.get_update_type()));
         println!(
             "postinstall: {}",
             if partition.run_postinstall == Some(true) {
@@ -100,6 +195,22 @@ pub fn inspect(
             }
         );
         println!("num_operations: {}", partition.operations.len());
+        let size = partition_size(partition, block_size);
+        total_size += size;
+        println!("size: {} ({} B)", format_size(size), size);
+        if has_copy_hash_mismatch(partition) {
+            diagnostic::warn(
+                Code::CopyHashMismatch,
+                format!("{} only copies blocks in place but the new hash differs", name),
+            );
+        }
+        if is_boot_partition(name) {
+            let replace_only = partition.get_update_type() == UpdateType::Full;
+            println!(
+                "boot_image: yes ({})",
+                if replace_only { "replace only" } else { "needs the source image" }
+            );
+        }
 
         let mut print_ops = false;
         if let Some(list_ops) = &list_ops {
@@ -112,9 +223,516 @@ pub fn inspect(
 
         println!("operations: ");
         for op in &partition.operations {
-            println!("- {}", op);
+            println!("- {}", PrettyOperation(op));
+        }
+        println!();
+    }
+    println!("==========");
+    println!();
+
+    // the groups the dynamic partitions in super are resized within
+    let groups = manifest.dynamic_partition_metadata.as_ref().map_or(&[][..], |meta| &meta.groups);
+    for group in groups {
+        println!("group: {}", output.paint(&group.name, Style::Bold));
+        match group.size {
+            Some(size) => println!("max_size: {} ({} B)", format_size(size), size),
+            None => println!("max_size: unknown"),
         }
+        let used_size = group_used_size(manifest, group, block_size);
+        println!("used_size: {} ({} B)", format_size(used_size), used_size);
+        println!("partitions: {}", group.partition_names.join(", "));
+        println!();
+    }
+    if !groups.is_empty() {
+        println!("==========");
+        println!();
+    }
+
+    // the mainline modules the update installs
+    for apex in &manifest.apex_info {
+        println!(
+            "apex: {}",
+            output.paint(print_option(apex.package_name.as_ref(), "unknown"), Style::Bold)
+        );
+        println!("version: {}", print_option(apex.version.as_ref(), "unknown"));
+        match (apex.is_compressed, apex.decompressed_size) {
+            (Some(true), Some(size)) => println!(
+                "compressed: yes (decompressed_size: {} ({} B))",
+                format_size(u64(size).unwrap_or(0)),
+                size
+            ),
+            (Some(true), None) => println!("compressed: yes"),
+            (Some(false), _) => println!("compressed: no"),
+            (None, _) => println!("compressed: unknown"),
+        }
+        println!();
+    }
+    if !manifest.apex_info.is_empty() {
+        println!("==========");
+        println!();
+    }
+    println!("total_size: {} ({} B)", format_size(total_size), total_size);
+}
+
+#[derive(Serialize)]
+struct JsonOperation<'a> {
+    #[serde(rename = "type")]
+    op_type: String,
+    data_offset: Option<u64>,
+    data_length: Option<u64>,
+    src_extents: &'a [RawExtent],
+    dst_extents: &'a [RawExtent],
+}
+
+#[derive(Serialize)]
+struct JsonPartition<'a> {
+    name: &'a str,
+    update_type: String,
+    num_operations: usize,
+    /// the postinstall program, if the partition runs one
+    postinstall: Option<&'a str>,
+    new_size: Option<u64>,
+    /// the size of the extracted image, falling back to the dst extents without new_size
+    size: u64,
+    /// base64, like in the text output
+    new_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operations: Option<Vec<JsonOperation<'a>>>,
+}
+
+#[derive(Serialize)]
+struct JsonGroup<'a> {
+    name: &'a str,
+    max_size: Option<u64>,
+    /// the sizes of the group's partitions in the payload
+    used_size: u64,
+    partitions: &'a [String],
+}
+
+#[derive(Serialize)]
+struct JsonSummary<'a> {
+    update_type: String,
+    block_size: u32,
+    minor_version: Option<u32>,
+    security_patch_level: Option<&'a str>,
+    data_offset: u64,
+    total_size: u64,
+    partitions: Vec<JsonPartition<'a>>,
+    /// none without dynamic partition metadata
+    groups: Option<Vec<JsonGroup<'a>>>,
+    apex: &'a [ApexInfo],
+}
+
+fn print_json(
+    manifest: &DeltaArchiveManifest,
+    list_ops: Option<Vec<&str>>,
+    data_offset: u64,
+) -> Result<()> {
+    println!("{}", json_summary(manifest, list_ops, data_offset)?);
+    Ok(())
+}
+
+/// the manifest summary printed by `--format json`
+fn json_summary(
+    manifest: &DeltaArchiveManifest,
+    list_ops: Option<Vec<&str>>,
+    data_offset: u64,
+) -> Result<String> {
+    let block_size = manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    let partitions: Vec<JsonPartition> = manifest
+        .partitions
+        .iter()
+        .map(|partition| {
+            let name = partition.partition_name.as_str();
+            let print_ops = list_ops
+                .as_ref()
+                .is_some_and(|list_ops| list_ops.is_empty() || list_ops.contains(&name));
+            let operations = print_ops.then(|| {
+                partition
+                    .operations
+                    .iter()
+                    .map(|op| JsonOperation {
+                        op_type: op_type_name(op),
+                        data_offset: op.data_offset,
+                        data_length: op.data_length,
+                        src_extents: &op.src_extents,
+                        dst_extents: &op.dst_extents,
+                    })
+                    .collect()
+            });
+            let info = partition.new_partition_info.as_ref();
+            JsonPartition {
+                name,
+                update_type: format!("{:?}", partition.get_update_type()),
+                num_operations: partition.operations.len(),
+                postinstall: (partition.run_postinstall == Some(true))
+                    .then(|| partition.postinstall_path.as_deref().unwrap_or("postinst")),
+                new_size: info.and_then(|info| info.size),
+                size: partition_size(partition, block_size),
+                new_hash: info
+                    .and_then(|info| info.hash.as_deref())
+                    .map(|hash| BASE64_STANDARD.encode(hash)),
+                operations,
+            }
+        })
+        .collect();
+    let groups = manifest.dynamic_partition_metadata.as_ref().map(|meta| {
+        meta.groups
+            .iter()
+            .map(|group| JsonGroup {
+                name: &group.name,
+                max_size: group.size,
+                used_size: group_used_size(manifest, group, block_size),
+                partitions: &group.partition_names,
+            })
+            .collect()
+    });
+
+    let summary = JsonSummary {
+        update_type: format!("{:?}", manifest.get_update_type()),
+        block_size,
+        minor_version: manifest.minor_version,
+        security_patch_level: manifest.security_patch_level.as_deref(),
+        data_offset,
+        total_size: partitions.iter().map(|partition| partition.size).sum(),
+        partitions,
+        groups,
+        apex: &manifest.apex_info,
+    };
+    Ok(serde_json::to_string_pretty(&summary)?)
+}
+
+/// prints `data` like `xxd`, with offsets relative to the start of `data`
+fn print_hexdump(data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex = line.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>();
+        let ascii = line
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect::<String>();
+        println!("{:08x}: {:<47}  {}", i * 16, hex.join(" "), ascii);
+    }
+}
+
+fn find_partition<'a>(
+    manifest: &'a DeltaArchiveManifest,
+    name: &str,
+) -> Result<&'a PartitionUpdate> {
+    manifest
+        .partitions
+        .iter()
+        .find(|partition| partition.partition_name == name)
+        .ok_or_else(|| anyhow!("Partition {} isn't in the payload", name))
+}
+
+fn op_type_name(op: &InstallOperation) -> String {
+    OperationType::try_from(op.r#type)
+        .map_or_else(|_| format!("invalid type {}", op.r#type), |op_type| format!("{:?}", op_type))
+}
+
+/// prints the raw data of the selected operations of a partition, up to `args.hexdump_limit` bytes each
+fn print_op_data(
+    manifest: &DeltaArchiveManifest,
+    args: &InspectArgs,
+    name: &str,
+    data_offset: u64,
+) -> Result<()> {
+    let partition = find_partition(manifest, name)?;
+    let ops = match &args.ops {
+        Some(ops) => ops
+            .split(",")
+            .map(|op| op.trim().parse::<usize>().with_context(|| format!("Invalid op {:?}", op)))
+            .collect::<Result<Vec<_>>>()?,
+        None => (0..partition.operations.len()).collect(),
+    };
+
+    let layout = PayloadLayout { data_offset };
+    let mut payload = File::open(&args.file)?;
+    for i in ops {
+        let op = partition.operations.get(i).ok_or_else(|| {
+            anyhow!("{} only has {} operations", name, partition.operations.len())
+        })?;
+        let op_type = op_type_name(op);
+        let Some(range) = absolute_data_range(&layout, op) else {
+            println!("{} op #{} ({}): no data", name, i, op_type);
+            println!();
+            continue;
+        };
+        let len = range.end - range.start;
+        let shown = len.min(u64(args.hexdump_limit));
+        println!(
+            "{} op #{} ({}): {} B at 0x{:x}{}",
+            name,
+            i,
+            op_type,
+            len,
+            range.start,
+            if shown < len { format!(", showing the first {} B", shown) } else { String::new() }
+        );
+        let mut data = vec![];
+        ExtentStream::new_range(&mut payload, usize(range.start), usize(shown))?
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to read the data of op #{}", i))?;
+        print_hexdump(&data);
         println!();
     }
     Ok(())
 }
+
+/// a table of which operation and payload bytes produce each range of dst blocks of a partition
+fn block_map(partition: &PartitionUpdate, data_offset: u64) -> String {
+    let layout = PayloadLayout { data_offset };
+    let mut ranges = partition
+        .operations
+        .iter()
+        .enumerate()
+        .flat_map(|(i, op)| op.dst_extents.iter().map(move |extent| (extent, i, op)))
+        .filter_map(|(extent, i, op)| Some((extent.start_block?, extent.num_blocks?, i, op)))
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|&(start, ..)| start);
+
+    let mut map = format!("{:<24} {:<8} {:<16} payload bytes\n", "dst blocks", "op", "type");
+    for (start, num, i, op) in ranges {
+        let blocks = match start {
+            u64::MAX => "hole".to_string(),
+            _ => format!("{}..{}", start, start.saturating_add(num)),
+        };
+        let data = match absolute_data_range(&layout, op) {
+            Some(range) => format!("0x{:x}..0x{:x}", range.start, range.end),
+            None => "none".to_string(),
+        };
+        let i = format!("#{}", i);
+        let _ = writeln!(map, "{:<24} {:<8} {:<16} {}", blocks, i, op_type_name(op), data);
+    }
+    map
+}
+
+/// prints which operation and payload bytes produce each range of dst blocks of a partition
+fn print_block_map(manifest: &DeltaArchiveManifest, name: &str, data_offset: u64) -> Result<()> {
+    print!("{}", block_map(find_partition(manifest, name)?, data_offset));
+    Ok(())
+}
+
+/// writes the whole manifest to `out`, or stdout if there's none
+fn dump_manifest(
+    manifest: &DeltaArchiveManifest,
+    format: ManifestFormat,
+    out: Option<&str>,
+) -> Result<()> {
+    let data = match format {
+        ManifestFormat::Binary => manifest.encode_to_vec(),
+        ManifestFormat::Text => prototext::to_prototext(manifest)
+            .map_err(|_| anyhow!("Failed to write the manifest as prototext"))?
+            .into_bytes(),
+    };
+    match out {
+        Some(path) => fs::write(path, data).with_context(|| format!("Failed to write {}", path)),
+        None => io::stdout().write_all(&data).with_context(|| format!("Failed to write to stdout")),
+    }
+}
+
+pub fn inspect(
+    manifest: &DeltaArchiveManifest,
+    args: &InspectArgs,
+    data_offset: u64,
+    output: &Output,
+) -> Result<()> {
+    if let Some(format) = args.raw_manifest {
+        return dump_manifest(manifest, format, args.out.as_deref());
+    }
+    if let Some(name) = &args.hexdump {
+        return print_op_data(manifest, args, name, data_offset);
+    }
+    if let Some(name) = &args.block_map {
+        return print_block_map(manifest, name, data_offset);
+    }
+    let list_ops = parse_parts(&args.dump_ops);
+    match output.format {
+        OutputFormat::Text => print_text(manifest, list_ops, data_offset, output),
+        OutputFormat::Json => print_json(manifest, list_ops, data_offset)?,
+    }
+    Ok(())
+}
+
+/// prints one partition name per line, so the output can be fed back into --parts
+pub fn list(manifest: &DeltaArchiveManifest, args: &ListArgs) {
+    for part in &manifest.partitions {
+        if args.with_type {
+            println!("{}\t{:?}", part.partition_name, part.get_update_type());
+        } else {
+            println!("{}", part.partition_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{block_map, describe_vabc, group_used_size, json_summary, partition_size};
+    use crate::update_metadata::{
+        install_operation::Type as OperationType, DeltaArchiveManifest, DynamicPartitionGroup,
+        DynamicPartitionMetadata, Extent, InstallOperation, PartitionInfo, PartitionUpdate,
+    };
+
+    #[test]
+    fn partition_size_test() {
+        let extent = |start, len| Extent { start_block: Some(start), num_blocks: Some(len) };
+        let mut partition = PartitionUpdate {
+            operations: vec![
+                InstallOperation { dst_extents: vec![extent(4, 2)], ..Default::default() },
+                InstallOperation {
+                    dst_extents: vec![extent(0, 3), extent(u64::MAX, 10)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        // holes don't count towards the size
+        assert_eq!(partition_size(&partition, 4096), 6 * 4096);
+        // a malformed extent saturates instead of overflowing
+        partition.operations[0].dst_extents.push(extent(u64::MAX - 1, 2));
+        assert_eq!(partition_size(&partition, 4096), u64::MAX);
+        partition.operations[0].dst_extents.pop();
+        partition.new_partition_info = Some(PartitionInfo { size: Some(100), hash: None });
+        assert_eq!(partition_size(&partition, 4096), 100);
+    }
+
+    #[test]
+    fn describe_vabc_test() {
+        let mut manifest = DeltaArchiveManifest::default();
+        assert_eq!(describe_vabc(&manifest), "no (no dynamic partitions)");
+        manifest.dynamic_partition_metadata =
+            Some(DynamicPartitionMetadata { snapshot_enabled: Some(true), ..Default::default() });
+        assert_eq!(describe_vabc(&manifest), "no (snapshots: yes)");
+
+        let metadata = manifest.dynamic_partition_metadata.as_mut().unwrap();
+        metadata.vabc_enabled = Some(true);
+        assert_eq!(describe_vabc(&manifest), "yes (algorithm: unknown)");
+
+        let metadata = manifest.dynamic_partition_metadata.as_mut().unwrap();
+        metadata.vabc_compression_param = Some("gz".to_string());
+        metadata.cow_version = Some(2);
+        manifest.minor_version = Some(8);
+        manifest.partitions = [Some(100), None, Some(20)]
+            .map(|estimate_cow_size| PartitionUpdate { estimate_cow_size, ..Default::default() })
+            .to_vec();
+        assert_eq!(
+            describe_vabc(&manifest),
+            "yes (algorithm: gz, cow_version: 2, minor_version: 8, estimated cow size: 120)"
+        );
+    }
+
+    #[test]
+    fn group_used_size_test() {
+        let partition = |name: &str, size| PartitionUpdate {
+            partition_name: name.to_string(),
+            new_partition_info: Some(PartitionInfo { size: Some(size), hash: None }),
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![partition("system", 100), partition("boot", 10), partition("odm", 1)],
+            ..Default::default()
+        };
+        let group = DynamicPartitionGroup {
+            name: "main".to_string(),
+            size: Some(1000),
+            partition_names: ["system", "odm", "vendor"].map(String::from).to_vec(),
+        };
+        // vendor isn't in the payload and boot isn't in the group
+        assert_eq!(group_used_size(&manifest, &group, 4096), 101);
+    }
+
+    #[test]
+    fn json_summary_test() {
+        let extent = |start, len| Extent { start_block: Some(start), num_blocks: Some(len) };
+        let partition = |name: &str| PartitionUpdate {
+            partition_name: name.to_string(),
+            run_postinstall: Some(name == "system"),
+            new_partition_info: Some(PartitionInfo { size: Some(8192), hash: Some(vec![0xff; 3]) }),
+            operations: vec![InstallOperation {
+                r#type: OperationType::Replace.into(),
+                data_offset: Some(0),
+                data_length: Some(10),
+                dst_extents: vec![extent(0, 2)],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            minor_version: Some(0),
+            security_patch_level: Some("2024-01-05".to_string()),
+            partitions: vec![partition("boot"), partition("system")],
+            ..Default::default()
+        };
+
+        let summary: serde_json::Value =
+            serde_json::from_str(&json_summary(&manifest, Some(vec!["system"]), 24).unwrap())
+                .unwrap();
+        assert_eq!(summary["update_type"], "Full");
+        assert_eq!(summary["block_size"], 4096);
+        assert_eq!(summary["minor_version"], 0);
+        assert_eq!(summary["security_patch_level"], "2024-01-05");
+        assert_eq!(summary["data_offset"], 24);
+        assert_eq!(summary["total_size"], 2 * 8192);
+        assert_eq!(summary["groups"], serde_json::Value::Null);
+        // only the partitions given to --dump-ops list their operations
+        assert_eq!(
+            summary["partitions"][0],
+            json!({
+                "name": "boot",
+                "update_type": "Full",
+                "num_operations": 1,
+                "postinstall": null,
+                "new_size": 8192,
+                "size": 8192,
+                "new_hash": "////",
+            })
+        );
+        assert_eq!(summary["partitions"][1]["postinstall"], "postinst");
+        assert_eq!(
+            summary["partitions"][1]["operations"],
+            json!([{
+                "type": "Replace",
+                "data_offset": 0,
+                "data_length": 10,
+                "src_extents": [],
+                "dst_extents": [{ "start_block": 0, "num_blocks": 2 }],
+            }])
+        );
+    }
+
+    #[test]
+    fn block_map_test() {
+        let extent = |start, len| Extent { start_block: Some(start), num_blocks: Some(len) };
+        let partition = PartitionUpdate {
+            operations: vec![
+                InstallOperation {
+                    r#type: OperationType::Replace.into(),
+                    data_offset: Some(16),
+                    data_length: Some(32),
+                    dst_extents: vec![extent(4, 2), extent(u64::MAX, 3)],
+                    ..Default::default()
+                },
+                InstallOperation {
+                    r#type: OperationType::Zero.into(),
+                    dst_extents: vec![extent(0, 4)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let map = block_map(&partition, 100);
+        let rows =
+            map.lines().map(|line| line.split_whitespace().collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!(
+            rows[1..],
+            [
+                vec!["0..4", "#1", "Zero", "none"],
+                vec!["4..6", "#0", "Replace", "0x74..0x94"],
+                vec!["hole", "#0", "Replace", "0x74..0x94"],
+            ]
+        );
+    }
+}
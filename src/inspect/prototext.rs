@@ -0,0 +1,353 @@
+use serde::{
+    ser::{Impossible, SerializeSeq, SerializeStruct},
+    Serialize, Serializer,
+};
+use std::fmt::{self, Write};
+
+/// writes a message in the protobuf text format, with fields in the order they're declared
+///
+/// enum fields are written as numbers unless they're serialized as unit variants, which protobuf's
+/// text parser accepts as well
+pub fn to_prototext<T: Serialize>(message: &T) -> Result<String, fmt::Error> {
+    let mut out = String::new();
+    // the top level message is the only field without a name, and isn't wrapped in braces
+    message.serialize(FieldSerializer {
+        out: &mut out,
+        indent: 0,
+        key: "",
+        present: true,
+        bytes: None,
+    })?;
+    Ok(out)
+}
+
+/// escapes like protobuf's text printer, as octal for everything which isn't printable ascii
+fn escape(out: &mut String, bytes: &[u8]) {
+    for &byte in bytes {
+        match byte {
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            b'"' => out.push_str("\\\""),
+            b'\'' => out.push_str("\\'"),
+            b'\\' => out.push_str("\\\\"),
+            0x20..=0x7e => out.push(char::from(byte)),
+            _ => {
+                let _ = write!(out, "\\{:03o}", byte);
+            }
+        }
+    }
+}
+
+/// serializes the fields of a message, one per line
+struct MessageSerializer<'a> {
+    out: &'a mut String,
+    indent: usize,
+    /// whether the message is a field of another one, so it's closed with a brace
+    nested: bool,
+}
+
+impl SerializeStruct for MessageSerializer<'_> {
+    type Ok = ();
+    type Error = fmt::Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), fmt::Error> {
+        value.serialize(FieldSerializer {
+            out: self.out,
+            indent: self.indent,
+            key,
+            present: false,
+            bytes: None,
+        })
+    }
+
+    fn end(self) -> Result<(), fmt::Error> {
+        if self.nested {
+            writeln!(self.out, "{:indent$}}}", "", indent = (self.indent - 1) * 2)?;
+        }
+        Ok(())
+    }
+}
+
+/// serializes the value of one field, which may be unset, repeated or a nested message
+struct FieldSerializer<'a> {
+    out: &'a mut String,
+    indent: usize,
+    key: &'static str,
+    /// whether an optional field is set, so a set but empty bytes field is still written
+    present: bool,
+    /// where the bytes go when serializing the elements of a bytes field
+    bytes: Option<&'a mut Vec<u8>>,
+}
+
+impl FieldSerializer<'_> {
+    fn scalar(self, value: impl fmt::Display) -> Result<(), fmt::Error> {
+        writeln!(self.out, "{:indent$}{}: {}", "", self.key, value, indent = self.indent * 2)
+    }
+
+    fn string(self, bytes: &[u8]) -> Result<(), fmt::Error> {
+        write!(self.out, "{:indent$}{}: \"", "", self.key, indent = self.indent * 2)?;
+        escape(self.out, bytes);
+        self.out.push_str("\"\n");
+        Ok(())
+    }
+}
+
+impl<'a> Serializer for FieldSerializer<'a> {
+    type Ok = ();
+    type Error = fmt::Error;
+    type SerializeSeq = SeqSerializer<'a>;
+    type SerializeTuple = Impossible<(), fmt::Error>;
+    type SerializeTupleStruct = Impossible<(), fmt::Error>;
+    type SerializeTupleVariant = Impossible<(), fmt::Error>;
+    type SerializeMap = Impossible<(), fmt::Error>;
+    type SerializeStruct = MessageSerializer<'a>;
+    type SerializeStructVariant = Impossible<(), fmt::Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    /// protobuf has no 8 bit integers, so these are always the elements of a bytes field
+    fn serialize_u8(self, v: u8) -> Result<(), fmt::Error> {
+        self.bytes.ok_or(fmt::Error)?.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), fmt::Error> {
+        self.scalar(v)
+    }
+
+    fn serialize_char(self, _v: char) -> Result<(), fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), fmt::Error> {
+        self.string(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), fmt::Error> {
+        self.string(v)
+    }
+
+    fn serialize_none(self) -> Result<(), fmt::Error> {
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), fmt::Error> {
+        value.serialize(FieldSerializer { present: true, ..self })
+    }
+
+    fn serialize_unit(self) -> Result<(), fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), fmt::Error> {
+        self.scalar(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), fmt::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SeqSerializer<'a>, fmt::Error> {
+        Ok(SeqSerializer {
+            out: self.out,
+            indent: self.indent,
+            key: self.key,
+            present: self.present,
+            bytes: vec![],
+        })
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, fmt::Error> {
+        Err(fmt::Error)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<MessageSerializer<'a>, fmt::Error> {
+        if self.key.is_empty() {
+            return Ok(MessageSerializer { out: self.out, indent: self.indent, nested: false });
+        }
+        writeln!(self.out, "{:indent$}{} {{", "", self.key, indent = self.indent * 2)?;
+        Ok(MessageSerializer { out: self.out, indent: self.indent + 1, nested: true })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, fmt::Error> {
+        Err(fmt::Error)
+    }
+}
+
+/// a repeated field, written as the field once per element, or a bytes field
+struct SeqSerializer<'a> {
+    out: &'a mut String,
+    indent: usize,
+    key: &'static str,
+    present: bool,
+    bytes: Vec<u8>,
+}
+
+impl SerializeSeq for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = fmt::Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), fmt::Error> {
+        value.serialize(FieldSerializer {
+            out: self.out,
+            indent: self.indent,
+            key: self.key,
+            present: true,
+            bytes: Some(&mut self.bytes),
+        })
+    }
+
+    fn end(self) -> Result<(), fmt::Error> {
+        if self.present || !self.bytes.is_empty() {
+            let field = FieldSerializer {
+                out: self.out,
+                indent: self.indent,
+                key: self.key,
+                present: true,
+                bytes: None,
+            };
+            field.string(&self.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_prototext;
+    use crate::update_metadata::{
+        install_operation::Type as OperationType, DeltaArchiveManifest, Extent, InstallOperation,
+        PartitionInfo, PartitionUpdate,
+    };
+
+    #[test]
+    fn to_prototext_test() {
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![PartitionUpdate {
+                partition_name: "boot".to_string(),
+                new_partition_info: Some(PartitionInfo { size: Some(8), hash: Some(vec![]) }),
+                operations: vec![InstallOperation {
+                    r#type: OperationType::Zero.into(),
+                    dst_extents: vec![Extent { start_block: Some(1), num_blocks: Some(2) }],
+                    data_sha256_hash: Some(b"a\"\0\xff".to_vec()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let expected = r#"block_size: 4096
+partitions {
+  partition_name: "boot"
+  new_partition_info {
+    size: 8
+    hash: ""
+  }
+  operations {
+    type: ZERO
+    dst_extents {
+      start_block: 1
+      num_blocks: 2
+    }
+    data_sha256_hash: "a\"\000\377"
+  }
+}
+"#;
+        assert_eq!(to_prototext(&manifest).unwrap(), expected);
+    }
+}
@@ -0,0 +1,176 @@
+//! Reusable pieces of android-ota-extractor for working with payload.bin files from Android OTAs
+//!
+//! ```no_run
+//! use android_ota_extractor::{ExtentStream, Payload};
+//! use std::{fs::File, io::Read};
+//!
+//! let mut file = File::open("payload.bin")?;
+//! let payload = Payload::open(&mut file)?;
+//! for partition in &payload.manifest.partitions {
+//!     println!("{}: {} operations", partition.partition_name, partition.operations.len());
+//! }
+//! // the data of an operation is read from the payload file through its absolute range
+//! let op = &payload.manifest.partitions[0].operations[0];
+//! let range = android_ota_extractor::payload::absolute_data_range(&payload.layout, op).unwrap();
+//! let mut data = vec![];
+//! ExtentStream::new_range(file, range.start as usize, (range.end - range.start) as usize)?
+//!     .read_to_end(&mut data)?;
+//! # anyhow::Ok(())
+//! ```
+//!
+//! Whole images are extracted with [`extract`], which the command line tool is a wrapper around:
+//!
+//! ```no_run
+//! use android_ota_extractor::{extract, progress::StderrProgress, ExtractOptions, Payload};
+//! use std::fs::File;
+//!
+//! let payload = Payload::open(&mut File::open("payload.bin")?)?;
+//! let options = ExtractOptions {
+//!     manifest: payload.manifest,
+//!     data_file: "payload.bin".to_string(),
+//!     data_offset: payload.layout.data_offset,
+//!     dst: Some("out".to_string()),
+//!     parts: Some(vec!["boot".to_string()]),
+//!     ..Default::default()
+//! };
+//! extract(&options, &StderrProgress)?;
+//! # anyhow::Ok(())
+//! ```
+
+use update_metadata::{
+    install_operation::Type as OperationType, InstallOperation, PartitionUpdate,
+};
+
+pub mod boot_image;
+mod bspatch;
+pub mod diagnostic;
+pub mod extent;
+mod extract;
+mod flash;
+pub mod patch_level;
+pub mod path_template;
+pub mod payload;
+pub mod progress;
+
+pub use bspatch::bspatch;
+pub use extent::ExtentStream;
+pub use extract::{
+    check_hash, extract,
+    hash::{HashKind, Hasher},
+    ExtractOptions, HashCheck, ImageFormat,
+};
+pub use payload::{Payload, PayloadLayout};
+pub use update_metadata::DeltaArchiveManifest;
+
+pub mod update_metadata {
+    pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
+    include!(concat!(env!("OUT_DIR"), "/chromeos_update_engine.rs"));
+
+    /// serializes a known enum value by its name in the proto, and anything else as its number
+    fn serialize_enum<S: serde::Serializer>(
+        serializer: S,
+        value: i32,
+        name: Option<&'static str>,
+    ) -> Result<S::Ok, S::Error> {
+        match name {
+            Some(name) => serializer.serialize_unit_variant("Type", value as u32, name),
+            None => serializer.serialize_i32(value),
+        }
+    }
+
+    fn serialize_op_type<S: serde::Serializer>(
+        value: &i32,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let name =
+            install_operation::Type::try_from(*value).ok().map(|op_type| op_type.as_str_name());
+        serialize_enum(serializer, *value, name)
+    }
+
+    fn serialize_cow_op_type<S: serde::Serializer>(
+        value: &Option<i32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => {
+                let name = cow_merge_operation::Type::try_from(*value).ok();
+                serialize_enum(serializer, *value, name.map(|op_type| op_type.as_str_name()))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+/// whether something can be applied without the previous image, ordered so that the minimum
+/// over the operations of a partition or payload classifies the whole
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum UpdateType {
+    Unknown,
+    Incremental,
+    Full,
+}
+
+/// classifies operations, partitions and payloads as full or incremental
+pub trait HasUpdateType {
+    fn get_update_type(&self) -> UpdateType;
+}
+
+impl HasUpdateType for OperationType {
+    fn get_update_type(&self) -> UpdateType {
+        use UpdateType::*;
+        match self {
+            // deprecated
+            OperationType::Move => Incremental,
+            OperationType::Bsdiff => Incremental,
+            // full
+            OperationType::Replace => Full,
+            OperationType::ReplaceBz => Full,
+            OperationType::ReplaceXz => Full,
+            OperationType::ReplaceZstd => Full,
+            OperationType::Zero => Full,
+            OperationType::Discard => Full,
+            // incremental
+            OperationType::SourceCopy => Incremental,
+            OperationType::SourceBsdiff => Incremental,
+            OperationType::BrotliBsdiff => Incremental,
+            OperationType::Puffdiff => Incremental,
+            OperationType::Zucchini => Incremental,
+            OperationType::Lz4diffBsdiff => Incremental,
+            OperationType::Lz4diffPuffdiff => Incremental,
+        }
+    }
+}
+
+impl HasUpdateType for InstallOperation {
+    fn get_update_type(&self) -> UpdateType {
+        OperationType::try_from(self.r#type)
+            .as_ref()
+            .map(HasUpdateType::get_update_type)
+            .unwrap_or(UpdateType::Unknown)
+    }
+}
+
+impl HasUpdateType for PartitionUpdate {
+    fn get_update_type(&self) -> UpdateType {
+        self.operations.iter().map(HasUpdateType::get_update_type).min().unwrap_or(UpdateType::Full)
+    }
+}
+
+impl HasUpdateType for DeltaArchiveManifest {
+    fn get_update_type(&self) -> UpdateType {
+        self.partitions.iter().map(HasUpdateType::get_update_type).min().unwrap_or(UpdateType::Full)
+    }
+}
+
+/// whether every operation of a partition copies src blocks to the same dst blocks, yet the new
+/// hash differs from the old one, which a correctly built payload can't produce
+pub fn has_copy_hash_mismatch(part: &PartitionUpdate) -> bool {
+    let (Some(old), Some(new)) = (&part.old_partition_info, &part.new_partition_info) else {
+        return false;
+    };
+    let copies_in_place = !part.operations.is_empty()
+        && part.operations.iter().all(|op| {
+            op.r#type == OperationType::SourceCopy as i32 && op.src_extents == op.dst_extents
+        });
+    copies_in_place && old.size == new.size && old.hash.is_some() && old.hash != new.hash
+}
@@ -1,16 +1,26 @@
-use std::{fs::File, io::Seek};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+};
 
+use android_ota_extractor::{
+    diagnostic, extract, patch_level::PatchLevel, path_template::PathTemplate, payload::Payload,
+    progress::StderrProgress, update_metadata, ExtractOptions, HasUpdateType, HashCheck,
+    ImageFormat, UpdateType,
+};
 use anyhow::{ensure, Context, Result};
-use binrw::BinRead;
-use clap::{Args, Parser, Subcommand};
+use cast::u64;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use output::{render_diagnostics, ColorChoice, Output, OutputFormat};
 use prost::Message;
-use update_metadata::{
-    install_operation::Type as OperationType, DeltaArchiveManifest, InstallOperation,
-    PartitionUpdate,
-};
+use update_metadata::DeltaArchiveManifest;
+use zip::{CompressionMethod, ZipArchive};
 
-mod extract;
 mod inspect;
+mod output;
+mod slice;
+mod verify;
 
 // cli
 
@@ -19,9 +29,20 @@ mod inspect;
 struct Cli {
     #[command(subcommand)]
     command: Action,
+    #[arg(long, global = true, value_enum, default_value_t)]
+    /// The format of printed information
+    format: OutputFormat,
+    #[arg(long, global = true, value_enum, default_value_t)]
+    /// When to color printed information
+    color: ColorChoice,
+    #[arg(long, global = true)]
+    /// Skip this many bytes of container framing before the payload, instead of searching for it
+    skip_prefix: Option<u64>,
 }
 
 #[derive(Subcommand, Debug)]
+// only ever constructed once, so boxing the extract args wouldn't gain anything
+#[allow(clippy::large_enum_variant)]
 enum Action {
     #[command(name = "extract")]
     /// Extract image files from the payload file
@@ -29,155 +50,651 @@ enum Action {
     #[command(name = "inspect")]
     /// Show information about included partition updates
     Inspect(InspectArgs),
+    #[command(name = "list")]
+    /// Print the names of the included partitions, one per line
+    List(ListArgs),
+    #[command(name = "verify")]
+    /// Check image files against the hashes in the payload file
+    Verify(VerifyArgs),
+    #[command(name = "slice")]
+    /// Write a smaller payload file which only updates some of the partitions
+    Slice(SliceArgs),
 }
 
 impl Action {
     fn get_file(&self) -> &str {
         match self {
-            Action::Extract(inner) => &inner.file,
+            Action::Extract(inner) => inner.data_path(),
             Action::Inspect(inner) => &inner.file,
+            Action::List(inner) => &inner.file,
+            Action::Verify(inner) => &inner.file,
+            Action::Slice(inner) => &inner.file,
         }
     }
 }
 
-#[derive(Debug, Args)]
+#[derive(Clone, Debug, Args)]
 struct ExtractArgs {
-    #[arg()]
-    /// The payload.bin file
-    file: String,
+    #[arg(required_unless_present = "raw_manifest")]
+    /// The payload.bin file, or an OTA zip containing it
+    file: Option<String>,
+    #[arg(long, requires = "data_file", conflicts_with = "file")]
+    /// Read the manifest from a standalone manifest.pb instead of a payload file
+    raw_manifest: Option<String>,
+    #[arg(long, requires = "raw_manifest")]
+    /// The data region extracted from a payload file, used together with --raw-manifest
+    data_file: Option<String>,
     #[arg(long)]
-    /// The folder which contains the image files before the update (only needed for incremental OTAs)
+    /// The folder or zip which contains the image files before the update (only needed for incremental OTAs)
     src: Option<String>,
+    #[arg(long, required_unless_present_any = ["pipe", "archive_ops", "dry_run"])]
+    /// The folder which will contain the image files after the update, or `-` to write the image
+    /// of the single selected partition to stdout
+    dst: Option<String>,
     #[arg(long)]
-    /// The folder which will contain the image files after the update
-    dst: String,
-    #[arg(long)]
-    /// The parts to extract; defaults to all parts
+    /// The parts to extract, which may be globs like `system*`; defaults to all parts, or `-` or `@file` to read them from stdin or a file
     parts: Option<Option<String>>,
     #[arg(long)]
-    /// Disable hash checking for src images and payload data
-    skip_hash: bool,
+    /// The parts to leave out, separated by commas and also allowing globs; applied after --parts
+    exclude: Option<String>,
+    #[arg(long, value_enum, value_delimiter = ',', num_args = 0..=1, require_equals = true)]
+    /// Disable the given hash checks (e.g. --skip-hash=src,data); disables all of them if empty
+    skip_hash: Option<Vec<HashCheck>>,
+    #[arg(long)]
+    /// A file of `name=filename` lines overriding the output file names of partitions; a name
+    /// may include subfolders but must stay inside --dst
+    name_map: Option<String>,
+    #[arg(long, visible_alias = "min-spl")]
+    /// Refuse to extract payloads with a security patch level older than this (YYYY-MM-DD)
+    min_patch_level: Option<PatchLevel>,
+    #[arg(long)]
+    /// Refuse to extract incremental payloads
+    require_full: bool,
+    #[arg(long)]
+    /// Report the time spent reading, decompressing, patching, writing and hashing per operation type
+    profile: bool,
+    #[arg(long)]
+    /// Also write the output of each operation to `{partition}_op{N}.bin` in this folder
+    per_op_output: Option<String>,
+    #[arg(long)]
+    /// A file of `name=block_size` lines overriding the manifest block size for partitions
+    block_size_map: Option<String>,
+    #[arg(long)]
+    /// Record completed operations next to each image and continue from them if interrupted;
+    /// images which already match their partition hash are skipped, and partitions updated in
+    /// place start over
+    resume: bool,
+    #[arg(long)]
+    /// Truncate trailing all-zero blocks from the extracted images
+    trim_trailing_zeros: bool,
+    #[arg(long)]
+    /// Flash each extracted image to the connected device with `fastboot flash`
+    flash: bool,
+    #[arg(long, requires = "flash")]
+    /// Don't ask for confirmation before flashing each partition
+    yes: bool,
+    #[arg(long)]
+    /// Only apply the first N operations of each partition, producing incomplete images
+    max_ops: Option<usize>,
+    #[arg(long, conflicts_with_all = ["dst", "resume", "trim_trailing_zeros", "flash"])]
+    /// Feed the image of the single selected partition to this shell command instead of a file,
+    /// once its hash checked out
+    pipe: Option<String>,
+    #[arg(long, conflicts_with = "pipe")]
+    /// Print the boot image header of the extracted boot, init_boot and vendor_boot images
+    boot_header: bool,
+    #[arg(long, hide = true, conflicts_with = "max_ops")]
+    /// Images are always checked now unless --skip-hash=output is given; kept for old scripts
+    verify_after: bool,
+    #[arg(long, requires = "src")]
+    /// List the files that changed between the src and extracted ext4 images of incremental parts
+    changed_files: bool,
+    #[arg(long, default_value_t = 1 << 20)]
+    /// The size in bytes of the buffer used to copy data into the images
+    io_buffer_size: usize,
+    #[arg(long)]
+    /// Put the images of dynamic partitions in a subfolder named after their group
+    group_dirs: bool,
+    #[arg(long, conflicts_with_all = ["name_map", "group_dirs", "pipe"])]
+    /// Lay out the images with a path like `{type}/{group}/{name}{slot}.img` inside --dst, where
+    /// `{slot}` is the `_a` or `_b` suffix of the partition name
+    path_template: Option<PathTemplate>,
+    #[arg(long, conflicts_with_all = ["pipe", "resume", "per_op_output"])]
+    /// Extract every image a second time into a temporary folder and fail if the two differ
+    verify_deterministic: bool,
+    #[arg(long)]
+    /// Finish with one line summarizing how many images were extracted, their size and the time taken
+    summary: bool,
+    #[arg(long)]
+    /// Write partitions without operations or with only zero operations as sparse zero filled images
+    emit_empty: bool,
+    #[arg(long, conflicts_with_all = ["skip_hash", "verify_after", "io_buffer_size"])]
+    /// Skip every hash check and use a large copy buffer; only for payloads you already trust,
+    /// since nothing about the payload or the images is verified
+    fast: bool,
+    #[arg(long)]
+    /// Warn when an operation writes dst blocks that a previous operation already wrote
+    check_overlap: bool,
+    #[arg(long)]
+    /// Fail instead of warning when operations write overlapping dst blocks
+    strict: bool,
+    #[arg(long, conflicts_with_all = ["dst", "pipe", "flash"])]
+    /// Write the raw data of every operation and a JSON index of them to this cpio archive
+    /// instead of extracting the images
+    archive_ops: Option<String>,
+    #[arg(long, conflicts_with_all = ["pipe", "archive_ops", "flash"])]
+    /// Check and list the operations of each partition without writing any images, flagging the
+    /// ones which would fail, e.g. because no src image was given or the type is unsupported
+    dry_run: bool,
+    #[arg(long, conflicts_with = "raw_manifest")]
+    /// Refuse to extract unless the metadata signature matches this PEM encoded RSA public key
+    pubkey: Option<String>,
+    #[arg(long)]
+    /// How many partitions to extract at the same time; defaults to the number of CPUs. Their
+    /// progress messages are interleaved
+    jobs: Option<usize>,
+    #[arg(long)]
+    /// Only extract as many of the --jobs partitions at the same time as fit in this many bytes
+    /// of memory, as estimated from their operations; a partition needing more runs on its own
+    max_total_memory: Option<u64>,
+    #[arg(long)]
+    /// Only extract as many of the --jobs partitions at the same time as can keep their files
+    /// open within this many: the payload, the image and the src image of each
+    max_open_files: Option<u64>,
+    #[arg(long)]
+    /// Write a JSON report of the run to this file: payload details, the expected and produced
+    /// size and hash of each image, timings, warnings and the tool version
+    report: Option<String>,
+    #[arg(long, value_enum, default_value_t = ImageFormat::Raw, conflicts_with_all = ["pipe", "resume", "trim_trailing_zeros"])]
+    /// The format of the written images; sparse images leave out the blocks no operation writes,
+    /// which makes flashing them with fastboot faster
+    output_format: ImageFormat,
+    #[arg(long)]
+    /// Write a care map of the extracted parts to this file, listing the blocks their operations
+    /// write in AOSP's care_map.txt format
+    care_map: Option<String>,
+    #[arg(long, conflicts_with = "pipe")]
+    /// Write the sha256 hashes of the extracted images to this file in the format of sha256sum,
+    /// so they can be checked with `sha256sum -c`
+    checksums: Option<String>,
+    #[arg(long, conflicts_with_all = ["pipe", "flash", "output_format"])]
+    /// Write the images of the dynamic partitions into one super image at this path, laid out
+    /// like lpmake would as the `_a` slot, instead of separate images
+    combined: Option<String>,
+    #[arg(long, requires = "combined")]
+    /// The size of the super partition for --combined; defaults to the smallest size the
+    /// partitions fit in
+    super_size: Option<u64>,
+    #[arg(long, default_value_t = 1, conflicts_with = "op_retries")]
+    /// How many operations of a partition to decompress and patch at the same time, for each of
+    /// the --jobs partitions; the images are still read and written in order
+    threads: usize,
+    #[arg(long, default_value_t = 0)]
+    /// Apply an operation again up to N times when it fails with a transient IO error
+    op_retries: usize,
 }
 
+impl ExtractArgs {
+    /// expands --fast into the options it stands for
+    fn apply_fast(&mut self) {
+        if self.fast {
+            self.skip_hash = Some(vec![]);
+            self.io_buffer_size = FAST_IO_BUFFER_SIZE;
+        }
+    }
+
+    /// the file operation data is read from, where the data region starts at the payload's data_offset
+    fn data_path(&self) -> &str {
+        self.data_file.as_deref().or(self.file.as_deref()).expect("clap requires a payload file")
+    }
+
+    /// the library options these arguments stand for, extracting from the given manifest
+    fn into_options(
+        self,
+        manifest: DeltaArchiveManifest,
+        data_offset: u64,
+    ) -> Result<ExtractOptions> {
+        let data_file = self.data_path().to_string();
+        let to_strings = |parts: Vec<&str>| parts.into_iter().map(str::to_string).collect();
+        let parts = parse_parts(&self.parts).map(to_strings);
+        let exclude = self.exclude.as_deref().map(split_parts).map_or(vec![], to_strings);
+        let skip_hash = match self.skip_hash {
+            // an empty --skip-hash skips all of them
+            Some(checks) if checks.is_empty() => HashCheck::value_variants().to_vec(),
+            checks => checks.unwrap_or_default(),
+        };
+        let name_map =
+            self.name_map.as_deref().map(parse_mapping_file).transpose()?.unwrap_or_default();
+        let block_size_map = self
+            .block_size_map
+            .as_deref()
+            .map(parse_mapping_file)
+            .transpose()?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, block_size)| {
+                let block_size = block_size.parse::<usize>().with_context(|| {
+                    format!("Invalid block size {:?} for partition {}", block_size, name)
+                })?;
+                Ok((name, block_size))
+            })
+            .collect::<Result<_>>()?;
+        Ok(ExtractOptions {
+            manifest,
+            data_file,
+            data_offset,
+            src: self.src,
+            dst: self.dst,
+            parts,
+            exclude,
+            skip_hash,
+            name_map,
+            block_size_map,
+            min_patch_level: self.min_patch_level,
+            require_full: self.require_full,
+            profile: self.profile,
+            per_op_output: self.per_op_output,
+            resume: self.resume,
+            trim_trailing_zeros: self.trim_trailing_zeros,
+            flash: self.flash,
+            yes: self.yes,
+            max_ops: self.max_ops,
+            pipe: self.pipe,
+            boot_header: self.boot_header,
+            changed_files: self.changed_files,
+            io_buffer_size: self.io_buffer_size,
+            group_dirs: self.group_dirs,
+            path_template: self.path_template,
+            verify_deterministic: self.verify_deterministic,
+            summary: self.summary,
+            emit_empty: self.emit_empty,
+            check_overlap: self.check_overlap,
+            strict: self.strict,
+            archive_ops: self.archive_ops,
+            dry_run: self.dry_run,
+            jobs: self.jobs,
+            max_total_memory: self.max_total_memory,
+            max_open_files: self.max_open_files,
+            report: self.report,
+            output_format: self.output_format,
+            care_map: self.care_map,
+            checksums: self.checksums,
+            combined: self.combined,
+            super_size: self.super_size,
+            threads: self.threads,
+            op_retries: self.op_retries,
+        })
+    }
+}
+
+/// the copy buffer size used by --fast
+const FAST_IO_BUFFER_SIZE: usize = 16 << 20;
+
 #[derive(Debug, Args)]
 struct InspectArgs {
     #[arg()]
-    /// The payload.bin file
+    /// The payload.bin file, or an OTA zip containing it
     file: String,
     #[arg(long)]
     /// The parts to list operations for; leave empty for all parts
     dump_ops: Option<Option<String>>,
+    #[arg(long)]
+    /// Print a hexdump of the data of this part's operations instead of the summary
+    hexdump: Option<String>,
+    #[arg(long, requires = "hexdump")]
+    /// The operations to dump, separated by commas; defaults to all operations
+    ops: Option<String>,
+    #[arg(long, default_value_t = 256)]
+    /// The maximum number of bytes to dump for each operation
+    hexdump_limit: usize,
+    #[arg(long, conflicts_with = "hexdump")]
+    /// Print which operation and payload bytes produce each range of this part's blocks
+    block_map: Option<String>,
+    #[arg(long, conflicts_with_all = ["hexdump", "block_map"])]
+    /// Print the summary as JSON, same as --format json
+    json: bool,
+    #[arg(long)]
+    /// Fail unless the metadata signature matches this PEM encoded RSA public key
+    pubkey: Option<String>,
+    #[arg(long, value_enum, conflicts_with_all = ["hexdump", "block_map", "json"])]
+    /// Write the whole manifest instead of the summary, e.g. to diff it against other tools
+    raw_manifest: Option<ManifestFormat>,
+    #[arg(long, requires = "raw_manifest")]
+    /// The file to write the manifest to; defaults to stdout
+    out: Option<String>,
 }
 
-// payload
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+enum ManifestFormat {
+    /// the protobuf encoding, re-encoded so fields this tool doesn't know about are dropped
+    Binary,
+    /// the protobuf text format, including the fields the summary doesn't show
+    Text,
+}
 
-pub mod update_metadata {
-    pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
-    include!(concat!(env!("OUT_DIR"), "/chromeos_update_engine.rs"));
+#[derive(Debug, Args)]
+struct ListArgs {
+    #[arg()]
+    /// The payload.bin file, or an OTA zip containing it
+    file: String,
+    #[arg(long)]
+    /// Also print whether each partition is updated fully or incrementally, after a tab
+    with_type: bool,
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
-pub enum UpdateType {
-    Unknown,
-    Incremental,
-    Full,
+#[derive(Debug, Args)]
+struct SliceArgs {
+    #[arg()]
+    /// The payload.bin file, or an OTA zip containing it
+    file: String,
+    #[arg(long)]
+    /// The parts to keep, separated by commas
+    parts: String,
+    #[arg(long)]
+    /// The payload file to write
+    out: String,
 }
 
-pub trait HasUpdateType {
-    fn get_update_type(&self) -> UpdateType;
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("checks").required(true).multiple(true)))]
+struct VerifyArgs {
+    #[arg()]
+    /// The payload.bin file, or an OTA zip containing it
+    file: String,
+    #[arg(long, group = "checks")]
+    /// Check the src images for incremental partitions in this folder against the payload
+    check_src: Option<String>,
+    #[arg(long, group = "checks")]
+    /// Check the extracted images in this folder against the new partition hashes
+    dst: Option<String>,
+    #[arg(long, group = "checks")]
+    /// Check every operation for invalid or inconsistent fields and report all problems found
+    lint: bool,
+    #[arg(long, group = "checks", requires = "key")]
+    /// Check both the metadata and the payload signature against --key
+    full_chain: bool,
+    #[arg(long)]
+    /// The PEM encoded RSA public key the payload should be signed with
+    key: Option<String>,
+    #[arg(long)]
+    /// The parts to verify; defaults to all parts, or `-` or `@file` to read them from stdin or a file
+    parts: Option<Option<String>>,
+    #[arg(long, group = "checks", visible_alias = "min-spl")]
+    /// Fail if the payload's security patch level is older than this (YYYY-MM-DD)
+    min_patch_level: Option<PatchLevel>,
 }
 
-impl HasUpdateType for OperationType {
-    fn get_update_type(&self) -> UpdateType {
-        use UpdateType::*;
-        match self {
-            // deprecated
-            OperationType::Move => Incremental,
-            OperationType::Bsdiff => Incremental,
-            // full
-            OperationType::Replace => Full,
-            OperationType::ReplaceBz => Full,
-            OperationType::ReplaceXz => Full,
-            OperationType::Zero => Full,
-            OperationType::Discard => Full,
-            // incremental
-            OperationType::SourceCopy => Incremental,
-            OperationType::SourceBsdiff => Incremental,
-            OperationType::BrotliBsdiff => Incremental,
-            OperationType::Puffdiff => Incremental,
-            OperationType::Zucchini => Incremental,
-            OperationType::Lz4diffBsdiff => Incremental,
-            OperationType::Lz4diffPuffdiff => Incremental,
-        }
-    }
+// payload
+
+pub fn parse_parts(parts: &Option<Option<String>>) -> Option<Vec<&str>> {
+    parts.as_ref().map(|parts| parts.as_deref().map(split_parts).unwrap_or_default())
 }
 
-impl HasUpdateType for InstallOperation {
-    fn get_update_type(&self) -> UpdateType {
-        update_metadata::install_operation::Type::try_from(self.r#type)
-            .as_ref()
-            .map(HasUpdateType::get_update_type)
-            .unwrap_or(UpdateType::Unknown)
-    }
+/// splits a comma separated list of parts
+pub fn split_parts(parts: &str) -> Vec<&str> {
+    parts.split(",").map(|part| part.trim()).collect()
 }
 
-impl HasUpdateType for PartitionUpdate {
-    fn get_update_type(&self) -> UpdateType {
-        self.operations.iter().map(HasUpdateType::get_update_type).min().unwrap_or(UpdateType::Full)
-    }
+/// the parts in a list read from stdin or a file, separated by commas or lines, ignoring empty
+/// lines and `#` comments
+fn parse_parts_list(input: &str) -> Vec<&str> {
+    input
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(line, _comment)| line))
+        .flat_map(|line| line.split(","))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect()
 }
 
-impl HasUpdateType for DeltaArchiveManifest {
-    fn get_update_type(&self) -> UpdateType {
-        self.partitions.iter().map(HasUpdateType::get_update_type).min().unwrap_or(UpdateType::Full)
+/// replaces a `--parts -` or `--parts @file` list with the parts read from stdin or the file
+fn read_parts_list(parts: &mut Option<Option<String>>) -> Result<()> {
+    let Some(Some(list)) = parts else {
+        return Ok(());
+    };
+    let (input, source) = if list == "-" {
+        (io::read_to_string(io::stdin())?, "stdin".to_string())
+    } else if let Some(path) = list.strip_prefix('@') {
+        let input = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read parts file {}", path))?;
+        (input, path.to_string())
+    } else {
+        return Ok(());
+    };
+    let names = parse_parts_list(&input);
+    ensure!(!names.is_empty(), "No parts were given in {}", source);
+    *list = names.join(",");
+    Ok(())
+}
+
+/// Parses a file of `key=value` lines, ignoring empty lines and `#` comments
+pub fn parse_mapping_file(path: &str) -> Result<HashMap<String, String>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read mapping file {}", path))?;
+    let mut mapping = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Expected key=value on line {} of {}", i + 1, path))?;
+        mapping.insert(key.trim().to_string(), value.trim().to_string());
     }
+    Ok(mapping)
 }
 
-#[derive(BinRead)]
-#[br(magic = b"CrAU", big)]
-struct PayloadFile {
-    file_format_version: u64,
-    _manifest_size: u64,
-    #[br(if(file_format_version >= 2))]
-    _metadata_signature_size: u32,
-    #[br(count = _manifest_size)]
-    manifest: Vec<u8>,
-    #[br(count = _metadata_signature_size)]
-    _metadata_signature_message: Vec<u8>,
+/// how far into a file to look for the payload magic when no --skip-prefix is given
+const MAGIC_SEARCH_LEN: usize = 64 * 1024;
+
+/// finds the `payload.bin` of an OTA zip, which is stored uncompressed so it can be read in place
+fn find_zip_payload(file: &mut File) -> Result<Option<u64>> {
+    let mut magic = [0; 4];
+    let is_zip = file.read_exact(&mut magic).is_ok() && magic == *b"PK\x03\x04";
+    file.rewind()?;
+    if !is_zip {
+        return Ok(None);
+    }
+    let start = {
+        let mut archive = ZipArchive::new(&mut *file).context("Failed to read the OTA zip")?;
+        let entry = archive.by_name("payload.bin").context("The OTA zip has no payload.bin")?;
+        ensure!(
+            entry.compression() == CompressionMethod::Stored,
+            "payload.bin is compressed in the OTA zip, unzip it first"
+        );
+        entry.data_start()
+    };
+    file.rewind()?;
+    Ok(Some(start))
 }
 
-pub fn parse_parts(parts: &Option<Option<String>>) -> Option<Vec<&str>> {
-    parts.as_ref().map(|parts| {
-        parts
-            .as_deref()
-            .map(|parts| parts.split(",").map(|part| part.trim()).collect::<Vec<_>>())
-            .unwrap_or_default()
-    })
+/// finds where the payload starts in a file that may have some container framing prepended
+fn find_payload_start(file: &mut File, skip_prefix: Option<u64>) -> Result<u64> {
+    if let Some(skip_prefix) = skip_prefix {
+        return Ok(skip_prefix);
+    }
+    if let Some(start) = find_zip_payload(file)? {
+        return Ok(start);
+    }
+    let mut head = vec![];
+    file.take(u64(MAGIC_SEARCH_LEN)).read_to_end(&mut head)?;
+    file.rewind()?;
+    let start = head.windows(4).position(|window| window == b"CrAU");
+    match start {
+        Some(start) if start > 0 => {
+            diagnostic::warn(
+                diagnostic::Code::PayloadPrefix,
+                format!("skipped {} bytes before the payload magic", start),
+            );
+            Ok(u64(start))
+        }
+        // let parsing report a missing magic
+        _ => Ok(0),
+    }
 }
 
-fn main() -> Result<()> {
-    let args = Cli::parse();
-    let file_name = args.command.get_file();
+/// reads the manifest, returning it along with the offsets of the payload and its data in the file
+fn read_payload(
+    file_name: &str,
+    skip_prefix: Option<u64>,
+) -> Result<(DeltaArchiveManifest, u64, u64)> {
     let mut file = File::open(file_name)
         .with_context(|| format!("Failed to open file payload file {}", file_name))?;
-    let payload = PayloadFile::read(&mut file)
+    let payload_start = find_payload_start(&mut file, skip_prefix)?;
+    file.seek(SeekFrom::Start(payload_start))?;
+    let payload = Payload::open(&mut file)
         .with_context(|| format!("Failed to parse file payload file {}", file_name))?;
-    ensure!(
-        payload.file_format_version == 2,
-        "unsupported file version {}, only version 2 is supported",
-        payload.file_format_version
-    );
-
-    let data_offset = file.stream_position()?;
-    let manifest = DeltaArchiveManifest::decode(&*payload.manifest)
-        .with_context(|| format!("Failed to parse file payload file manifest for payload file"))?;
-
-    match args.command {
-        Action::Extract(extract_args) => extract::extract(&manifest, &extract_args, data_offset)
-            .with_context(|| format!("Failed to extract images"))?,
-        Action::Inspect(inspect_args) => inspect::inspect(&manifest, &inspect_args, data_offset)
-            .with_context(|| format!("Failed to inspect payload"))?,
+    Ok((payload.manifest, payload_start, payload.layout.data_offset))
+}
+
+fn main() -> Result<()> {
+    let mut args = Cli::parse();
+    match &mut args.command {
+        Action::Extract(ExtractArgs { parts, .. }) | Action::Verify(VerifyArgs { parts, .. }) => {
+            read_parts_list(parts).with_context(|| format!("Failed to read the list of parts"))?
+        }
+        Action::Inspect(_) | Action::List(_) | Action::Slice(_) => {}
+    }
+    if let Action::Extract(extract_args) = &mut args.command {
+        extract_args.apply_fast();
+    }
+    let format = match &args.command {
+        Action::Inspect(InspectArgs { json: true, .. }) => OutputFormat::Json,
+        _ => args.format,
+    };
+    let output = Output::new(format, args.color);
+    let (manifest, payload_start, data_offset) = match &args.command {
+        Action::Extract(ExtractArgs { raw_manifest: Some(raw_manifest), .. }) => {
+            // the data file starts at the data region, so all data offsets are relative to 0
+            let manifest = fs::read(raw_manifest)
+                .with_context(|| format!("Failed to read manifest file {}", raw_manifest))?;
+            let manifest = DeltaArchiveManifest::decode(&*manifest)
+                .with_context(|| format!("Failed to parse manifest file {}", raw_manifest))?;
+            (manifest, 0, 0)
+        }
+        command => read_payload(command.get_file(), args.skip_prefix)?,
     };
+    if let Action::Extract(ExtractArgs { pubkey: Some(key), .. })
+    | Action::Inspect(InspectArgs { pubkey: Some(key), .. }) = &args.command
+    {
+        verify::check_metadata_signature(args.command.get_file(), payload_start, key)?;
+    }
 
-    Ok(())
+    let result = match args.command {
+        Action::Extract(extract_args) => extract_args
+            .into_options(manifest, data_offset)
+            .and_then(|options| extract(&options, &StderrProgress))
+            .with_context(|| format!("Failed to extract images")),
+        Action::Inspect(inspect_args) => {
+            inspect::inspect(&manifest, &inspect_args, data_offset, &output)
+                .with_context(|| format!("Failed to inspect payload"))
+        }
+        Action::List(list_args) => {
+            inspect::list(&manifest, &list_args);
+            Ok(())
+        }
+        Action::Verify(verify_args) => {
+            verify::verify(&manifest, &verify_args, payload_start, &output)
+                .with_context(|| format!("Failed to verify images"))
+        }
+        Action::Slice(slice_args) => slice::slice(&manifest, &slice_args, data_offset)
+            .with_context(|| format!("Failed to slice payload")),
+    };
+    // warnings are printed even if the subcommand failed, since they may explain why
+    render_diagnostics(&output)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use android_ota_extractor::{update_metadata::DeltaArchiveManifest, HashCheck};
+    use clap::Parser;
+    use std::{
+        fs::File,
+        io::{Read, Seek, SeekFrom, Write},
+    };
+    use tempfile::NamedTempFile;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    use super::{find_payload_start, parse_parts_list, Action, Cli, ExtractOptions};
+
+    /// the library options of `extract payload.bin --dst out` followed by `extra`
+    fn extract_options(extra: &[&str]) -> ExtractOptions {
+        let base = ["android-ota-extractor", "extract", "payload.bin", "--dst", "out"];
+        let Action::Extract(mut args) = Cli::parse_from(base.iter().chain(extra)).command else {
+            unreachable!()
+        };
+        args.apply_fast();
+        args.into_options(DeltaArchiveManifest::default(), 0).unwrap()
+    }
+
+    fn ota_zip(payload_method: CompressionMethod) -> NamedTempFile {
+        let mut zip = ZipWriter::new(NamedTempFile::new().unwrap());
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("payload_properties.txt", deflated).unwrap();
+        zip.write_all(b"FILE_HASH=...").unwrap();
+        let options = FileOptions::default().compression_method(payload_method);
+        zip.start_file("payload.bin", options).unwrap();
+        zip.write_all(b"CrAU payload").unwrap();
+        zip.finish().unwrap()
+    }
+
+    #[test]
+    fn find_payload_start_test() {
+        // the payload is read in place from the zip
+        let mut file = File::open(ota_zip(CompressionMethod::Stored).path()).unwrap();
+        let start = find_payload_start(&mut file, None).unwrap();
+        assert_eq!(file.stream_position().unwrap(), 0);
+        file.seek(SeekFrom::Start(start)).unwrap();
+        let mut payload = [0; 12];
+        file.read_exact(&mut payload).unwrap();
+        assert_eq!(&payload, b"CrAU payload");
+        // --skip-prefix wins over the zip
+        assert_eq!(find_payload_start(&mut file, Some(7)).unwrap(), 7);
+
+        let mut file = File::open(ota_zip(CompressionMethod::Deflated).path()).unwrap();
+        let err = find_payload_start(&mut file, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("is compressed"), "{:#}", err);
+
+        let mut zip = ZipWriter::new(NamedTempFile::new().unwrap());
+        zip.start_file("system.img", FileOptions::default()).unwrap();
+        let mut file = File::open(zip.finish().unwrap().path()).unwrap();
+        let err = find_payload_start(&mut file, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("has no payload.bin"), "{:#}", err);
+
+        // a bare payload, with and without framing before the magic
+        let mut payload = NamedTempFile::new().unwrap();
+        payload.write_all(b"CrAU payload").unwrap();
+        payload.rewind().unwrap();
+        assert_eq!(find_payload_start(payload.as_file_mut(), None).unwrap(), 0);
+        let mut framed = NamedTempFile::new().unwrap();
+        framed.write_all(b"header CrAU payload").unwrap();
+        framed.rewind().unwrap();
+        assert_eq!(find_payload_start(framed.as_file_mut(), None).unwrap(), 7);
+    }
+
+    #[test]
+    fn into_options_test() {
+        let options = extract_options(&[]);
+        assert_eq!(options.data_file, "payload.bin");
+        assert!(options.parts.is_none() && options.skip_hash.is_empty());
+
+        let options = extract_options(&["--parts", "boot, system*", "--exclude", "system_ext"]);
+        assert_eq!(options.parts.unwrap(), ["boot", "system*"]);
+        assert_eq!(options.exclude, ["system_ext"]);
+
+        // an empty --skip-hash and --fast skip every check
+        let all = [HashCheck::Src, HashCheck::Data, HashCheck::Output];
+        assert_eq!(extract_options(&["--skip-hash"]).skip_hash, all);
+        assert_eq!(extract_options(&["--skip-hash=src,output"]).skip_hash, [all[0], all[2]]);
+        assert_eq!(extract_options(&["--fast"]).skip_hash, all);
+    }
+
+    #[test]
+    fn parse_parts_list_test() {
+        let input = "# partitions to extract\nboot, vendor_boot\n\nsystem # the big one\n  odm\n";
+        assert_eq!(parse_parts_list(input), ["boot", "vendor_boot", "system", "odm"]);
+        assert!(parse_parts_list("# nothing\n\n").is_empty());
+    }
 }
@@ -0,0 +1,99 @@
+use android_ota_extractor::diagnostic;
+use anyhow::Result;
+use clap::ValueEnum;
+use std::{
+    env,
+    fmt::Display,
+    io::{self, IsTerminal},
+};
+
+/// the format used for the output of subcommands which print information about the payload;
+/// new formats are added here as the subcommands learn to produce them
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// human readable text
+    #[default]
+    Text,
+    /// machine readable JSON
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// color when stdout is a terminal and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                !matches!(env::var_os("NO_COLOR"), Some(val) if !val.is_empty())
+                    && io::stdout().is_terminal()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Style {
+    Bold,
+    Green,
+    Yellow,
+    Red,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Green => "32",
+            Style::Yellow => "33",
+            Style::Red => "31",
+        }
+    }
+}
+
+/// output settings shared by all subcommands
+#[derive(Clone, Copy, Debug)]
+pub struct Output {
+    pub format: OutputFormat,
+    pub color: bool,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, color: ColorChoice) -> Self {
+        Self { format, color: color.enabled() }
+    }
+
+    pub fn paint(&self, val: impl Display, style: Style) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", style.code(), val)
+        } else {
+            val.to_string()
+        }
+    }
+}
+
+/// prints the collected warnings to stderr, so stdout stays parseable for json output
+pub fn render_diagnostics(output: &Output) -> Result<()> {
+    let diagnostics = diagnostic::collected();
+    match output.format {
+        OutputFormat::Text => {
+            for diagnostic in &diagnostics {
+                let code = output.paint(format!("warning[{}]", diagnostic.code.name()), Style::Red);
+                eprintln!("{}: {}", code, diagnostic.message);
+            }
+        }
+        OutputFormat::Json => {
+            let report = serde_json::json!({ "diagnostics": diagnostics });
+            eprintln!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use anyhow::{anyhow, ensure, Context, Error, Result};
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+/// a security patch level in the `YYYY-MM-DD` format used by android
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PatchLevel {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl FromStr for PatchLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.trim().splitn(3, '-');
+        let mut next_field = |name: &str| {
+            fields.next().ok_or_else(|| anyhow!("Missing {} in patch level {:?}", name, s))
+        };
+        let year = next_field("year")?;
+        let month = next_field("month")?;
+        let day = next_field("day")?;
+
+        let level = PatchLevel {
+            year: year.parse().with_context(|| format!("Invalid year in patch level {:?}", s))?,
+            month: month
+                .parse()
+                .with_context(|| format!("Invalid month in patch level {:?}", s))?,
+            day: day.parse().with_context(|| format!("Invalid day in patch level {:?}", s))?,
+        };
+        ensure!((1..=12).contains(&level.month), "Invalid month in patch level {:?}", s);
+        ensure!((1..=31).contains(&level.day), "Invalid day in patch level {:?}", s);
+        Ok(level)
+    }
+}
+
+impl Display for PatchLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// fails unless the payload's security patch level is at least `min_level`
+pub fn check_min_patch_level(level: Option<&str>, min_level: &PatchLevel) -> Result<()> {
+    let level: PatchLevel =
+        level.ok_or_else(|| anyhow!("Payload does not specify a security patch level"))?.parse()?;
+    ensure!(
+        level >= *min_level,
+        "Payload security patch level {} is older than the minimum {}",
+        level,
+        min_level
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_min_patch_level, PatchLevel};
+
+    #[test]
+    fn patch_level_parse_test() {
+        let level: PatchLevel = "2024-06-01".parse().unwrap();
+        assert_eq!(level, PatchLevel { year: 2024, month: 6, day: 1 });
+        assert_eq!(level.to_string(), "2024-06-01");
+
+        assert!("2024-06".parse::<PatchLevel>().is_err());
+        assert!("2024-13-01".parse::<PatchLevel>().is_err());
+        assert!("2024-06-xx".parse::<PatchLevel>().is_err());
+    }
+
+    #[test]
+    fn patch_level_order_test() {
+        let parse = |s: &str| s.parse::<PatchLevel>().unwrap();
+        assert!(parse("2024-06-01") < parse("2024-06-05"));
+        assert!(parse("2023-12-05") < parse("2024-01-01"));
+        assert!(parse("2024-02-01") > parse("2024-01-31"));
+    }
+
+    #[test]
+    fn check_min_patch_level_test() {
+        let min_level = "2024-06-01".parse().unwrap();
+        assert!(check_min_patch_level(Some("2024-06-01"), &min_level).is_ok());
+        assert!(check_min_patch_level(Some("2024-07-05"), &min_level).is_ok());
+        let err = check_min_patch_level(Some("2024-05-05"), &min_level).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Payload security patch level 2024-05-05 is older than the minimum 2024-06-01"
+        );
+        assert!(check_min_patch_level(None, &min_level).is_err());
+    }
+}
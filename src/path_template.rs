@@ -0,0 +1,134 @@
+use anyhow::{anyhow, bail, ensure, Error, Result};
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+/// the values substituted into a path template for one partition
+pub struct PathFields<'a> {
+    pub name: &'a str,
+    pub group: Option<&'a str>,
+    pub slot: Option<&'a str>,
+    pub update_type: &'a str,
+}
+
+impl<'a> PathFields<'a> {
+    /// splits a trailing `_a` or `_b` off the partition name into the slot
+    pub fn new(name: &'a str, group: Option<&'a str>, update_type: &'a str) -> Self {
+        let (name, slot) = match name.rsplit_once('_') {
+            Some((base, slot @ ("a" | "b"))) if !base.is_empty() => (base, Some(slot)),
+            _ => (name, None),
+        };
+        Self { name, group, slot, update_type }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Name,
+    Group,
+    Slot,
+    Type,
+}
+
+/// an output path relative to the destination folder, with `{name}`, `{group}`, `{slot}` (like
+/// `_a`) and `{type}` placeholders, e.g. `{type}/{group}/{name}.img`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathTemplate(Vec<Piece>);
+
+impl FromStr for PathTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut pieces = vec![];
+        let mut rest = s;
+        while let Some(open) = rest.find(['{', '}']) {
+            ensure!(&rest[open..open + 1] == "{", "Unmatched }} in path template {:?}", s);
+            if open > 0 {
+                pieces.push(Piece::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unmatched {{ in path template {:?}", s))?;
+            pieces.push(match &rest[open + 1..open + close] {
+                "name" => Piece::Name,
+                "group" => Piece::Group,
+                "slot" => Piece::Slot,
+                "type" => Piece::Type,
+                other => bail!("Unknown placeholder {{{}}} in path template {:?}", other, s),
+            });
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            pieces.push(Piece::Literal(rest.to_string()));
+        }
+        ensure!(!pieces.is_empty(), "Path template is empty");
+        Ok(Self(pieces))
+    }
+}
+
+impl PathTemplate {
+    /// fills in the placeholders, leaving out folders which end up empty, and makes sure the
+    /// path stays inside the destination folder
+    pub fn render(&self, fields: &PathFields) -> Result<PathBuf> {
+        let mut rendered = String::new();
+        for piece in &self.0 {
+            match piece {
+                Piece::Literal(literal) => rendered.push_str(literal),
+                Piece::Name => rendered.push_str(fields.name),
+                Piece::Group => rendered.push_str(fields.group.unwrap_or("")),
+                // the slot keeps its underscore, so `{name}{slot}` gives back the partition name
+                Piece::Slot => {
+                    if let Some(slot) = fields.slot {
+                        rendered.push('_');
+                        rendered.push_str(slot);
+                    }
+                }
+                Piece::Type => rendered.push_str(fields.update_type),
+            }
+        }
+
+        relative_path(&rendered, fields.name)
+    }
+}
+
+/// the output path of partition `name` inside the destination folder, failing if `path` would
+/// leave the folder or names no file
+pub fn relative_path(path: &str, name: &str) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => bail!("Path {:?} for {} leaves the destination folder", path, name),
+        }
+    }
+    ensure!(relative.file_name().is_some(), "Path {:?} for {} is empty", path, name);
+    Ok(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{PathFields, PathTemplate};
+
+    #[test]
+    fn path_template_test() {
+        let template: PathTemplate = "{type}/{group}/{name}{slot}.img".parse().unwrap();
+        let fields = PathFields::new("system_a", Some("main"), "full");
+        assert_eq!((fields.name, fields.slot), ("system", Some("a")));
+        assert_eq!(template.render(&fields).unwrap(), Path::new("full/main/system_a.img"));
+        // partitions outside of a group don't get an empty folder
+        let fields = PathFields::new("boot", None, "incremental");
+        assert_eq!(template.render(&fields).unwrap(), Path::new("incremental/boot.img"));
+
+        assert!("{name".parse::<PathTemplate>().is_err());
+        assert!("{version}.img".parse::<PathTemplate>().is_err());
+        let template: PathTemplate = "../{name}.img".parse().unwrap();
+        assert!(template.render(&fields).is_err());
+        let template: PathTemplate = "/{name}.img".parse().unwrap();
+        assert!(template.render(&fields).is_err());
+    }
+}
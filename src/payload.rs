@@ -0,0 +1,232 @@
+use anyhow::{ensure, Context, Result};
+use binrw::BinRead;
+use cast::usize;
+use prost::Message;
+use std::{
+    io::{Read, Seek},
+    ops::Range,
+};
+
+use crate::{
+    extent::Extent,
+    update_metadata::{DeltaArchiveManifest, InstallOperation, PartitionInfo, PartitionUpdate},
+};
+
+/// the header of a payload file, up to the start of the data region
+#[derive(BinRead)]
+#[br(magic = b"CrAU", big)]
+pub struct PayloadFile {
+    pub file_format_version: u64,
+    pub manifest_size: u64,
+    #[br(if(file_format_version >= 2))]
+    pub metadata_signature_size: u32,
+    #[br(count = manifest_size)]
+    pub manifest: Vec<u8>,
+    #[br(count = metadata_signature_size)]
+    pub metadata_signature_message: Vec<u8>,
+}
+
+/// the fields version 1 manifests list the operations in instead of `partitions`, which are
+/// reserved in the current proto
+#[derive(Clone, PartialEq, prost::Message)]
+struct LegacyManifest {
+    #[prost(message, repeated, tag = "1")]
+    install_operations: Vec<InstallOperation>,
+    #[prost(message, repeated, tag = "2")]
+    kernel_install_operations: Vec<InstallOperation>,
+    #[prost(message, optional, tag = "6")]
+    old_kernel_info: Option<PartitionInfo>,
+    #[prost(message, optional, tag = "7")]
+    new_kernel_info: Option<PartitionInfo>,
+    #[prost(message, optional, tag = "8")]
+    old_rootfs_info: Option<PartitionInfo>,
+    #[prost(message, optional, tag = "9")]
+    new_rootfs_info: Option<PartitionInfo>,
+}
+
+impl LegacyManifest {
+    /// the rootfs and kernel as partitions, named and ordered like update_engine applies them
+    fn into_partitions(self) -> Vec<PartitionUpdate> {
+        let partition = |name: &str, operations: Vec<_>, old_info, new_info| PartitionUpdate {
+            partition_name: name.to_string(),
+            operations,
+            old_partition_info: old_info,
+            new_partition_info: new_info,
+            ..Default::default()
+        };
+        [
+            partition("root", self.install_operations, self.old_rootfs_info, self.new_rootfs_info),
+            partition(
+                "kernel",
+                self.kernel_install_operations,
+                self.old_kernel_info,
+                self.new_kernel_info,
+            ),
+        ]
+        .into_iter()
+        .filter(|part| !part.operations.is_empty() || part.new_partition_info.is_some())
+        .collect()
+    }
+}
+
+/// where the regions of a payload file are located
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PayloadLayout {
+    /// the offset of the data region, which the data offsets of operations are relative to
+    pub data_offset: u64,
+}
+
+/// a parsed payload: the manifest describing the partitions and where their data is
+pub struct Payload {
+    pub manifest: DeltaArchiveManifest,
+    pub layout: PayloadLayout,
+}
+
+impl Payload {
+    /// parses the payload starting at the current position of `reader`, so a payload inside a
+    /// bigger file can be read by seeking to it first
+    ///
+    /// the data offset of the returned layout is a position in `reader`, which the data of the
+    /// operations can then be read from
+    pub fn open(reader: &mut (impl Read + Seek)) -> Result<Self> {
+        let header = PayloadFile::read(reader).context("Failed to parse the payload header")?;
+        ensure!(
+            matches!(header.file_format_version, 1 | 2),
+            "unsupported file version {}, only versions 1 and 2 are supported",
+            header.file_format_version
+        );
+        let data_offset = reader.stream_position()?;
+        let mut manifest = DeltaArchiveManifest::decode(&*header.manifest)
+            .context("Failed to parse the payload manifest")?;
+        if header.file_format_version == 1 {
+            manifest.partitions = LegacyManifest::decode(&*header.manifest)
+                .context("Failed to parse the version 1 payload manifest")?
+                .into_partitions();
+        }
+        Ok(Self { manifest, layout: PayloadLayout { data_offset } })
+    }
+}
+
+/// the absolute byte range of an operation's data in the payload file
+///
+/// returns none if the operation has no data, or if the range doesn't fit in a u64
+pub fn absolute_data_range(layout: &PayloadLayout, op: &InstallOperation) -> Option<Range<u64>> {
+    let (offset, len) = op.data_offset.zip(op.data_length)?;
+    let start = layout.data_offset.checked_add(offset)?;
+    Some(start..start.checked_add(len)?)
+}
+
+/// the absolute byte extents of an operation's data in the payload file, in reading order
+///
+/// standard operations have a single contiguous range, but the data stream is built from a list
+/// so operations whose data is split over several ranges can be read the same way
+pub fn data_extents(layout: &PayloadLayout, op: &InstallOperation) -> Option<Vec<Extent>> {
+    let range = absolute_data_range(layout, op)?;
+    Some(vec![Extent { start: usize(range.start), len: usize(range.end - range.start) }])
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use std::io::Cursor;
+
+    use super::{absolute_data_range, data_extents, LegacyManifest, Payload, PayloadLayout};
+    use crate::{
+        extent::Extent,
+        update_metadata::{DeltaArchiveManifest, InstallOperation, PartitionInfo, PartitionUpdate},
+    };
+
+    #[test]
+    fn payload_open_test() {
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![PartitionUpdate {
+                partition_name: "boot".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .encode_to_vec();
+        // some framing before the payload, which the reader is positioned after
+        let mut file = b"junk".to_vec();
+        file.extend_from_slice(b"CrAU");
+        file.extend_from_slice(&2u64.to_be_bytes());
+        file.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+        file.extend_from_slice(&3u32.to_be_bytes());
+        file.extend_from_slice(&manifest);
+        file.extend_from_slice(b"sig");
+        let data_offset = file.len() as u64;
+        file.extend_from_slice(b"data");
+
+        let mut reader = Cursor::new(file);
+        reader.set_position(4);
+        let payload = Payload::open(&mut reader).unwrap();
+        assert_eq!(payload.layout, PayloadLayout { data_offset });
+        assert_eq!(payload.manifest.partitions[0].partition_name, "boot");
+        assert!(Payload::open(&mut Cursor::new(b"CrAU")).is_err());
+    }
+
+    #[test]
+    fn payload_open_v1_test() {
+        let op =
+            InstallOperation { data_offset: Some(0), data_length: Some(4), ..Default::default() };
+        let mut manifest = LegacyManifest {
+            install_operations: vec![op.clone()],
+            kernel_install_operations: vec![op],
+            new_rootfs_info: Some(PartitionInfo { size: Some(4096), hash: None }),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        DeltaArchiveManifest { block_size: Some(4096), ..Default::default() }
+            .encode(&mut manifest)
+            .unwrap();
+        // version 1 has no metadata signature size or signature after the manifest
+        let mut file = b"CrAU".to_vec();
+        file.extend_from_slice(&1u64.to_be_bytes());
+        file.extend_from_slice(&(manifest.len() as u64).to_be_bytes());
+        file.extend_from_slice(&manifest);
+        let data_offset = file.len() as u64;
+        file.extend_from_slice(b"data");
+
+        let payload = Payload::open(&mut Cursor::new(file)).unwrap();
+        assert_eq!(payload.layout, PayloadLayout { data_offset });
+        assert_eq!(payload.manifest.block_size, Some(4096));
+        let parts = &payload.manifest.partitions;
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].partition_name, "root");
+        assert_eq!(parts[0].new_partition_info.as_ref().unwrap().size, Some(4096));
+        assert_eq!(parts[1].partition_name, "kernel");
+        assert_eq!(parts[1].operations.len(), 1);
+
+        let mut file = b"CrAU".to_vec();
+        file.extend_from_slice(&3u64.to_be_bytes());
+        file.extend_from_slice(&0u64.to_be_bytes());
+        file.extend_from_slice(&0u32.to_be_bytes());
+        assert!(Payload::open(&mut Cursor::new(file)).is_err());
+    }
+
+    #[test]
+    fn absolute_data_range_test() {
+        let layout = PayloadLayout { data_offset: 100 };
+        let op = |data_offset, data_length| InstallOperation {
+            data_offset,
+            data_length,
+            ..Default::default()
+        };
+
+        assert_eq!(absolute_data_range(&layout, &op(Some(0), Some(10))), Some(100..110));
+        assert_eq!(absolute_data_range(&layout, &op(Some(20), Some(5))), Some(120..125));
+        assert_eq!(absolute_data_range(&layout, &op(None, None)), None);
+        assert_eq!(absolute_data_range(&layout, &op(Some(20), None)), None);
+        assert_eq!(absolute_data_range(&layout, &op(Some(u64::MAX), Some(1))), None);
+    }
+
+    #[test]
+    fn data_extents_test() {
+        let layout = PayloadLayout { data_offset: 100 };
+        let op =
+            InstallOperation { data_offset: Some(20), data_length: Some(5), ..Default::default() };
+
+        assert_eq!(data_extents(&layout, &op), Some(vec![Extent { start: 120, len: 5 }]));
+        assert_eq!(data_extents(&layout, &InstallOperation::default()), None);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::update_metadata::install_operation::Type as OperationType;
+
+/// how many of the bytes the operations of a partition write were written, which measures
+/// progress better than counting operations since they vary a lot in size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BytesProgress {
+    pub done: u64,
+    pub total: u64,
+}
+
+impl BytesProgress {
+    pub fn percent(&self) -> u64 {
+        match self.total {
+            0 => 100,
+            // saturating, since a malformed payload can give any byte counts
+            total => self.done.min(total).saturating_mul(100) / total,
+        }
+    }
+}
+
+/// formats a byte count with a binary unit, like `12.4 GiB`
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// gets told how extraction is going, so front ends can show progress their own way
+///
+/// partitions may be extracted on several threads at once, so every call names its partition
+pub trait ProgressSink: Sync {
+    /// `total_bytes` is the sum of the dst extents of all operations
+    fn on_partition_start(&self, name: &str, total_ops: usize, total_bytes: u64);
+    /// called before operation `index` of the partition is applied
+    fn on_operation(&self, name: &str, index: usize, op_type: OperationType, bytes: BytesProgress);
+    fn on_partition_done(&self, name: &str);
+}
+
+/// prints a line for every partition and operation to stderr
+pub struct StderrProgress;
+
+impl ProgressSink for StderrProgress {
+    fn on_partition_start(&self, name: &str, _total_ops: usize, _total_bytes: u64) {
+        eprintln!("processing partition: {}", name);
+    }
+
+    fn on_operation(
+        &self,
+        _name: &str,
+        index: usize,
+        op_type: OperationType,
+        bytes: BytesProgress,
+    ) {
+        eprintln!("applying operation #{}: {:?} ({}%)", index, op_type, bytes.percent());
+    }
+
+    fn on_partition_done(&self, _name: &str) {
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BytesProgress;
+
+    #[test]
+    fn bytes_progress_percent_test() {
+        assert_eq!(BytesProgress { done: 0, total: 8192 }.percent(), 0);
+        assert_eq!(BytesProgress { done: 4095, total: 8192 }.percent(), 49);
+        assert_eq!(BytesProgress { done: 8192, total: 8192 }.percent(), 100);
+        assert_eq!(BytesProgress { done: 0, total: 0 }.percent(), 100);
+        assert!(BytesProgress { done: u64::MAX, total: u64::MAX }.percent() <= 100);
+    }
+}
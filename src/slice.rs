@@ -0,0 +1,122 @@
+use anyhow::{bail, Context, Result};
+use cast::u64;
+use prost::Message;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{update_metadata::DeltaArchiveManifest, SliceArgs};
+
+/// writes a payload with only the selected partitions and the data their operations reference
+///
+/// the data of each operation is copied in order, so the new data offsets are compact,
+/// and the payload is marked as a partial update and left unsigned
+fn write_slice(
+    manifest: &DeltaArchiveManifest,
+    parts: &[&str],
+    data: &mut (impl Read + Seek),
+    data_offset: u64,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut sliced = manifest.clone();
+    sliced.partitions.retain(|part| parts.contains(&part.partition_name.as_str()));
+    for part in parts {
+        if !sliced.partitions.iter().any(|sliced_part| sliced_part.partition_name == *part) {
+            bail!("Partition {} isn't in the payload", part);
+        }
+    }
+    if let Some(metadata) = sliced.dynamic_partition_metadata.as_mut() {
+        for group in &mut metadata.groups {
+            group.partition_names.retain(|name| parts.contains(&name.as_str()));
+        }
+    }
+    sliced.partial_update = Some(true);
+    sliced.signatures_offset = None;
+    sliced.signatures_size = None;
+
+    // the data is written after the manifest, so first assign every operation its new offset
+    let mut ranges = vec![];
+    let mut new_offset = 0;
+    for part in &mut sliced.partitions {
+        for op in &mut part.operations {
+            if let (Some(offset), Some(length)) = (op.data_offset, op.data_length) {
+                ranges.push((offset, length));
+                op.data_offset = Some(new_offset);
+                new_offset += length;
+            }
+        }
+    }
+
+    let manifest_bytes = sliced.encode_to_vec();
+    out.write_all(b"CrAU")?;
+    out.write_all(&2_u64.to_be_bytes())?;
+    out.write_all(&u64(manifest_bytes.len()).to_be_bytes())?;
+    // no metadata signature
+    out.write_all(&0_u32.to_be_bytes())?;
+    out.write_all(&manifest_bytes)?;
+    for (offset, length) in ranges {
+        data.seek(SeekFrom::Start(data_offset + offset))?;
+        let copied = io::copy(&mut data.by_ref().take(length), out)?;
+        if copied != length {
+            bail!("Payload ended inside the data at 0x{:x}", offset);
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub fn slice(manifest: &DeltaArchiveManifest, args: &SliceArgs, data_offset: u64) -> Result<()> {
+    let parts = args.parts.split(",").map(|part| part.trim()).collect::<Vec<_>>();
+    let mut data = File::open(&args.file)?;
+    let mut out = BufWriter::new(
+        File::create(&args.out).with_context(|| format!("Failed to create {}", args.out))?,
+    );
+    write_slice(manifest, &parts, &mut data, data_offset, &mut out)?;
+    println!("wrote {} partitions to {}", parts.len(), args.out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use binrw::BinRead;
+    use cast::usize;
+    use prost::Message;
+    use std::io::Cursor;
+
+    use super::write_slice;
+    use crate::update_metadata::{DeltaArchiveManifest, InstallOperation, PartitionUpdate};
+    use android_ota_extractor::payload::PayloadFile;
+
+    #[test]
+    fn slice_test() {
+        let op = |data_offset, data_length| InstallOperation {
+            data_offset: Some(data_offset),
+            data_length: Some(data_length),
+            ..Default::default()
+        };
+        let part = |name: &str, operations| PartitionUpdate {
+            partition_name: name.to_string(),
+            operations,
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![part("boot", vec![op(0, 2)]), part("dtbo", vec![op(2, 3), op(5, 1)])],
+            ..Default::default()
+        };
+        // 4 bytes standing in for the header and manifest
+        let mut payload = Cursor::new(b"xxxxbbdddD".to_vec());
+
+        let mut out = vec![];
+        write_slice(&manifest, &["dtbo"], &mut payload, 4, &mut out).unwrap();
+
+        let mut out = Cursor::new(out);
+        let header = PayloadFile::read(&mut out).unwrap();
+        let sliced = DeltaArchiveManifest::decode(&*header.manifest).unwrap();
+        assert_eq!(sliced.partitions.len(), 1);
+        let ops = &sliced.partitions[0].operations;
+        assert_eq!((ops[0].data_offset, ops[1].data_offset), (Some(0), Some(3)));
+        assert_eq!(sliced.partial_update, Some(true));
+        assert_eq!(&out.get_ref()[usize(out.position())..], b"dddD");
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::{bail, Result};
+use std::{fs::File, path::Path};
+
+use self::{
+    lint::{count_categories, lint},
+    signature::{read_public_key, verify_metadata_signature, verify_signatures},
+};
+use crate::{
+    output::{Output, OutputFormat, Style},
+    parse_parts,
+    update_metadata::{DeltaArchiveManifest, PartitionInfo},
+    HasUpdateType, UpdateType, VerifyArgs,
+};
+use android_ota_extractor::{check_hash, patch_level::check_min_patch_level, HashKind};
+
+mod lint;
+mod signature;
+
+/// the result of checking one image against its expected size and hash
+enum Status {
+    Ok,
+    Wrong(String),
+    Missing(String),
+    Unknown(String),
+}
+
+impl Status {
+    fn label(&self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Wrong(_) => "wrong",
+            Status::Missing(_) => "missing",
+            Status::Unknown(_) => "unknown",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            Status::Ok => "",
+            Status::Wrong(detail) | Status::Missing(detail) | Status::Unknown(detail) => detail,
+        }
+    }
+}
+
+/// fails unless the payload's metadata signature matches the public key at `key_path`, so the
+/// manifest can be trusted before anything is extracted
+pub fn check_metadata_signature(path: &str, payload_start: u64, key_path: &str) -> Result<()> {
+    let key = read_public_key(key_path)?;
+    match verify_metadata_signature(path, payload_start, &key)? {
+        Status::Ok => {
+            eprintln!("metadata signature verified with {}", key_path);
+            Ok(())
+        }
+        status => bail!("Metadata signature check failed: {}", status.detail()),
+    }
+}
+
+/// checks the image at `path` against the size and hash in `info`, naming the info in `which`
+fn check_image(path: &Path, info: Option<&PartitionInfo>, which: &str) -> Status {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => return Status::Missing(format!("{}: {}", path.display(), err)),
+    };
+    let Some(info) = info else {
+        return Status::Unknown(format!("payload has no {}", which));
+    };
+
+    if let Some(size) = info.size {
+        match file.metadata() {
+            Ok(metadata) if metadata.len() != size => {
+                return Status::Wrong(format!("size is {} but expected {}", metadata.len(), size))
+            }
+            Ok(_) => (),
+            Err(err) => return Status::Missing(format!("{}: {}", path.display(), err)),
+        }
+    }
+    match info.hash.as_deref() {
+        Some(hash) => match check_hash(&mut file, HashKind::Sha256, hash) {
+            Ok(()) => Status::Ok,
+            Err(err) => Status::Wrong(format!("{:#}", err)),
+        },
+        None => Status::Unknown(format!("{} has no hash", which)),
+    }
+}
+
+/// checks the src images of incremental partitions against old_partition_info
+fn verify_src(manifest: &DeltaArchiveManifest, args: &VerifyArgs, src_dir: &Path) -> usize {
+    let parts = parse_parts(&args.parts);
+    let mut failed = 0;
+    println!("{:<24} {:<8} detail", "partition", "src");
+    for part in &manifest.partitions {
+        let name = &part.partition_name;
+        if parts.as_ref().is_some_and(|parts| !parts.contains(&name.as_str())) {
+            continue;
+        }
+        if part.old_partition_info.is_none() && part.get_update_type() != UpdateType::Incremental {
+            continue;
+        }
+
+        let path = src_dir.join(format!("{}.img", name));
+        let status = check_image(&path, part.old_partition_info.as_ref(), "old_partition_info");
+        if matches!(status, Status::Wrong(_) | Status::Missing(_)) {
+            failed += 1;
+        }
+        println!("{:<24} {:<8} {}", name, status.label(), status.detail());
+    }
+    failed
+}
+
+/// checks the extracted images of all partitions against new_partition_info
+fn verify_dst(manifest: &DeltaArchiveManifest, args: &VerifyArgs, dst_dir: &Path) -> usize {
+    let parts = parse_parts(&args.parts);
+    let mut failed = 0;
+    println!("{:<24} {:<8} detail", "partition", "image");
+    for part in &manifest.partitions {
+        let name = &part.partition_name;
+        if parts.as_ref().is_some_and(|parts| !parts.contains(&name.as_str())) {
+            continue;
+        }
+
+        let path = dst_dir.join(format!("{}.img", name));
+        let status = check_image(&path, part.new_partition_info.as_ref(), "new_partition_info");
+        if !matches!(status, Status::Ok) {
+            failed += 1;
+        }
+        println!("{:<24} {:<8} {}", name, status.label(), status.detail());
+    }
+    failed
+}
+
+/// lints the payload, printing every violation grouped by category
+fn verify_lint(
+    manifest: &DeltaArchiveManifest,
+    args: &VerifyArgs,
+    output: &Output,
+) -> Result<usize> {
+    let parts = parse_parts(&args.parts);
+    let violations = lint(manifest, parts.as_deref());
+    let counts = count_categories(&violations);
+    match output.format {
+        OutputFormat::Text => {
+            println!("lint: {} violations", violations.len());
+            for (category, count) in &counts {
+                println!("{} ({}):", output.paint(category.name(), Style::Bold), count);
+                for violation in
+                    violations.iter().filter(|violation| violation.category == *category)
+                {
+                    match violation.op {
+                        Some(op) => {
+                            println!("  {} op #{}: {}", violation.partition, op, violation.message)
+                        }
+                        None => println!("  {}: {}", violation.partition, violation.message),
+                    }
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let report = serde_json::json!({ "violations": violations, "counts": counts });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(violations.len())
+}
+
+pub fn verify(
+    manifest: &DeltaArchiveManifest,
+    args: &VerifyArgs,
+    payload_start: u64,
+    output: &Output,
+) -> Result<()> {
+    if output.format == OutputFormat::Json
+        && (args.check_src.is_some() || args.dst.is_some() || args.full_chain)
+    {
+        bail!("--format json is only supported by --lint");
+    }
+    if let Some(min_level) = &args.min_patch_level {
+        check_min_patch_level(manifest.security_patch_level.as_deref(), min_level)?;
+    }
+    let mut failed = 0;
+    if args.lint {
+        failed += verify_lint(manifest, args, output)?;
+    }
+    if let Some(src_dir) = &args.check_src {
+        failed += verify_src(manifest, args, Path::new(src_dir));
+    }
+    if let Some(dst_dir) = &args.dst {
+        failed += verify_dst(manifest, args, Path::new(dst_dir));
+    }
+    if args.full_chain {
+        let key = read_public_key(args.key.as_deref().expect("clap requires --key"))?;
+        println!("{:<24} {:<8} detail", "signature", "status");
+        for (name, status) in verify_signatures(&args.file, payload_start, manifest, &key)? {
+            if !matches!(status, Status::Ok) {
+                failed += 1;
+            }
+            println!("{:<24} {:<8} {}", name, status.label(), status.detail());
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} checks failed", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cast::u64;
+    use clap::Parser;
+    use sha2::{Digest, Sha256};
+    use std::fs;
+
+    use super::verify_dst;
+    use crate::{
+        update_metadata::{DeltaArchiveManifest, PartitionInfo, PartitionUpdate},
+        Action, Cli,
+    };
+
+    #[test]
+    fn verify_dst_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let partition = |name: &str, image: &[u8], hash: bool| {
+            let info = PartitionInfo {
+                size: Some(u64(image.len())),
+                hash: hash.then(|| Sha256::digest(image).to_vec()),
+            };
+            PartitionUpdate {
+                partition_name: name.to_string(),
+                new_partition_info: Some(info),
+                ..Default::default()
+            }
+        };
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![
+                partition("boot", b"boot", true),
+                partition("system", b"system", true),
+                partition("vendor", b"vendor", true),
+                partition("odm", b"odm", false),
+            ],
+            ..Default::default()
+        };
+        fs::write(dir.path().join("boot.img"), b"boot").unwrap();
+        fs::write(dir.path().join("system.img"), b"SYSTEM").unwrap();
+        fs::write(dir.path().join("odm.img"), b"odm").unwrap();
+
+        let verify = |extra: &[&str]| {
+            let base = ["android-ota-extractor", "verify", "payload.bin", "--dst", "out"];
+            let Action::Verify(args) = Cli::parse_from(base.iter().chain(extra)).command else {
+                unreachable!()
+            };
+            verify_dst(&manifest, &args, dir.path())
+        };
+        // the wrong system image, the missing vendor image and odm without a hash
+        assert_eq!(verify(&[]), 3);
+        assert_eq!(verify(&["--parts", "boot"]), 0);
+        assert_eq!(verify(&["--parts", "boot,system"]), 1);
+    }
+}
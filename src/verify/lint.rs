@@ -0,0 +1,456 @@
+use android_ota_extractor::has_copy_hash_mismatch;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    update_metadata::{
+        install_operation::Type as OperationType, DeltaArchiveManifest, Extent as RawExtent,
+        InstallOperation, PartitionUpdate, DEFAULT_BLOCK_SIZE,
+    },
+    HasUpdateType, UpdateType,
+};
+
+/// the kinds of problems the linter looks for
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// invalid operation types, types this tool can't apply and types too new for the minor version
+    UnsupportedType,
+    /// data_offset and data_length which are inconsistent or don't fit the operation type
+    DataRange,
+    /// src and dst extents which are invalid or don't fit the operation type
+    Extents,
+    /// hashes which are missing
+    Hashes,
+    /// dst extents of a partition which overlap or leave gaps
+    DstTiling,
+}
+
+impl Category {
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::UnsupportedType => "unsupported_type",
+            Category::DataRange => "data_range",
+            Category::Extents => "extents",
+            Category::Hashes => "hashes",
+            Category::DstTiling => "dst_tiling",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Violation {
+    pub category: Category,
+    pub partition: String,
+    /// none for problems with the partition as a whole
+    pub op: Option<usize>,
+    pub message: String,
+}
+
+struct Linter<'a> {
+    manifest: &'a DeltaArchiveManifest,
+    part: &'a PartitionUpdate,
+    violations: &'a mut Vec<Violation>,
+}
+
+impl<'a> Linter<'a> {
+    fn report(&mut self, category: Category, op: Option<usize>, message: String) {
+        let partition = self.part.partition_name.clone();
+        self.violations.push(Violation { category, partition, op, message });
+    }
+
+    fn minor_version(&self) -> u32 {
+        self.manifest.minor_version.unwrap_or(0)
+    }
+
+    /// the first minor version which supports the operation type, for incremental types
+    fn required_minor_version(op_type: OperationType) -> u32 {
+        match op_type {
+            OperationType::Move | OperationType::Bsdiff => 1,
+            OperationType::SourceCopy | OperationType::SourceBsdiff => 2,
+            OperationType::BrotliBsdiff => 4,
+            OperationType::Puffdiff => 5,
+            OperationType::Zucchini => 8,
+            OperationType::Lz4diffBsdiff | OperationType::Lz4diffPuffdiff => 9,
+            _ => 0,
+        }
+    }
+
+    fn check_type(&mut self, i: usize, op: &InstallOperation) -> Option<OperationType> {
+        let Ok(op_type) = OperationType::try_from(op.r#type) else {
+            self.report(Category::UnsupportedType, Some(i), format!("invalid type {}", op.r#type));
+            return None;
+        };
+        let supported = matches!(
+            op_type,
+            OperationType::Replace
+                | OperationType::ReplaceBz
+                | OperationType::ReplaceXz
+                | OperationType::ReplaceZstd
+                | OperationType::Zero
+                | OperationType::SourceCopy
+                | OperationType::SourceBsdiff
+                | OperationType::BrotliBsdiff
+                | OperationType::Move
+                | OperationType::Bsdiff
+        );
+        if !supported {
+            self.report(
+                Category::UnsupportedType,
+                Some(i),
+                format!("{:?} operations can't be applied by this tool", op_type),
+            );
+        }
+        let required = Self::required_minor_version(op_type);
+        if self.minor_version() < required {
+            self.report(
+                Category::UnsupportedType,
+                Some(i),
+                format!(
+                    "{:?} requires minor version {} but the payload has {}",
+                    op_type,
+                    required,
+                    self.minor_version()
+                ),
+            );
+        }
+        Some(op_type)
+    }
+
+    fn check_data(&mut self, i: usize, op: &InstallOperation, op_type: OperationType) {
+        let needs_data = !matches!(
+            op_type,
+            OperationType::Zero
+                | OperationType::Discard
+                | OperationType::SourceCopy
+                | OperationType::Move
+        );
+        match (op.data_offset, op.data_length) {
+            (Some(offset), Some(length)) => {
+                if !needs_data {
+                    self.report(
+                        Category::DataRange,
+                        Some(i),
+                        format!("{:?} operations shouldn't have data", op_type),
+                    );
+                }
+                match offset.checked_add(length) {
+                    None => self.report(
+                        Category::DataRange,
+                        Some(i),
+                        format!("data range 0x{:x} + 0x{:x} overflows", offset, length),
+                    ),
+                    Some(end) => {
+                        if let Some(signatures_offset) = self.manifest.signatures_offset {
+                            if end > signatures_offset {
+                                self.report(
+                                    Category::DataRange,
+                                    Some(i),
+                                    format!(
+                                        "data ends at 0x{:x}, past the signatures at 0x{:x}",
+                                        end, signatures_offset
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+                if length == 0 && needs_data {
+                    self.report(Category::DataRange, Some(i), format!("data is empty"));
+                }
+            }
+            (None, None) => {
+                if needs_data {
+                    self.report(
+                        Category::DataRange,
+                        Some(i),
+                        format!("{:?} operations need data", op_type),
+                    );
+                }
+            }
+            _ => self.report(
+                Category::DataRange,
+                Some(i),
+                format!("only one of data_offset and data_length is set"),
+            ),
+        }
+    }
+
+    /// checks the extents are valid and fit in a partition of `size` bytes, if known
+    fn check_extents(&mut self, i: usize, kind: &str, extents: &[RawExtent], size: Option<u64>) {
+        let block_size = u64::from(self.manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE));
+        for (j, extent) in extents.iter().enumerate() {
+            let (Some(start), Some(num)) = (extent.start_block, extent.num_blocks) else {
+                self.report(
+                    Category::Extents,
+                    Some(i),
+                    format!("{} extent {} is incomplete", kind, j),
+                );
+                continue;
+            };
+            if num == 0 {
+                self.report(Category::Extents, Some(i), format!("{} extent {} is empty", kind, j));
+            }
+            // sparse holes don't refer to any blocks, like in check_tiling
+            if start == u64::MAX {
+                continue;
+            }
+            let end = start.checked_add(num).and_then(|end| end.checked_mul(block_size));
+            match (end, size) {
+                (None, _) => self.report(
+                    Category::Extents,
+                    Some(i),
+                    format!("{} extent {} overflows", kind, j),
+                ),
+                (Some(end), Some(size)) if end > size => self.report(
+                    Category::Extents,
+                    Some(i),
+                    format!(
+                        "{} extent {} ends at byte {} past the partition size {}",
+                        kind, j, end, size
+                    ),
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    fn check_op(&mut self, i: usize, op: &InstallOperation) {
+        let Some(op_type) = self.check_type(i, op) else {
+            return;
+        };
+        self.check_data(i, op, op_type);
+
+        let old_size = self.part.old_partition_info.as_ref().and_then(|info| info.size);
+        let new_size = self.part.new_partition_info.as_ref().and_then(|info| info.size);
+        let needs_src = op_type.get_update_type() == UpdateType::Incremental;
+        if op.dst_extents.is_empty() {
+            self.report(Category::Extents, Some(i), format!("no dst extents"));
+        }
+        self.check_extents(i, "dst", &op.dst_extents, new_size);
+        match (needs_src, op.src_extents.is_empty()) {
+            (true, true) => self.report(
+                Category::Extents,
+                Some(i),
+                format!("{:?} operations need src extents", op_type),
+            ),
+            (false, false) => self.report(
+                Category::Extents,
+                Some(i),
+                format!("{:?} operations shouldn't have src extents", op_type),
+            ),
+            _ => {}
+        }
+        self.check_extents(i, "src", &op.src_extents, old_size);
+
+        if op.data_length.is_some_and(|length| length > 0) && op.data_sha256_hash.is_none() {
+            self.report(Category::Hashes, Some(i), format!("data has no data_sha256_hash"));
+        }
+        // src hashes were introduced with minor version 3
+        if needs_src && self.minor_version() >= 3 && op.src_sha256_hash.is_none() {
+            self.report(Category::Hashes, Some(i), format!("src has no src_sha256_hash"));
+        }
+    }
+
+    /// checks the dst extents of all operations cover the partition exactly once
+    fn check_tiling(&mut self) {
+        let block_size = u64::from(self.manifest.block_size.unwrap_or(DEFAULT_BLOCK_SIZE));
+        let mut ranges = self
+            .part
+            .operations
+            .iter()
+            .enumerate()
+            .flat_map(|(i, op)| op.dst_extents.iter().map(move |extent| (extent, i)))
+            .filter_map(|(extent, i)| {
+                let start = extent.start_block?;
+                Some((start, start.checked_add(extent.num_blocks?)?, i))
+            })
+            .collect::<Vec<_>>();
+        ranges.sort();
+
+        let mut covered = 0;
+        for (start, end, i) in ranges {
+            if start < covered {
+                self.report(
+                    Category::DstTiling,
+                    Some(i),
+                    format!("dst blocks {}..{} were already written", start, covered.min(end)),
+                );
+            } else if start > covered {
+                self.report(
+                    Category::DstTiling,
+                    None,
+                    format!("dst blocks {}..{} aren't written by any operation", covered, start),
+                );
+            }
+            covered = covered.max(end);
+        }
+        let size = self.part.new_partition_info.as_ref().and_then(|info| info.size);
+        if let Some(blocks) = size.map(|size| size.div_ceil(block_size)) {
+            if covered < blocks {
+                self.report(
+                    Category::DstTiling,
+                    None,
+                    format!("dst blocks {}..{} aren't written by any operation", covered, blocks),
+                );
+            }
+        }
+    }
+
+    fn lint(&mut self) {
+        let new_hash = self.part.new_partition_info.as_ref().and_then(|info| info.hash.as_ref());
+        if new_hash.is_none() {
+            self.report(Category::Hashes, None, format!("no new partition hash"));
+        }
+        let old_hash = self.part.old_partition_info.as_ref().and_then(|info| info.hash.as_ref());
+        if self.part.get_update_type() == UpdateType::Incremental && old_hash.is_none() {
+            self.report(Category::Hashes, None, format!("no old partition hash"));
+        }
+        if has_copy_hash_mismatch(self.part) {
+            self.report(
+                Category::Hashes,
+                None,
+                format!("all operations copy blocks in place but the new hash differs"),
+            );
+        }
+
+        for (i, op) in self.part.operations.iter().enumerate() {
+            self.check_op(i, op);
+        }
+        self.check_tiling();
+    }
+}
+
+/// checks every operation of the selected partitions, collecting all violations
+pub fn lint(manifest: &DeltaArchiveManifest, parts: Option<&[&str]>) -> Vec<Violation> {
+    let mut violations = vec![];
+    for part in &manifest.partitions {
+        if parts.is_some_and(|parts| !parts.contains(&part.partition_name.as_str())) {
+            continue;
+        }
+        Linter { manifest, part, violations: &mut violations }.lint();
+    }
+    violations
+}
+
+/// the number of violations in each category
+pub fn count_categories(violations: &[Violation]) -> BTreeMap<Category, usize> {
+    let mut counts = BTreeMap::new();
+    for violation in violations {
+        *counts.entry(violation.category).or_default() += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Category};
+    use crate::update_metadata::{
+        install_operation::Type as OperationType, DeltaArchiveManifest, Extent as RawExtent,
+        InstallOperation, PartitionInfo, PartitionUpdate,
+    };
+
+    fn extent(start_block: u64, num_blocks: u64) -> RawExtent {
+        RawExtent { start_block: Some(start_block), num_blocks: Some(num_blocks) }
+    }
+
+    #[test]
+    fn lint_test() {
+        let op = |op_type: OperationType, dst_extents| InstallOperation {
+            r#type: op_type as i32,
+            dst_extents,
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![PartitionUpdate {
+                partition_name: "system".to_string(),
+                new_partition_info: Some(PartitionInfo {
+                    size: Some(4 * 4096),
+                    hash: Some(vec![0; 32]),
+                }),
+                operations: vec![
+                    op(OperationType::Zero, vec![extent(0, 2)]),
+                    // replace without data, overlapping the zero op
+                    op(OperationType::Replace, vec![extent(1, 2)]),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let violations = lint(&manifest, None);
+        let found = violations
+            .iter()
+            .map(|violation| (violation.category, violation.op))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (Category::DataRange, Some(1)),
+                (Category::DstTiling, Some(1)),
+                (Category::DstTiling, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn lint_hole_test() {
+        let zero = |dst_extents| InstallOperation {
+            r#type: OperationType::Zero as i32,
+            dst_extents,
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![PartitionUpdate {
+                partition_name: "system".to_string(),
+                new_partition_info: Some(PartitionInfo {
+                    size: Some(4 * 4096),
+                    hash: Some(vec![0; 32]),
+                }),
+                operations: vec![
+                    zero(vec![extent(0, 2)]),
+                    zero(vec![extent(u64::MAX, 3), extent(2, 2)]),
+                ],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(lint(&manifest, None).is_empty());
+    }
+
+    #[test]
+    fn copy_hash_mismatch_test() {
+        let info = |hash: u8| PartitionInfo { size: Some(4096), hash: Some(vec![hash; 32]) };
+        let mut part = PartitionUpdate {
+            partition_name: "vendor".to_string(),
+            old_partition_info: Some(info(1)),
+            new_partition_info: Some(info(2)),
+            operations: vec![InstallOperation {
+                r#type: OperationType::SourceCopy as i32,
+                src_extents: vec![extent(0, 1)],
+                dst_extents: vec![extent(0, 1)],
+                src_sha256_hash: Some(vec![0; 32]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mismatches = |part: &PartitionUpdate| {
+            let manifest = DeltaArchiveManifest {
+                minor_version: Some(2),
+                partitions: vec![part.clone()],
+                ..Default::default()
+            };
+            lint(&manifest, None)
+                .iter()
+                .filter(|violation| violation.category == Category::Hashes)
+                .count()
+        };
+        assert_eq!(mismatches(&part), 1);
+
+        // moving blocks around can legitimately change the hash
+        part.operations[0].src_extents = vec![extent(1, 1)];
+        assert_eq!(mismatches(&part), 0);
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{anyhow, Context, Result};
+use binrw::BinRead;
+use cast::u64;
+use prost::Message;
+use rsa::{
+    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, sha2::Sha256, Pkcs1v15Sign, RsaPublicKey,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use super::Status;
+use crate::update_metadata::{DeltaArchiveManifest, Signatures};
+use android_ota_extractor::{payload::PayloadFile, HashKind};
+
+/// reads a PEM encoded RSA public key, either as a SubjectPublicKeyInfo or in PKCS#1 form
+pub fn read_public_key(path: &str) -> Result<RsaPublicKey> {
+    let pem = fs::read_to_string(path).with_context(|| format!("Failed to read key {}", path))?;
+    RsaPublicKey::from_public_key_pem(&pem)
+        .or_else(|_| RsaPublicKey::from_pkcs1_pem(&pem))
+        .with_context(|| format!("Failed to parse RSA public key {}", path))
+}
+
+/// hashes `start..end` of the file
+fn hash_range(file: &mut File, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let len = end - start;
+    let mut hasher = HashKind::Sha256.hasher();
+    file.seek(SeekFrom::Start(start))?;
+    let copied = io::copy(&mut file.by_ref().take(len), &mut hasher)?;
+    if copied != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(hasher.finalize())
+}
+
+/// checks whether any of the signatures in the serialized Signatures message signs `digest`
+fn check_signatures(key: &RsaPublicKey, message: &[u8], digest: &[u8]) -> Status {
+    let signatures = match Signatures::decode(message) {
+        Ok(signatures) => signatures.signatures,
+        Err(err) => return Status::Wrong(format!("invalid signatures message: {}", err)),
+    };
+    if signatures.is_empty() {
+        return Status::Missing(format!("payload has no signatures"));
+    }
+    for signature in &signatures {
+        let mut data = signature.data();
+        // signatures may be padded to the maximum size for the key
+        if let Some(size) = signature.unpadded_signature_size {
+            data = &data[..data.len().min(cast::usize(size))];
+        }
+        if key.verify(Pkcs1v15Sign::new::<Sha256>(), digest, data).is_ok() {
+            return Status::Ok;
+        }
+    }
+    Status::Wrong(format!("none of the {} signatures match the key", signatures.len()))
+}
+
+/// opens the payload and reads its header, returning the file and the offset of the data
+fn read_header(path: &str, payload_start: u64) -> Result<(File, PayloadFile, u64)> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    file.seek(SeekFrom::Start(payload_start))?;
+    let payload = PayloadFile::read(&mut file)
+        .with_context(|| format!("Failed to parse file payload file {}", path))?;
+    let data_offset = file.stream_position()?;
+    Ok((file, payload, data_offset))
+}
+
+/// checks the metadata signature, which signs the header and manifest up to the signature itself
+fn check_metadata(
+    file: &mut File,
+    payload: &PayloadFile,
+    payload_start: u64,
+    data_offset: u64,
+    key: &RsaPublicKey,
+) -> Result<Status> {
+    if payload.metadata_signature_message.is_empty() {
+        return Ok(Status::Missing(format!("payload has no metadata signature")));
+    }
+    let metadata_end = data_offset - u64(payload.metadata_signature_size);
+    let digest = hash_range(file, payload_start, metadata_end)?;
+    Ok(check_signatures(key, &payload.metadata_signature_message, &digest))
+}
+
+/// checks only the metadata signature, which is quick since it doesn't cover the data
+pub fn verify_metadata_signature(
+    path: &str,
+    payload_start: u64,
+    key: &RsaPublicKey,
+) -> Result<Status> {
+    let (mut file, payload, data_offset) = read_header(path, payload_start)?;
+    check_metadata(&mut file, &payload, payload_start, data_offset, key)
+}
+
+/// checks the metadata signature (over the header and manifest)
+/// and the payload signature (over everything before the payload signatures)
+pub fn verify_signatures(
+    path: &str,
+    payload_start: u64,
+    manifest: &DeltaArchiveManifest,
+    key: &RsaPublicKey,
+) -> Result<[(&'static str, Status); 2]> {
+    let (mut file, payload, data_offset) = read_header(path, payload_start)?;
+    let metadata = check_metadata(&mut file, &payload, payload_start, data_offset, key)?;
+
+    let payload_status = match (manifest.signatures_offset, manifest.signatures_size) {
+        (Some(offset), Some(size)) => {
+            let signed_end = data_offset
+                .checked_add(offset)
+                .ok_or_else(|| anyhow!("Invalid signatures_offset {}", offset))?;
+            // the size isn't trusted, so only as much is allocated as the file actually holds
+            let mut message = vec![];
+            file.seek(SeekFrom::Start(signed_end))?;
+            match (&mut file).take(size).read_to_end(&mut message) {
+                Ok(read) if u64(read) == size => check_signatures(
+                    key,
+                    &message,
+                    &hash_range(&mut file, payload_start, signed_end)?,
+                ),
+                Ok(read) => Status::Missing(format!(
+                    "payload signatures can't be read: only {} of {} bytes are there",
+                    read, size
+                )),
+                Err(err) => Status::Missing(format!("payload signatures can't be read: {}", err)),
+            }
+        }
+        _ => Status::Missing(format!("payload has no payload signatures")),
+    };
+
+    Ok([("metadata", metadata), ("payload", payload_status)])
+}